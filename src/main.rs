@@ -1,32 +1,333 @@
 mod torrent;
 
-use std::io::Write;
+use torrent::{
+    fetch_metadata, Dht, DownloadError, Downloader, Encryption, FileStorage, Listener, Lsd, Magnet,
+    MmapStorage, PeerId, Priority, Resume, Torrent, TorrentBuilder, UtpSocket, Verification,
+    BOOTSTRAP_NODES,
+};
 
-use torrent::{PeerId, Torrent};
+const MAX_PEERS: usize = 8;
 
-fn main() {
+const LISTEN_PORT: u16 = 6881;
+
+const DEFAULT_TORRENT: &str = "sample.torrent";
+
+// Where the DHT node keeps its id and routing table between runs
+const DHT_STATE: &str = "dht.state";
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("verify") => verify(args.get(1).map_or(DEFAULT_TORRENT, String::as_str)),
+        Some("create") => create(&args[1..]),
+        _ => download(&args).await,
+    }
+}
+
+/// Download from a .torrent file or a magnet link, given as the first
+/// argument. `--dht-node <host:port>` replaces the default DHT bootstrap
+/// nodes, and `--no-dht` keeps out of the DHT altogether. `--no-lsd` stops
+/// looking for peers on the local network, and `--no-utp` talks to peers
+/// over TCP only. `--encryption <plaintext|prefer|require>` sets whether
+/// connections are encrypted, preferring it by default.
+/// `--priority <file>=<skip|low|normal|high>` sets how eagerly a file of a
+/// multi file torrent is downloaded, by its index in the file list leaving
+/// out padding files.
+async fn download(args: &[String]) {
     let id = PeerId::new();
 
-    let torrent = Torrent::from("sample.torrent");
+    let source = args
+        .first()
+        .filter(|arg| !arg.starts_with("--"))
+        .map_or(DEFAULT_TORRENT, String::as_str);
+
+    // Keep uploading to the swarm once the download is done
+    let seeding = args.iter().any(|arg| arg == "--seed");
+
+    // Access the payload through memory maps rather than file reads and writes
+    let mmap = args.iter().any(|arg| arg == "--mmap");
+
+    let encryption = match args
+        .windows(2)
+        .find(|pair| pair[0] == "--encryption")
+        .map(|pair| pair[1].as_str())
+    {
+        Some("plaintext") => Encryption::Plaintext,
+        Some("require") => Encryption::Require,
+        Some("prefer") | None => Encryption::Prefer,
+        Some(other) => panic!("Unknown encryption policy `{}`", other),
+    };
+
+    let listener = Listener::bind(LISTEN_PORT)
+        .await
+        .expect("Cannot listen for peers")
+        .with_encryption(encryption);
+
+    listener.start();
+
+    let utp = if args.iter().any(|arg| arg == "--no-utp") {
+        None
+    } else {
+        UtpSocket::bind(listener.port())
+            .await
+            .map_err(|e| println!("uTP unavailable: {:?}", e))
+            .ok()
+    };
+
+    if let Some(utp) = &utp {
+        listener.start_utp(utp);
+    }
+
+    let dht = if args.iter().any(|arg| arg == "--no-dht") {
+        None
+    } else {
+        Some(join_dht(args, listener.port(), utp.as_ref()).await)
+    };
+
+    let lsd = if args.iter().any(|arg| arg == "--no-lsd") {
+        None
+    } else {
+        // Networks without multicast only miss out on local peers
+        Lsd::bind()
+            .await
+            .map_err(|e| println!("Local service discovery unavailable: {:?}", e))
+            .ok()
+    };
+
+    let (mut torrent, peer_list) = if source.starts_with("magnet:") {
+        let magnet = Magnet::parse(source).expect("Invalid magnet link");
+
+        println!(
+            "Fetching metadata for {}",
+            magnet.name.as_deref().unwrap_or(source)
+        );
+
+        fetch_metadata(&magnet, id, listener.port(), dht.as_ref(), encryption)
+            .await
+            .expect("Cannot fetch metadata from any peer")
+    } else {
+        let torrent = Torrent::from(source);
+        let peer_list = torrent.get_peer_list(id, listener.port()).await;
+
+        (torrent, peer_list)
+    };
+
+    for pair in args.windows(2).filter(|pair| pair[0] == "--priority") {
+        let (file, priority) = pair[1]
+            .split_once('=')
+            .expect("File priority is not `<file>=<priority>`");
+
+        let priority = match priority {
+            "skip" => Priority::Skip,
+            "low" => Priority::Low,
+            "normal" => Priority::Normal,
+            "high" => Priority::High,
+            other => panic!("Unknown file priority `{}`", other),
+        };
+
+        let file = file.parse().expect("File is not an index");
+
+        if !torrent.set_file_priority(file, priority) {
+            println!("No file {} in the torrent, ignoring its priority", file);
+        }
+    }
+
     println!("{:#?}", torrent);
 
-    let mut single_file = torrent.create_single_payload();
+    let incoming = listener.register(&torrent, id);
 
-    let peer_list = torrent.get_peer_list(id);
+    // Whatever an earlier run left behind has to be looked at before the
+    // payload files are opened
+    let resume = Resume::new(&torrent, ".");
+    let startup = resume.check();
 
-    let mut peers = peer_list.connect(1);
+    let mut downloader = Downloader::new(&torrent, peer_list, MAX_PEERS)
+        .with_seeding(seeding)
+        .with_incoming(incoming)
+        .with_listen_port(listener.port())
+        .with_encryption(encryption)
+        .with_resume(resume, startup);
 
-    let peer = peers.first_mut().expect("No peers in peer list");
+    if let Some(dht) = &dht {
+        downloader = downloader.with_dht(dht.clone());
+    }
 
-    for piece in 0..torrent.piece_count() {
-        match peer.download(&torrent, piece) {
-            Ok(bytes) => {
-                single_file.write(&bytes).ok();
+    if let Some(lsd) = lsd {
+        downloader = downloader.with_lsd(lsd);
+    }
+
+    if let Some(utp) = utp {
+        downloader = downloader.with_utp(utp);
+    }
+
+    let result = if mmap {
+        let storage = MmapStorage::open(&torrent, ".").expect("Cannot open payload files");
+        downloader.run(storage).await
+    } else {
+        let storage = FileStorage::open(&torrent, ".").expect("Cannot open payload files");
+        downloader.run(storage).await
+    };
+
+    if let Some(dht) = &dht {
+        dht.save(DHT_STATE).ok();
+    }
+
+    match result {
+        Ok(stats) => println!(
+            "Download complete ({} duplicate bytes received, {} bytes uploaded)",
+            stats.duplicate_bytes, stats.uploaded_bytes
+        ),
+        Err(DownloadError::Storage(e)) => println!("Cannot write to the payload: {}", e),
+        Err(e) => println!("Error: {:?}", e),
+    }
+}
+
+/// Start a DHT node on `port`, picking up the routing table of the last run.
+/// With uTP about, the node shares its socket instead.
+async fn join_dht(args: &[String], port: u16, utp: Option<&UtpSocket>) -> Dht {
+    let dht = match utp {
+        Some(utp) => {
+            let (socket, datagrams) = utp.divert();
+            Dht::over(socket, datagrams)
+        }
+        None => Dht::bind(port).await.expect("Cannot bind DHT socket"),
+    };
+
+    // A missing or broken state file only means starting from scratch
+    dht.load(DHT_STATE).ok();
+
+    let nodes: Vec<&str> = args
+        .windows(2)
+        .filter(|pair| pair[0] == "--dht-node")
+        .map(|pair| pair[1].as_str())
+        .collect();
+
+    if nodes.is_empty() {
+        dht.bootstrap(BOOTSTRAP_NODES).await;
+    } else {
+        dht.bootstrap(&nodes).await;
+    }
+
+    println!(
+        "DHT node on port {} knows {} nodes",
+        dht.port(),
+        dht.node_count()
+    );
+
+    dht
+}
+
+/// Check the payload on disk against the piece hashes, and record the result
+/// for the next download to resume from
+fn verify(path: &str) {
+    let torrent = Torrent::from(path);
+    let verification = Verification::check(&torrent, ".");
+
+    for file in &verification.files {
+        println!(
+            "{}: {}/{} pieces{}",
+            file.path.display(),
+            file.valid_pieces,
+            file.pieces,
+            if file.is_complete() {
+                " (complete)"
+            } else {
+                ""
             }
+        );
+    }
+
+    let missing: Vec<String> = (0..verification.pieces.len())
+        .filter(|&piece| !verification.pieces[piece])
+        .map(|piece| piece.to_string())
+        .collect();
+
+    println!(
+        "{}/{} pieces valid",
+        verification.valid_pieces(),
+        verification.pieces.len()
+    );
+
+    if !missing.is_empty() {
+        println!("Missing or corrupt pieces: {}", missing.join(", "));
+    }
+
+    if let Err(e) = Resume::new(&torrent, ".").save_verified(&verification) {
+        println!("Cannot write resume file: {:?}", e);
+    }
+}
 
-            Err(e) => println!("Error: {:?}", e),
+/// Make a .torrent file from a file or directory:
+///
+/// `create <path> [--announce <url>[,<url>...]]... [--comment <text>]
+/// [--piece-length <bytes>] [--private] [--web-seed <url>]... [--no-date]
+/// [--output <file>]`
+///
+/// Each `--announce` adds a tier of trackers.
+fn create(args: &[String]) {
+    let path = match args.first() {
+        Some(path) => std::path::Path::new(path),
+        None => {
+            println!("Usage: create <path> [options]");
+            return;
+        }
+    };
+
+    let mut builder = TorrentBuilder::new(path);
+    let mut output = None;
+
+    let mut options = args[1..].iter();
+
+    while let Some(option) = options.next() {
+        match (option.as_str(), options.clone().next()) {
+            ("--private", _) => builder = builder.with_private(true),
+            ("--no-date", _) => builder = builder.with_creation_date(None),
+
+            ("--announce", Some(urls)) => {
+                builder = builder.with_tracker_tier(urls.split(',').map(String::from).collect());
+                options.next();
+            }
+
+            ("--comment", Some(comment)) => {
+                builder = builder.with_comment(comment);
+                options.next();
+            }
+
+            ("--piece-length", Some(length)) => {
+                let length = length.parse().expect("Piece length is not a number");
+                builder = builder.with_piece_length(length);
+                options.next();
+            }
+
+            ("--web-seed", Some(url)) => {
+                builder = builder.with_web_seed(url);
+                options.next();
+            }
+
+            ("--output", Some(file)) => {
+                output = Some(std::path::PathBuf::from(file));
+                options.next();
+            }
+
+            (option, _) => {
+                println!("Unknown or incomplete option `{}`", option);
+                return;
+            }
         }
     }
 
-    println!("Download complete");
+    let output = output.unwrap_or_else(|| {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        std::path::PathBuf::from(format!("{}.torrent", name))
+    });
+
+    match builder
+        .build()
+        .and_then(|torrent| std::fs::write(&output, torrent))
+    {
+        Ok(()) => println!("Wrote {}", output.display()),
+        Err(e) => println!("Error: {:?}", e),
+    }
 }
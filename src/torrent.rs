@@ -1,13 +1,19 @@
+use std::collections::VecDeque;
 use std::fs;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
 
 mod bencode;
 use bencode::Bencoded;
 
 mod sha;
-use sha::Sha1;
+use sha::{Sha1, Sha256};
 
 mod url;
 use url::Url;
@@ -15,7 +21,94 @@ use url::Url;
 pub mod peer_id;
 pub use peer_id::PeerId;
 
-#[derive(Debug)]
+mod download;
+pub use download::Downloader;
+
+mod pipeline;
+use pipeline::{Arrival, BlockRequest, PartialPiece, Pipeline};
+
+mod picker;
+use picker::{Availability, PiecePicker};
+
+mod endgame;
+use endgame::{Endgame, Sync};
+
+mod choker;
+use choker::{Choker, PeerStats};
+
+mod listener;
+use listener::ConnectionSlot;
+pub use listener::Listener;
+
+mod storage;
+use storage::Storage;
+pub use storage::{FileStorage, MmapStorage, Verification};
+
+mod magnet;
+pub use magnet::Magnet;
+
+mod extension;
+use extension::{Extensions, MAX_QUEUED_REQUESTS};
+
+mod metadata;
+pub use metadata::fetch_metadata;
+use metadata::UtMetadata;
+
+mod pex;
+use pex::{PeerExchange, UtPex};
+
+mod dht;
+pub use dht::{Dht, BOOTSTRAP_NODES};
+
+mod utp;
+pub use utp::UtpSocket;
+use utp::UtpStream;
+
+mod stream;
+use stream::PeerStream;
+
+mod mse;
+pub use mse::Encryption;
+
+mod lsd;
+pub use lsd::Lsd;
+
+mod webseed;
+use webseed::WebSeed;
+
+mod v2;
+use v2::PieceHash;
+
+mod fast;
+use fast::{allowed_fast_set, ALLOWED_FAST_COUNT, FAST_EXTENSION, MAX_FAST_PIECES};
+
+mod builder;
+pub use builder::TorrentBuilder;
+
+mod resume;
+pub use resume::Resume;
+use resume::{ResumeData, Startup};
+
+// How long a peer that has unchoked us may go without sending us a block
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long to wait for a message before getting back to other business
+const RECV_TIMEOUT: Duration = Duration::from_millis(200);
+
+// How often extensions get to send messages of their own accord
+const EXTENSION_TICK: Duration = Duration::from_secs(1);
+
+// Longest message a peer may send: enough for the bitfield of two million
+// pieces, and far more than a block with its header
+const MAX_MESSAGE_LENGTH: usize = 256 * 1024;
+
+// Byte and bit of the reserved handshake bytes announcing BEP 10 support
+const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+
+#[derive(Debug, Clone)]
 pub struct Torrent {
     // Announce URL of tracker
     announce: String,
@@ -24,12 +117,29 @@ pub struct Torrent {
 
     // Number of bytes in each piece
     piece_length: usize,
-    // Sha1 hash values for each piece
-    pieces: Vec<Sha1>,
+    // What each piece is checked against: its SHA-1 for v1 torrents, and
+    // its merkle root for v2 and hybrid ones
+    pieces: Vec<PieceHash>,
     // "No external peer source"
     private: bool,
 
     payload: Payload,
+
+    // The info dictionary as it was hashed, for handing to other peers
+    info: RawInfo,
+
+    // HTTP servers holding the payload (BEP 19)
+    web_seeds: Vec<String>,
+}
+
+/// Encoded info dictionary, kept out of the way of debug output
+#[derive(Clone)]
+struct RawInfo(Arc<Vec<u8>>);
+
+impl std::fmt::Debug for RawInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes", self.0.len())
+    }
 }
 
 impl Torrent {
@@ -37,39 +147,116 @@ impl Torrent {
         let raw_content = fs::read(path).expect("failed to read torrent file");
         let bencoded = Bencoded::parse(&raw_content).expect("failed parsing bencoding");
 
+        // Trackerless torrents leave finding peers to the DHT
         let announce = get_bstr(&bencoded, "announce")
             .map(|url| String::from_utf8(url).expect("Malformed torrent `announce`"))
-            .expect("No torrent `announce` entry");
+            .unwrap_or_default();
 
         let info = get_dict(&bencoded, "info").expect("No torrent `info` entry");
 
-        let info_reencoded = Vec::from(&info);
-        let info_hash = Sha1::digest(&info_reencoded);
+        // A single web seed may come as a string rather than a list
+        let web_seeds = match get_bencoded_dict_value(&bencoded, "url-list") {
+            Some(Bencoded::Bstr(url)) => vec![url],
+            Some(Bencoded::List(urls)) => urls
+                .into_iter()
+                .filter_map(|url| match url {
+                    Bencoded::Bstr(url) => Some(url),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        // The hashes of v2 pieces are kept outside of the info dictionary
+        let piece_layers = get_dict(&bencoded, "piece layers");
+
+        let mut torrent = Self::from_info(announce, &info, piece_layers.as_ref());
+
+        torrent.web_seeds = web_seeds
+            .into_iter()
+            .filter_map(|url| String::from_utf8(url).ok())
+            .filter(|url| !url.is_empty())
+            .collect();
+
+        torrent
+    }
 
-        let name = get_bstr(&info, "name")
+    /// Build a torrent from its info dictionary, and the piece layers of a
+    /// v2 torrent if there are any. Info dictionaries fetched from peers for
+    /// a magnet link come without.
+    fn from_info(announce: String, info: &Bencoded, piece_layers: Option<&Bencoded>) -> Self {
+        let info_reencoded = Vec::from(info);
+
+        let name = get_bstr(info, "name")
             .map(|name| String::from_utf8(name).expect("Torrent `info.name` not valid UTF-8"))
             .expect("No torrent `info.name` entry");
 
-        let piece_length = get_int(&info, "piece length")
+        let piece_length = get_int(info, "piece length")
             .map(|length| length as usize)
             .expect("No torrent `piece length` entry");
 
-        let pieces = get_bstr(&info, "pieces")
-            .map(|sha_string| {
-                assert_eq!(
-                    sha_string.len() % 20,
-                    0,
-                    "Torrent `pieces` does not contain a valid multiple of SHA1 digests"
-                );
-                sha_string.chunks_exact(20).map(Sha1::new_raw).collect()
-            })
-            .expect("No torrent `pieces` entry");
-
-        let private = get_int(&info, "private")
+        let v1_pieces: Option<Vec<PieceHash>> = get_bstr(info, "pieces").map(|sha_string| {
+            assert_eq!(
+                sha_string.len() % 20,
+                0,
+                "Torrent `pieces` does not contain a valid multiple of SHA1 digests"
+            );
+            sha_string
+                .chunks_exact(20)
+                .map(|sha| PieceHash::Sha1(Sha1::new_raw(sha)))
+                .collect()
+        });
+
+        let private = get_int(info, "private")
             .map(|private_flag| private_flag == 1)
             .unwrap_or_default();
 
-        let payload = Payload::new(name, &info);
+        let (info_hash, pieces, payload) = match (get_int(info, "meta version"), v1_pieces) {
+            (None | Some(1), Some(pieces)) => (
+                Sha1::digest(&info_reencoded),
+                pieces,
+                Payload::new(name, info),
+            ),
+
+            // Swarms of v2 torrents go by the SHA-256 info hash, cut short
+            // where only 20 bytes fit
+            (Some(2), None) => {
+                let files = v2::tree_files(info);
+                let pieces = v2::piece_hashes(&files, piece_length, piece_layers)
+                    .expect("No torrent `piece layers` entry");
+
+                (
+                    Sha256::digest(&info_reencoded).truncated(),
+                    pieces,
+                    v2::payload(name, &files, piece_length),
+                )
+            }
+
+            // Hybrid torrents also join the v1 swarm, and need the v1 info
+            // hash for that
+            (Some(2), Some(v1_pieces)) => {
+                let files = v2::tree_files(info);
+                let payload = Payload::new(name, info);
+                v2::check_hybrid(&payload, &files);
+
+                let pieces = match v2::piece_hashes(&files, piece_length, piece_layers) {
+                    Some(pieces) => {
+                        assert_eq!(
+                            pieces.len(),
+                            v1_pieces.len(),
+                            "Hybrid torrent has different pieces for v1 and v2"
+                        );
+                        pieces
+                    }
+                    None => v1_pieces,
+                };
+
+                (Sha1::digest(&info_reencoded), pieces, payload)
+            }
+
+            (None | Some(1), None) => panic!("No torrent `pieces` entry"),
+            (Some(_), _) => panic!("Unsupported torrent `meta version`"),
+        };
 
         Self {
             announce,
@@ -78,56 +265,105 @@ impl Torrent {
             pieces,
             private,
             payload,
+            info: RawInfo(Arc::new(info_reencoded)),
+            web_seeds: Vec::new(),
         }
     }
 
-    pub fn get_peer_list(&self, our_id: PeerId) -> PeerList {
-        let bytes_left = match &self.payload {
-            Payload::Single { name, length } => *length,
-            Payload::Multi { name, files } => 0,
+    /// Ask the tracker for peers. Without a tracker that answers, the list
+    /// is empty, and other peer sources have to make up for it.
+    pub async fn get_peer_list(&self, our_id: PeerId, port: u16) -> PeerList {
+        let found = if self.announce.is_empty() {
+            None
+        } else {
+            announce(
+                &self.announce,
+                self.info_hash,
+                our_id,
+                port,
+                self.payload.length(),
+            )
+            .await
         };
 
-        let url: String = Url::new(&self.announce)
-            .with_param("info_hash", self.info_hash)
-            .with_param("peer_id", our_id.as_ref())
-            .with_param("port", 6881)
-            .with_param("uploaded", 0)
-            .with_param("downloaded", 0)
-            .with_param("left", bytes_left)
-            .with_param("compact", 1)
-            .into();
-
-        let response = reqwest::blocking::get(url)
-            .expect("Cannot contact tracker")
-            .bytes()
-            .expect("Cannot read bytes of tracker response");
+        found.unwrap_or(PeerList {
+            our_id,
+            expected_info_hash: self.info_hash,
+            interval: 0,
+            peers: Vec::new(),
+        })
+    }
 
-        let bencoded = Bencoded::parse(&response).expect("Cannot parse bencoded tracker response");
+    pub fn name(&self) -> &str {
+        match &self.payload {
+            Payload::Single { name, length: _ } => name,
+            Payload::Multi { name, files: _ } => name,
+        }
+    }
 
-        PeerList::new(bencoded, our_id, self.info_hash)
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len()
     }
 
-    pub fn create_single_payload(&self) -> fs::File {
-        match &self.payload {
-            Payload::Single { name, length: _ } => {
-                fs::File::create_new(name).expect("Cannot create single payload file")
-            }
+    /// Download file `file` of a multi file torrent with `priority`, which
+    /// may be not to download it at all, returning whether there is such a
+    /// file. Files are counted as users see them, without padding files.
+    pub fn set_file_priority(&mut self, file: usize, priority: Priority) -> bool {
+        let files = match &mut self.payload {
+            Payload::Multi { name: _, files } => files,
+            Payload::Single { .. } => return false,
+        };
 
-            Payload::Multi { name: _, files: _ } => {
-                todo!("Multi payload not implemented");
+        match files
+            .iter_mut()
+            .filter(|file| !file.attributes.padding)
+            .nth(file)
+        {
+            Some(file) => {
+                file.priority = priority;
+                true
             }
+            None => false,
         }
     }
 
-    pub fn piece_count(&self) -> usize {
-        self.pieces.len()
+    fn piece_size(&self, piece: usize) -> usize {
+        let payload_left = self.payload.length() - self.piece_length * piece;
+        std::cmp::min(self.piece_length, payload_left)
     }
 }
 
+/// Ask the tracker at `url` for peers of the torrent with `info_hash`
+async fn announce(
+    url: &str,
+    info_hash: Sha1,
+    our_id: PeerId,
+    port: u16,
+    bytes_left: usize,
+) -> Option<PeerList> {
+    let url: String = Url::new(url)
+        .with_param("info_hash", info_hash)
+        .with_param("peer_id", our_id.as_ref())
+        .with_param("port", port as usize)
+        .with_param("uploaded", 0)
+        .with_param("downloaded", 0)
+        .with_param("left", bytes_left)
+        .with_param("compact", 1)
+        .into();
+
+    let response = reqwest::get(url).await.ok()?.bytes().await.ok()?;
+    let bencoded =
+        Bencoded::parse(&response).filter(|bencoded| matches!(bencoded, Bencoded::Dict(_)))?;
+
+    Some(PeerList::new(bencoded, our_id, info_hash))
+}
+
 #[derive(Debug)]
 pub struct PeerList {
     our_id: PeerId,
     expected_info_hash: Sha1,
+    // Seconds the tracker wants us to wait before asking again, zero without
+    // a tracker that answered
     interval: usize,
     peers: Vec<PeerAddress>,
 }
@@ -137,12 +373,17 @@ impl PeerList {
         let interval =
             get_int(&response, "interval").expect("No `interval` in tracker response") as usize;
 
-        let peers = get_bstr(&response, "peers")
+        let mut peers: Vec<PeerAddress> = get_bstr(&response, "peers")
             .expect("No `peers` in tracker response")
             .chunks_exact(6)
             .map(PeerAddress::new)
             .collect();
 
+        // IPv6 peers come separately (BEP 7)
+        if let Some(peers6) = get_bstr(&response, "peers6") {
+            peers.extend(peers6.chunks_exact(18).map(PeerAddress::new));
+        }
+
         Self {
             our_id,
             expected_info_hash,
@@ -150,79 +391,183 @@ impl PeerList {
             peers,
         }
     }
-
-    pub fn connect(&self, max_connections: usize) -> Vec<PeerConnection> {
-        self.peers
-            .iter()
-            .take(max_connections)
-            .filter_map(|peer| peer.connect(self.our_id, self.expected_info_hash))
-            .collect()
-    }
 }
 
-struct PeerAddress {
-    ip: (u8, u8, u8, u8),
-    port: u16,
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct PeerAddress {
+    address: SocketAddr,
 }
 
 impl PeerAddress {
-    fn new(peer_sextet: &[u8]) -> Self {
-        let mut port_bytes = [0u8; 2];
-        port_bytes.copy_from_slice(&peer_sextet[4..=5]);
-
-        let ip = (
-            peer_sextet[0],
-            peer_sextet[1],
-            peer_sextet[2],
-            peer_sextet[3],
-        );
+    /// Read an address in compact form: six bytes for IPv4, or eighteen for
+    /// IPv6, the last two being the port
+    fn new(compact: &[u8]) -> Self {
+        let (ip, port) = compact.split_at(compact.len() - 2);
+
+        let ip = match <[u8; 16]>::try_from(ip) {
+            Ok(ip) => IpAddr::from(ip),
+            Err(_) => IpAddr::from(<[u8; 4]>::try_from(ip).expect("Malformed compact address")),
+        };
 
-        let port = u16::from_be_bytes(port_bytes);
+        let port = u16::from_be_bytes([port[0], port[1]]);
 
-        Self { ip, port }
+        Self {
+            address: SocketAddr::new(ip, port),
+        }
     }
 
-    fn connect(&self, our_id: PeerId, expected_info_hash: Sha1) -> Option<PeerConnection> {
-        let handshake: Vec<u8> = [19]
-            .into_iter()
-            .chain("BitTorrent protocol".as_bytes().into_iter().cloned())
-            .chain([0, 0, 0, 0, 0, 0, 0, 0].into_iter())
-            .chain(expected_info_hash.as_ref().iter().cloned())
-            .chain(our_id.as_ref().into_iter().cloned())
-            .collect();
+    /// Connect over uTP if we can, and TCP otherwise, encrypting as far as
+    /// `encryption` asks
+    async fn connect(
+        &self,
+        our_id: PeerId,
+        expected_info_hash: Sha1,
+        utp: Option<&UtpSocket>,
+        encryption: Encryption,
+    ) -> Option<PeerConnection> {
+        let mut stream = self
+            .open_encrypted(utp, expected_info_hash, encryption)
+            .await?;
+
+        stream
+            .write_all(&handshake(expected_info_hash, our_id))
+            .await
+            .ok()?;
 
         let mut buffer = [0u8; 64 * 1024];
-        let mut stream = TcpStream::connect(self.to_string()).ok()?;
+        let mut received = timeout(HANDSHAKE_TIMEOUT, read_handshake(&mut stream, &mut buffer))
+            .await
+            .ok()??;
 
-        stream.write(&handshake).ok()?;
+        let got_info_hash = Vec::from(&received[28..48]);
 
-        let response_size = stream.read(&mut buffer).ok()?;
+        if got_info_hash == expected_info_hash.as_ref() {
+            let reserved = reserved_bytes(&received);
 
-        if response_size < 68 {
-            return None;
+            // Anything after the handshake (typically a bitfield) is the
+            // start of the message stream
+            received.drain(..68);
+
+            Some(PeerConnection::new(stream, buffer, received).with_reserved(reserved))
+        } else {
+            None
         }
+    }
 
-        let got_info_hash = Vec::from(&buffer[28..48]);
+    async fn open_encrypted(
+        &self,
+        utp: Option<&UtpSocket>,
+        info_hash: Sha1,
+        encryption: Encryption,
+    ) -> Option<PeerStream> {
+        let mut stream = self.open(utp).await?;
 
-        if got_info_hash == expected_info_hash.as_ref() {
-            stream
-                .set_read_timeout(Some(std::time::Duration::from_millis(200)))
-                .ok();
+        if encryption == Encryption::Plaintext {
+            return Some(stream);
+        }
 
-            Some(PeerConnection::new(stream, buffer))
-        } else {
-            None
+        let negotiated = timeout(
+            HANDSHAKE_TIMEOUT,
+            mse::initiate(&mut stream, info_hash, encryption),
+        )
+        .await
+        .ok()
+        .flatten();
+
+        match negotiated {
+            Some(()) => Some(stream),
+            // Peers that know nothing of encryption hang up on it, so they
+            // get another go in plaintext
+            None if encryption == Encryption::Prefer => self.open(utp).await,
+            None => None,
+        }
+    }
+
+    async fn open(&self, utp: Option<&UtpSocket>) -> Option<PeerStream> {
+        let address = self.to_socket_addr();
+
+        if let Some(utp) = utp {
+            if let Ok(Ok(stream)) = timeout(CONNECT_TIMEOUT, utp.connect(address)).await {
+                return Some(stream.into());
+            }
         }
+
+        let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+            .await
+            .ok()?
+            .ok()?;
+
+        Some(stream.into())
+    }
+
+    fn from_socket_addr(address: &SocketAddr) -> Self {
+        Self { address: *address }
+    }
+
+    /// The address in the compact form trackers use
+    fn to_compact(&self) -> Vec<u8> {
+        let mut compact = match self.address.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+
+        compact.extend_from_slice(&self.address.port().to_be_bytes());
+        compact
+    }
+
+    fn to_socket_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    fn is_ipv6(&self) -> bool {
+        self.address.is_ipv6()
     }
 }
 
+fn handshake(info_hash: Sha1, our_id: PeerId) -> Vec<u8> {
+    let mut reserved = [0u8; 8];
+    reserved[EXTENSION_PROTOCOL.0] |= EXTENSION_PROTOCOL.1;
+    reserved[FAST_EXTENSION.0] |= FAST_EXTENSION.1;
+
+    [19].into_iter()
+        .chain("BitTorrent protocol".as_bytes().iter().cloned())
+        .chain(reserved)
+        .chain(info_hash.as_ref().iter().cloned())
+        .chain(our_id.as_ref().iter().cloned())
+        .collect()
+}
+
+/// Read until at least a full handshake has arrived, returning everything
+/// read so far
+async fn read_handshake(stream: &mut PeerStream, buffer: &mut [u8]) -> Option<Vec<u8>> {
+    let mut received = Vec::new();
+
+    while received.len() < 68 {
+        let size = stream.read(buffer).await.ok()?;
+
+        if size == 0 {
+            return None;
+        }
+
+        received.extend_from_slice(&buffer[..size]);
+    }
+
+    if received[0] != 19 || &received[1..20] != "BitTorrent protocol".as_bytes() {
+        return None;
+    }
+
+    Some(received)
+}
+
+fn reserved_bytes(handshake: &[u8]) -> [u8; 8] {
+    let mut reserved = [0u8; 8];
+    reserved.copy_from_slice(&handshake[20..28]);
+    reserved
+}
+
 impl std::fmt::Display for PeerAddress {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}.{}.{}.{}:{}",
-            self.ip.0, self.ip.1, self.ip.2, self.ip.3, self.port
-        )
+        write!(f, "{}", self.address)
     }
 }
 
@@ -244,16 +589,29 @@ impl Bitfield {
         Self(Vec::from(bitfield))
     }
 
+    /// A bitfield for `piece_count` pieces, none of them set
+    fn with_length(piece_count: usize) -> Self {
+        Self(vec![0; piece_count.div_ceil(8)])
+    }
+
     fn has(&self, index: usize) -> Option<bool> {
-        if self.0.len() == 0 {
+        if self.0.is_empty() {
             return None;
         }
 
-        if index / 8 > self.0.len() {
+        if index / 8 >= self.0.len() {
             return Some(false);
         }
 
-        Some((self.0[index / 8] & (1u8 << (8 - index % 8))) != 0)
+        Some((self.0[index / 8] & (0x80 >> (index % 8))) != 0)
+    }
+
+    fn set(&mut self, index: usize) {
+        if index / 8 >= self.0.len() {
+            self.0.resize(index / 8 + 1, 0);
+        }
+
+        self.0[index / 8] |= 0x80 >> (index % 8);
     }
 }
 
@@ -263,122 +621,678 @@ pub enum DownloadError {
     PeerDoesNotHavePiece,
     ShaMismatch,
     PeerDisconnect,
-    IncorrectIndexReturned,
+    Choked,
+    AlreadyComplete,
+    Timeout,
+    OutOfPeers,
+    Interrupted,
+    // The peer said it will not send blocks we asked for
+    Rejected,
+    // The peer sent a message no peer following the protocol would
+    Protocol,
+    // The payload could not be written
+    Storage(std::io::Error),
 }
 
 #[derive(Debug)]
 pub struct PeerConnection {
-    stream: TcpStream,
+    stream: PeerStream,
     buffer: [u8; 64 * 1024],
+    // Bytes read from the stream that do not yet form a complete message
+    received: Vec<u8>,
+    // Pieces the peer has, sized for the torrent rather than by what the peer
+    // sends
     bitfield: Bitfield,
-    chocked: bool,
-    interested: bool,
+    piece_count: usize,
+    pipeline: Pipeline,
+    // Blocks the peer has asked us for, that we have not yet sent
+    requests: VecDeque<UploadRequest>,
+    // Where the blocks come from; without it nothing is sent
+    source: Option<Arc<dyn BlockSource>>,
+    // Whether we refuse to upload to the peer
+    am_choking: bool,
+    // Whether we want pieces the peer has
+    am_interested: bool,
+    // Whether the peer refuses to upload to us
+    peer_choking: bool,
+    // Whether the peer wants pieces we have
+    peer_interested: bool,
+    // Set for connections the peer made to us
+    slot: Option<ConnectionSlot>,
+    // Bytes of piece data received from and sent to the peer
+    downloaded: usize,
+    uploaded: usize,
+    // When the peer last sent us a block, or unchoked us
+    last_block: std::time::Instant,
+    // Reserved bytes of the peer's handshake, announcing what it supports
+    reserved: [u8; 8],
+    // Extensions negotiated through the extended handshake, and when they
+    // last had their say
+    extensions: Extensions,
+    last_tick: std::time::Instant,
+    // Set once the peer has said it has every piece, without a bitfield
+    has_all: bool,
+    // Pieces the peer lets us request while it chokes us, and pieces it
+    // suggests we request
+    allowed_fast: Vec<u32>,
+    suggested: Vec<u32>,
+    // Pieces we let the peer request while we choke it
+    our_allowed_fast: Vec<u32>,
 }
 
 impl PeerConnection {
-    fn new(stream: TcpStream, buffer: [u8; 64 * 1024]) -> Self {
+    fn new(stream: PeerStream, buffer: [u8; 64 * 1024], received: Vec<u8>) -> Self {
         Self {
             stream,
             buffer,
+            received,
             bitfield: Bitfield::new(),
-            chocked: true,
-            interested: false,
+            piece_count: 0,
+            pipeline: Pipeline::new(),
+            requests: VecDeque::new(),
+            source: None,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            slot: None,
+            downloaded: 0,
+            uploaded: 0,
+            last_block: std::time::Instant::now(),
+            reserved: [0; 8],
+            extensions: Extensions::new(),
+            last_tick: std::time::Instant::now(),
+            has_all: false,
+            allowed_fast: Vec::new(),
+            suggested: Vec::new(),
+            our_allowed_fast: Vec::new(),
+        }
+    }
+
+    fn with_slot(mut self, slot: ConnectionSlot) -> Self {
+        self.slot = Some(slot);
+        self
+    }
+
+    /// Answer the requests of the peer with blocks from `source`, as they
+    /// arrive
+    fn with_source(mut self, source: Arc<dyn BlockSource>) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Keep track of which of the torrent's `piece_count` pieces the peer has
+    fn with_piece_count(mut self, piece_count: usize) -> Self {
+        self.piece_count = piece_count;
+        self.bitfield = Bitfield::with_length(piece_count);
+        self
+    }
+
+    fn with_reserved(mut self, reserved: [u8; 8]) -> Self {
+        self.reserved = reserved;
+        self
+    }
+
+    fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL.0] & EXTENSION_PROTOCOL.1 != 0
+    }
+
+    fn supports_fast(&self) -> bool {
+        self.reserved[FAST_EXTENSION.0] & FAST_EXTENSION.1 != 0
+    }
+
+    /// Whether the peer lets us request `piece` while it chokes us
+    fn is_allowed_fast(&self, piece: usize) -> bool {
+        self.allowed_fast.contains(&(piece as u32))
+    }
+
+    fn has_allowed_fast(&self) -> bool {
+        !self.allowed_fast.is_empty()
+    }
+
+    fn is_suggested(&self, piece: usize) -> bool {
+        self.suggested.contains(&(piece as u32))
+    }
+
+    /// Let the peer request some pieces even while we choke it, so that a
+    /// newcomer gets something to trade with quickly
+    async fn allow_fast(&mut self, info_hash: Sha1, piece_count: usize) {
+        let ip = match self.stream.peer_addr() {
+            Ok(address) if self.supports_fast() => address.ip(),
+            _ => return,
+        };
+
+        self.our_allowed_fast = allowed_fast_set(ip, info_hash, piece_count, ALLOWED_FAST_COUNT);
+
+        for piece in self.our_allowed_fast.clone() {
+            self.send(PeerMessage::AllowedFast(piece)).await;
+        }
+    }
+
+    /// Where the peer accepts connections, if we know. Peers that connected
+    /// to us may have told us their port in the extended handshake.
+    fn listen_address(&self) -> Option<PeerAddress> {
+        let address = self.stream.peer_addr().ok()?;
+
+        if self.slot.is_none() {
+            return Some(PeerAddress::from_socket_addr(&address));
+        }
+
+        let port = self.extensions.peer_handshake()?.p?;
+
+        Some(PeerAddress::from_socket_addr(&SocketAddr::new(
+            address.ip(),
+            port,
+        )))
+    }
+
+    /// Use `extensions` on the connection, and tell the peer about them if
+    /// it supports the extension protocol
+    async fn start_extensions(&mut self, extensions: Extensions) {
+        self.extensions = extensions;
+
+        if self.supports_extensions() {
+            let peer_ip = self.stream.peer_addr().ok().map(|address| address.ip());
+            let handshake = self.extensions.handshake(peer_ip);
+
+            self.send(handshake).await;
         }
     }
 
-    pub fn download(&mut self, torrent: &Torrent, piece: usize) -> Result<Vec<u8>, DownloadError> {
-        if piece >= torrent.pieces.len() {
-            return Err(DownloadError::NoSuchPiece);
-        }
+    /// Download the blocks of a piece that are still missing from `progress`,
+    /// then verify the complete piece
+    pub async fn download(
+        &mut self,
+        torrent: &Torrent,
+        progress: &mut PartialPiece,
+        endgame: &Endgame,
+    ) -> Result<(), DownloadError> {
+        let piece = progress.index();
+
+        if piece >= torrent.pieces.len() {
+            return Err(DownloadError::NoSuchPiece);
+        }
+
+        if !self.has_piece(piece) {
+            return Err(DownloadError::PeerDoesNotHavePiece);
+        }
+
+        if !self.am_interested || (self.peer_choking && !self.is_allowed_fast(piece)) {
+            return Err(DownloadError::Choked);
+        }
+
+        let expected_hash = torrent.pieces[piece];
+
+        let mut unrequested: VecDeque<BlockRequest> = progress.missing().collect();
+        let mut rejected = Vec::new();
+
+        while !progress.is_complete() {
+            match endgame.sync(progress) {
+                Sync::Arrived(blocks) => {
+                    for block in blocks {
+                        if self.pipeline.cancel(block) {
+                            self.cancel(piece, block).await;
+                        }
+
+                        unrequested.retain(|&unrequested| unrequested != block);
+                    }
+                }
+
+                Sync::Finished => {
+                    for block in self.pipeline.clear() {
+                        self.cancel(piece, block).await;
+                    }
+
+                    return Err(DownloadError::AlreadyComplete);
+                }
+            }
+
+            if progress.is_complete() {
+                break;
+            }
+
+            while self.pipeline.has_room() {
+                let block = match unrequested.pop_front() {
+                    Some(block) => block,
+                    None => break,
+                };
+
+                self.request(piece, block).await;
+                self.pipeline.sent(block);
+            }
+
+            match self.pipeline.timed_out() {
+                Some(expired) => {
+                    for block in expired {
+                        self.request(piece, block).await;
+                    }
+                }
+
+                // The piece is put back, for other peers to send
+                None => {
+                    for block in self.pipeline.clear() {
+                        self.cancel(piece, block).await;
+                    }
+
+                    return Err(DownloadError::Timeout);
+                }
+            }
+
+            let message = match self.recv().await {
+                Ok(message) => message,
+                Err(DownloadError::Timeout) => continue,
+                Err(e) => return Err(e),
+            };
+
+            match message {
+                // Requests for allowed fast pieces survive a choke
+                PeerMessage::Choke if self.is_allowed_fast(piece) => (),
+
+                // A choke discards every other request we have sent, so the
+                // blocks have to be requested again once we are unchoked
+                PeerMessage::Choke => {
+                    self.pipeline.clear();
+                    return Err(DownloadError::Choked);
+                }
+
+                // Rejected blocks are requested again right away rather than
+                // after a timeout, but a peer rejecting the same block twice
+                // will not send it
+                PeerMessage::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                } if index == piece as u32 => {
+                    let block = BlockRequest { begin, length };
+
+                    if !self.pipeline.cancel(block) {
+                        continue;
+                    }
+
+                    if rejected.contains(&block) {
+                        for block in self.pipeline.clear() {
+                            self.cancel(piece, block).await;
+                        }
+
+                        return Err(DownloadError::Rejected);
+                    }
+
+                    rejected.push(block);
+                    unrequested.push_front(block);
+                }
+
+                PeerMessage::Piece {
+                    index,
+                    begin,
+                    piece: block,
+                } => {
+                    // Late answers to requests for an earlier piece are dropped
+                    if index != piece as u32 {
+                        endgame.wasted(block.len());
+                        continue;
+                    }
+
+                    let request = BlockRequest {
+                        begin,
+                        length: block.len() as u32,
+                    };
+
+                    match self.pipeline.received(request) {
+                        Arrival::Expected => (),
+                        Arrival::Duplicated => self.cancel(piece, request).await,
+                        Arrival::Unexpected => {
+                            endgame.wasted(block.len());
+                            continue;
+                        }
+                    }
+
+                    if progress.add_block(begin, &block) {
+                        self.downloaded += block.len();
+                        self.last_block = std::time::Instant::now();
+                    } else {
+                        endgame.wasted(block.len());
+                    }
+                }
+
+                _ => (),
+            }
+        }
+
+        if !expected_hash.matches(progress.data()) {
+            // Blocks shared by other peers may be the bad ones
+            endgame.discard(piece);
+            progress.reset();
+            return Err(DownloadError::ShaMismatch);
+        }
+
+        Ok(())
+    }
+
+    async fn request(&mut self, piece: usize, block: BlockRequest) {
+        self.send(PeerMessage::Request {
+            index: piece as u32,
+            begin: block.begin,
+            length: block.length,
+        })
+        .await;
+    }
+
+    async fn cancel(&mut self, piece: usize, block: BlockRequest) {
+        self.send(PeerMessage::Cancel {
+            index: piece as u32,
+            begin: block.begin,
+            length: block.length,
+        })
+        .await;
+    }
+
+    fn has_piece(&self, piece: usize) -> bool {
+        self.has_all || self.bitfield.has(piece) == Some(true)
+    }
+
+    /// Tell the peer whether we want anything from it, if that has changed
+    async fn set_interested(&mut self, interested: bool) {
+        if interested == self.am_interested {
+            return;
+        }
+
+        self.am_interested = interested;
+
+        self.send(if interested {
+            PeerMessage::Interested
+        } else {
+            PeerMessage::NotInterested
+        })
+        .await;
+    }
+
+    /// Tell the peer whether we will upload to it, if that has changed
+    async fn set_choking(&mut self, choking: bool) {
+        if choking == self.am_choking {
+            return;
+        }
+
+        self.am_choking = choking;
+
+        // Choking a peer discards whatever it has asked for, apart from
+        // allowed fast pieces; peers with the fast extension are told so
+        if choking {
+            let mut requests = std::mem::take(&mut self.requests);
+
+            while let Some(request) = requests.pop_front() {
+                if self.our_allowed_fast.contains(&request.index) {
+                    self.requests.push_back(request);
+                } else {
+                    self.reject(&request).await;
+                }
+            }
+        }
+
+        self.send(if choking {
+            PeerMessage::Choke
+        } else {
+            PeerMessage::Unchoke
+        })
+        .await;
+    }
+
+    /// Whether the peer lets us request blocks, but does not send them
+    fn is_snubbing(&self) -> bool {
+        self.am_interested && !self.peer_choking && self.last_block.elapsed() > SNUB_TIMEOUT
+    }
+
+    fn has_requests(&self) -> bool {
+        !self.requests.is_empty()
+    }
+
+    /// Answer the requests the peer has made so far with blocks from our
+    /// source, read away from the async threads
+    async fn serve(&mut self) {
+        let source = match &self.source {
+            Some(source) => Arc::clone(source),
+            None => return,
+        };
+
+        while let Some(request) = self.requests.pop_front() {
+            let source = Arc::clone(&source);
+            let read = tokio::task::spawn_blocking(move || source.read_block(&request));
+
+            match read.await.ok().flatten() {
+                Some(block) => {
+                    self.uploaded += block.len();
+
+                    self.send(PeerMessage::Piece {
+                        index: request.index,
+                        begin: request.begin,
+                        piece: block,
+                    })
+                    .await;
+                }
+
+                None => self.reject(&request).await,
+            }
+        }
+    }
+
+    /// Tell a peer with the fast extension that we will not serve a request;
+    /// others are left to find out for themselves
+    async fn reject(&mut self, request: &UploadRequest) {
+        if self.supports_fast() {
+            self.send(PeerMessage::RejectRequest {
+                index: request.index,
+                begin: request.begin,
+                length: request.length,
+            })
+            .await;
+        }
+    }
+
+    /// Wait until the peer lets us request pieces
+    async fn wait_for_unchoke(&mut self, timeout: Duration) -> Result<(), DownloadError> {
+        let deadline = std::time::Instant::now() + timeout;
+
+        while self.peer_choking {
+            if std::time::Instant::now() > deadline {
+                return Err(DownloadError::Choked);
+            }
+
+            match self.recv().await {
+                Ok(_) | Err(DownloadError::Timeout) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle whatever messages the peer has sent so far, without waiting for
+    /// more
+    async fn pump(&mut self) -> Result<(), DownloadError> {
+        loop {
+            match self.recv().await {
+                Ok(_) => (),
+                Err(DownloadError::Timeout) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn recv(&mut self) -> Result<PeerMessage, DownloadError> {
+        // However busy the connection, the extensions get their say
+        if self.last_tick.elapsed() >= EXTENSION_TICK {
+            self.last_tick = std::time::Instant::now();
+
+            for message in self.extensions.tick() {
+                self.send(message).await;
+            }
+        }
+
+        loop {
+            if let Some(message) = self.take_message()? {
+                match &message {
+                    PeerMessage::Choke => self.peer_choking = true,
+                    PeerMessage::Unchoke => {
+                        self.peer_choking = false;
+                        self.last_block = std::time::Instant::now();
+                    }
+                    PeerMessage::Interested => self.peer_interested = true,
+                    PeerMessage::NotInterested => self.peer_interested = false,
+                    // Pieces past the end of the torrent do not exist
+                    PeerMessage::Have(index) if (*index as usize) < self.piece_count => {
+                        self.bitfield.set(*index as usize)
+                    }
+
+                    PeerMessage::Have(_) => (),
+
+                    PeerMessage::Bitfield(field) => {
+                        self.bitfield = Bitfield::from(field);
+                        self.bitfield.0.resize(self.piece_count.div_ceil(8), 0);
+                    }
 
-        if let Some(false) = self.bitfield.has(piece) {
-            return Err(DownloadError::PeerDoesNotHavePiece);
-        }
+                    PeerMessage::HaveAll => self.has_all = true,
 
-        let expected_sha = torrent.pieces[piece];
+                    PeerMessage::HaveNone => {
+                        self.has_all = false;
+                        self.bitfield = Bitfield::with_length(self.piece_count);
+                    }
 
-        self.send(PeerMessage::Interested);
+                    PeerMessage::AllowedFast(index)
+                        if self.allowed_fast.len() < MAX_FAST_PIECES =>
+                    {
+                        self.allowed_fast.push(*index)
+                    }
 
-        let payload_left = match torrent.payload {
-            Payload::Single { name: _, length } => length - torrent.piece_length * piece,
-            Payload::Multi { name: _, files: _ } => todo!("Multi file torrents not supported"),
-        };
+                    PeerMessage::SuggestPiece(index) => {
+                        if self.suggested.len() >= MAX_FAST_PIECES {
+                            self.suggested.remove(0);
+                        }
 
-        let mut left = std::cmp::min(torrent.piece_length as u32, payload_left as u32);
-        let mut begin = 0;
+                        self.suggested.push(*index);
+                    }
 
-        let mut buffer = vec![0; left as usize];
+                    // Requests made while choked are not to be answered unless
+                    // for allowed fast pieces, and those beyond what we told
+                    // the peer we would queue are dropped. The rest are
+                    // answered right away, whatever else we are doing.
+                    PeerMessage::Request {
+                        index,
+                        begin,
+                        length,
+                    } => {
+                        let request = UploadRequest {
+                            index: *index,
+                            begin: *begin,
+                            length: *length,
+                        };
+
+                        let allowed = !self.am_choking || self.our_allowed_fast.contains(index);
+
+                        if allowed && self.requests.len() < MAX_QUEUED_REQUESTS {
+                            self.requests.push_back(request);
+                            self.serve().await;
+                        } else {
+                            self.reject(&request).await;
+                        }
+                    }
 
-        'outer: while left != 0 {
-            self.send(PeerMessage::Request {
-                index: piece as u32,
-                begin,
-                length: std::cmp::min(14 * 1024, left),
-            });
-
-            while let Ok(message) = self.recv() {
-                match message {
-                    PeerMessage::Piece {
-                        index: got_index,
-                        begin: got_begin,
-                        piece: got_piece,
+                    PeerMessage::Cancel {
+                        index,
+                        begin,
+                        length,
                     } => {
-                        if got_index != piece as u32 {
-                            return Err(DownloadError::IncorrectIndexReturned);
+                        let cancelled = UploadRequest {
+                            index: *index,
+                            begin: *begin,
+                            length: *length,
+                        };
+
+                        let queued = self.requests.len();
+                        self.requests.retain(|request| *request != cancelled);
+
+                        // Peers with the fast extension expect every request
+                        // to be answered
+                        if self.requests.len() != queued {
+                            self.reject(&cancelled).await;
                         }
+                    }
 
-                        if got_begin != begin {
-                            continue 'outer;
+                    PeerMessage::Extended { id, payload } => {
+                        for reply in self.extensions.receive(*id, payload) {
+                            self.send(reply).await;
                         }
 
-                        let range = begin as usize..begin as usize + got_piece.len();
-                        (&mut buffer[range]).copy_from_slice(&got_piece);
+                        // Keep within the number of requests the peer queues
+                        if *id == 0 {
+                            let reqq = self.extensions.peer_handshake().and_then(|peer| peer.reqq);
 
-                        begin += got_piece.len() as u32;
-                        left -= got_piece.len() as u32;
+                            if let Some(reqq) = reqq {
+                                self.pipeline.set_limit(reqq);
+                            }
+                        }
                     }
 
                     _ => (),
                 }
+
+                return Ok(message);
             }
-        }
 
-        self.send(PeerMessage::NotInterested);
+            let size = match timeout(RECV_TIMEOUT, self.stream.read(&mut self.buffer)).await {
+                Err(_) => return Err(DownloadError::Timeout),
+                Ok(Ok(0)) | Ok(Err(_)) => return Err(DownloadError::PeerDisconnect),
+                Ok(Ok(size)) => size,
+            };
 
-        let calculated_sha = Sha1::digest(&buffer);
-        if calculated_sha != expected_sha {
-            return Err(DownloadError::ShaMismatch);
+            self.received.extend_from_slice(&self.buffer[..size]);
         }
-
-        Ok(buffer)
     }
 
-    fn recv(&mut self) -> Result<PeerMessage, DownloadError> {
-        let size = self
-            .stream
-            .read(&mut self.buffer)
-            .map_err(|_| DownloadError::PeerDisconnect)?;
+    /// The next whole message received, if any. Messages that cannot be
+    /// made sense of are taken as keep-alives.
+    fn take_message(&mut self) -> Result<Option<PeerMessage>, DownloadError> {
+        let frame_size = match frame_size(&self.received) {
+            Ok(Some(frame_size)) => frame_size,
+            Ok(None) => return Ok(None),
 
-        let message = PeerMessage::parse(&self.buffer[..size]).unwrap_or(PeerMessage::KeepAlive);
+            Err(e) => {
+                self.received.clear();
+                return Err(e);
+            }
+        };
 
-        match &message {
-            PeerMessage::Choke => self.chocked = true,
-            PeerMessage::Unchoke => self.chocked = false,
-            PeerMessage::Interested => self.interested = true,
-            PeerMessage::NotInterested => self.interested = false,
-            PeerMessage::Bitfield(field) => self.bitfield = Bitfield::from(field),
-            _ => (),
-        }
+        let frame: Vec<u8> = self.received.drain(..frame_size).collect();
 
-        Ok(message)
+        Ok(Some(
+            PeerMessage::parse(&frame).unwrap_or(PeerMessage::KeepAlive),
+        ))
     }
 
-    fn send(&mut self, message: PeerMessage) {
+    async fn send(&mut self, message: PeerMessage) {
         let bytes = Vec::from(message);
-        self.stream.write(&bytes).ok();
+        self.stream.write_all(&bytes).await.ok();
     }
 }
 
+/// Where the blocks peers ask us for come from
+trait BlockSource: Send + std::marker::Sync {
+    /// The block asked for, if we have it and the request makes sense. May
+    /// block on disk reads.
+    fn read_block(&self, request: &UploadRequest) -> Option<Vec<u8>>;
+}
+
+impl std::fmt::Debug for dyn BlockSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BlockSource")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct UploadRequest {
+    index: u32,
+    begin: u32,
+    length: u32,
+}
+
 #[derive(Debug)]
 pub enum PeerMessage {
     KeepAlive,
@@ -403,14 +1317,29 @@ pub enum PeerMessage {
         begin: u32,
         length: u32,
     },
+    // BEP 6
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast(u32),
+    // BEP 10; id 0 is the extended handshake
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
 }
 
 impl From<PeerMessage> for Vec<u8> {
     fn from(msg: PeerMessage) -> Self {
-        let capacity = if let PeerMessage::Bitfield(field) = &msg {
-            5 + field.len()
-        } else {
-            17
+        let capacity = match &msg {
+            PeerMessage::Bitfield(field) => 5 + field.len(),
+            PeerMessage::Extended { id: _, payload } => 6 + payload.len(),
+            _ => 17,
         };
 
         let mut bytes = Vec::with_capacity(capacity);
@@ -447,7 +1376,7 @@ impl From<PeerMessage> for Vec<u8> {
 
             PeerMessage::Bitfield(mut field) => {
                 bytes.clear();
-                bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(&(1 + field.len() as u32).to_be_bytes());
                 bytes.push(5);
                 bytes.append(&mut field);
             }
@@ -469,7 +1398,8 @@ impl From<PeerMessage> for Vec<u8> {
                 begin,
                 mut piece,
             } => {
-                bytes[3] = 13;
+                bytes.clear();
+                bytes.extend_from_slice(&(9 + piece.len() as u32).to_be_bytes());
                 bytes.push(7);
                 bytes.extend_from_slice(&index.to_be_bytes());
                 bytes.extend_from_slice(&begin.to_be_bytes());
@@ -487,6 +1417,48 @@ impl From<PeerMessage> for Vec<u8> {
                 bytes.extend_from_slice(&begin.to_be_bytes());
                 bytes.extend_from_slice(&length.to_be_bytes());
             }
+
+            PeerMessage::SuggestPiece(index) => {
+                bytes[3] = 5;
+                bytes.push(13);
+                bytes.extend_from_slice(&index.to_be_bytes());
+            }
+
+            PeerMessage::HaveAll => {
+                bytes[3] = 1;
+                bytes.push(14);
+            }
+
+            PeerMessage::HaveNone => {
+                bytes[3] = 1;
+                bytes.push(15);
+            }
+
+            PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                bytes[3] = 13;
+                bytes.push(16);
+                bytes.extend_from_slice(&index.to_be_bytes());
+                bytes.extend_from_slice(&begin.to_be_bytes());
+                bytes.extend_from_slice(&length.to_be_bytes());
+            }
+
+            PeerMessage::AllowedFast(index) => {
+                bytes[3] = 5;
+                bytes.push(17);
+                bytes.extend_from_slice(&index.to_be_bytes());
+            }
+
+            PeerMessage::Extended { id, mut payload } => {
+                bytes.clear();
+                bytes.extend_from_slice(&(2 + payload.len() as u32).to_be_bytes());
+                bytes.push(20);
+                bytes.push(id);
+                bytes.append(&mut payload);
+            }
         }
 
         bytes
@@ -494,15 +1466,15 @@ impl From<PeerMessage> for Vec<u8> {
 }
 
 impl PeerMessage {
+    /// Parse a whole frame, length prefix included. None for unknown
+    /// messages, and for those too short for what they should hold.
     fn parse(buffer: &[u8]) -> Option<Self> {
         if buffer.len() < 5 {
             return Some(Self::KeepAlive);
         }
 
         let mut u32buffer = [0u8; 4];
-        u32buffer.copy_from_slice(&buffer[0..4]);
-
-        let length = u32::from_be_bytes(u32buffer);
+        let payload = &buffer[5..];
 
         match buffer[4] {
             0 => Some(Self::Choke),
@@ -510,17 +1482,15 @@ impl PeerMessage {
             2 => Some(Self::Interested),
             3 => Some(Self::NotInterested),
 
-            4 => {
-                u32buffer.copy_from_slice(&buffer[5..9]);
+            4 if payload.len() >= 4 => {
+                u32buffer.copy_from_slice(&payload[0..4]);
                 Some(Self::Have(u32::from_be_bytes(u32buffer)))
             }
 
-            5 => Some(Self::Bitfield(Vec::from(
-                &buffer[5..5 + length as usize - 1],
-            ))),
+            5 => Some(Self::Bitfield(Vec::from(payload))),
 
-            6 => {
-                let (index, begin, length) = extract_u32_triplet(&buffer[5..]);
+            6 if payload.len() >= 12 => {
+                let (index, begin, length) = extract_u32_triplet(payload);
                 Some(Self::Request {
                     index,
                     begin,
@@ -528,16 +1498,16 @@ impl PeerMessage {
                 })
             }
 
-            7 => {
+            7 if payload.len() >= 8 => {
                 let mut bytes = [0u8; 4];
 
-                bytes.copy_from_slice(&buffer[5..9]);
+                bytes.copy_from_slice(&payload[0..4]);
                 let index = u32::from_be_bytes(bytes);
 
-                bytes.copy_from_slice(&buffer[9..13]);
+                bytes.copy_from_slice(&payload[4..8]);
                 let begin = u32::from_be_bytes(bytes);
 
-                let piece = Vec::from(&buffer[13..]);
+                let piece = Vec::from(&payload[8..]);
 
                 Some(Self::Piece {
                     index,
@@ -546,8 +1516,8 @@ impl PeerMessage {
                 })
             }
 
-            8 => {
-                let (index, begin, length) = extract_u32_triplet(&buffer[5..]);
+            8 if payload.len() >= 12 => {
+                let (index, begin, length) = extract_u32_triplet(payload);
                 Some(Self::Cancel {
                     index,
                     begin,
@@ -555,11 +1525,58 @@ impl PeerMessage {
                 })
             }
 
+            13 if payload.len() >= 4 => {
+                u32buffer.copy_from_slice(&payload[0..4]);
+                Some(Self::SuggestPiece(u32::from_be_bytes(u32buffer)))
+            }
+
+            14 => Some(Self::HaveAll),
+            15 => Some(Self::HaveNone),
+
+            16 if payload.len() >= 12 => {
+                let (index, begin, length) = extract_u32_triplet(payload);
+                Some(Self::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                })
+            }
+
+            17 if payload.len() >= 4 => {
+                u32buffer.copy_from_slice(&payload[0..4]);
+                Some(Self::AllowedFast(u32::from_be_bytes(u32buffer)))
+            }
+
+            20 if !payload.is_empty() => Some(Self::Extended {
+                id: payload[0],
+                payload: Vec::from(&payload[1..]),
+            }),
+
             _ => None,
         }
     }
 }
 
+/// Length of the frame at the start of `received`, length prefix included,
+/// once all of it is there
+fn frame_size(received: &[u8]) -> Result<Option<usize>, DownloadError> {
+    if received.len() < 4 {
+        return Ok(None);
+    }
+
+    let mut u32buffer = [0u8; 4];
+    u32buffer.copy_from_slice(&received[0..4]);
+
+    let length = u32::from_be_bytes(u32buffer) as usize;
+
+    // Rather than buffer whatever the peer claims to send
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(DownloadError::Protocol);
+    }
+
+    Ok((received.len() >= 4 + length).then_some(4 + length))
+}
+
 fn extract_u32_triplet(buffer: &[u8]) -> (u32, u32, u32) {
     let mut bytes = [0u8; 4];
 
@@ -575,13 +1592,20 @@ fn extract_u32_triplet(buffer: &[u8]) -> (u32, u32, u32) {
     (a, b, c)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum Payload {
     Single { name: String, length: usize },
     Multi { name: String, files: Vec<File> },
 }
 
 impl Payload {
+    fn length(&self) -> usize {
+        match self {
+            Self::Single { name: _, length } => *length,
+            Self::Multi { name: _, files } => files.iter().map(|file| file.length).sum(),
+        }
+    }
+
     fn new(name: String, info: &Bencoded) -> Self {
         let length = get_int(info, "length");
         let files = get_list(info, "files");
@@ -604,10 +1628,33 @@ impl Payload {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct File {
     path: Vec<String>,
     length: usize,
+    attributes: Attributes,
+    priority: Priority,
+}
+
+/// How eagerly the pieces of a file are downloaded, if at all
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// What the `attr` entry of a file says about it (BEP 47)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Attributes {
+    // Only there to align the next file, and all zeros
+    padding: bool,
+    executable: bool,
+    hidden: bool,
+    // Where the file links to, as a path from the top of the payload
+    symlink: Option<Vec<String>>,
 }
 
 impl File {
@@ -622,21 +1669,47 @@ impl File {
             .expect("Multi file entry does not have a `length` entry");
 
         let path = get_list(&file_dict, "path")
-            .expect("Multi file entry does not have a `path` entry")
-            .into_iter()
-            .map(|sub_path| {
-                if let Bencoded::Bstr(sub_path) = sub_path {
-                    String::from_utf8(sub_path).expect("Multi file sub path is not valid UTF-8")
-                } else {
-                    panic!("Multi file sub path is not a byte string");
-                }
-            })
-            .collect();
+            .map(file_path)
+            .expect("Multi file entry does not have a `path` entry");
+
+        let attr = get_bstr(&file_dict, "attr").unwrap_or_default();
+
+        // A link without a target is of no use, so it is kept as a plain file
+        let symlink = if attr.contains(&b'l') {
+            get_list(&file_dict, "symlink path").map(file_path)
+        } else {
+            None
+        };
+
+        let attributes = Attributes {
+            padding: attr.contains(&b'p'),
+            executable: attr.contains(&b'x'),
+            hidden: attr.contains(&b'h'),
+            symlink,
+        };
 
-        Self { path, length }
+        Self {
+            path,
+            length,
+            attributes,
+            priority: Priority::default(),
+        }
     }
 }
 
+fn file_path(components: Vec<Bencoded>) -> Vec<String> {
+    components
+        .into_iter()
+        .map(|sub_path| {
+            if let Bencoded::Bstr(sub_path) = sub_path {
+                String::from_utf8(sub_path).expect("Multi file sub path is not valid UTF-8")
+            } else {
+                panic!("Multi file sub path is not a byte string");
+            }
+        })
+        .collect()
+}
+
 // Helper functions
 
 fn get_bencoded_dict_value(dict: &Bencoded, key: &str) -> Option<Bencoded> {
@@ -681,3 +1754,546 @@ fn get_dict(dict: &Bencoded, key: &str) -> Option<Bencoded> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::task::JoinHandle;
+
+    use super::download::DownloadStats;
+    use super::pipeline::BLOCK_SIZE;
+    use super::storage::{Layout, MemoryStorage};
+    use super::*;
+
+    fn frame(id: u8, payload: &[u8]) -> Vec<u8> {
+        let length = (payload.len() + 1) as u32;
+        [&length.to_be_bytes()[..], &[id], payload].concat()
+    }
+
+    /// A connection to a peer, and the peer's end of it
+    async fn connected() -> (PeerConnection, TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ours = TcpStream::connect(listener.local_addr().unwrap());
+        let (ours, theirs) = tokio::join!(ours, listener.accept());
+
+        let peer = PeerConnection::new(ours.unwrap().into(), [0; 64 * 1024], Vec::new());
+        (peer, theirs.unwrap().0)
+    }
+
+    /// A single file torrent of `length` bytes in pieces of `piece_length`,
+    /// and its payload
+    fn small_torrent(length: usize, piece_length: usize) -> (Torrent, Vec<u8>) {
+        let data: Vec<u8> = (0..length).map(|byte| byte as u8).collect();
+
+        let torrent = Torrent {
+            announce: String::new(),
+            info_hash: Sha1::digest(b"info"),
+            piece_length,
+            pieces: data
+                .chunks(piece_length)
+                .map(|piece| PieceHash::Sha1(Sha1::digest(piece)))
+                .collect(),
+            private: false,
+            payload: Payload::Single {
+                name: String::from("payload"),
+                length,
+            },
+            info: RawInfo(Arc::new(Vec::new())),
+            web_seeds: Vec::new(),
+        };
+
+        (torrent, data)
+    }
+
+    /// The whole payload of `torrent`, in memory
+    fn stored(torrent: &Torrent, data: &[u8]) -> MemoryStorage {
+        let mut storage = MemoryStorage::new(Layout::new(torrent));
+
+        for (piece, chunk) in data.chunks(torrent.piece_length).enumerate() {
+            storage.write_block(piece, 0, chunk).unwrap();
+        }
+
+        storage
+    }
+
+    /// Start downloading `torrent` into `storage`, keeping the pieces already
+    /// there that match their hashes, from `peers` peers one at a time. The
+    /// peers are waited for on the listeners handed back.
+    async fn start(
+        torrent: &Torrent,
+        storage: MemoryStorage,
+        seeding: bool,
+        peers: usize,
+    ) -> (
+        JoinHandle<Result<DownloadStats, DownloadError>>,
+        Vec<tokio::net::TcpListener>,
+    ) {
+        let mut listeners = Vec::new();
+
+        for _ in 0..peers {
+            listeners.push(tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+
+        let peer_list = PeerList {
+            our_id: PeerId::new(),
+            expected_info_hash: torrent.info_hash,
+            interval: 0,
+            peers: listeners
+                .iter()
+                .map(|listener| PeerAddress::from_socket_addr(&listener.local_addr().unwrap()))
+                .collect(),
+        };
+
+        // The resume file goes nowhere, as its directory does not exist
+        let root = std::env::temp_dir().join(format!("missing-{}", std::process::id()));
+
+        let downloader = Downloader::new(torrent, peer_list, 1)
+            .with_seeding(seeding)
+            .with_encryption(Encryption::Plaintext)
+            .with_resume(Resume::new(torrent, root), Startup::Recheck(Vec::new()));
+
+        (tokio::spawn(downloader.run(storage)), listeners)
+    }
+
+    /// Take the connection the download makes to a peer with handshake bytes
+    /// `reserved`, handing back the peer's end
+    async fn join(
+        listener: &tokio::net::TcpListener,
+        torrent: &Torrent,
+        reserved: [u8; 8],
+    ) -> TcpStream {
+        let (mut remote, _) = listener.accept().await.unwrap();
+
+        let mut theirs = [0u8; 68];
+        remote.read_exact(&mut theirs).await.unwrap();
+
+        let mut ours = handshake(torrent.info_hash, PeerId::new());
+        ours[20..28].copy_from_slice(&reserved);
+        remote.write_all(&ours).await.unwrap();
+
+        remote
+    }
+
+    async fn read_message(remote: &mut TcpStream) -> PeerMessage {
+        let read = async {
+            let mut length = [0u8; 4];
+            remote.read_exact(&mut length).await.unwrap();
+
+            let mut frame = length.to_vec();
+            frame.resize(4 + u32::from_be_bytes(length) as usize, 0);
+            remote.read_exact(&mut frame[4..]).await.unwrap();
+
+            PeerMessage::parse(&frame).unwrap()
+        };
+
+        timeout(Duration::from_secs(5), read)
+            .await
+            .expect("No message from the download")
+    }
+
+    async fn send_message(remote: &mut TcpStream, message: PeerMessage) {
+        remote.write_all(&Vec::from(message)).await.unwrap();
+    }
+
+    #[test]
+    fn messages_survive_a_round_trip() {
+        let request = Vec::from(PeerMessage::Request {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        });
+
+        assert!(matches!(
+            PeerMessage::parse(&request),
+            Some(PeerMessage::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384
+            })
+        ));
+
+        let piece = Vec::from(PeerMessage::Piece {
+            index: 2,
+            begin: 0,
+            piece: vec![7; 10],
+        });
+
+        match PeerMessage::parse(&piece) {
+            Some(PeerMessage::Piece {
+                index: 2,
+                begin: 0,
+                piece,
+            }) => assert_eq!(piece, vec![7; 10]),
+            other => panic!("Parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncated_messages_are_not_parsed() {
+        // Have, request, piece, cancel, suggest, reject, allowed fast and
+        // extended, each one byte short of the least they hold
+        for (id, least) in [
+            (4, 4),
+            (6, 12),
+            (7, 8),
+            (8, 12),
+            (13, 4),
+            (16, 12),
+            (17, 4),
+            (20, 1),
+        ] {
+            assert!(
+                PeerMessage::parse(&frame(id, &vec![0; least - 1])).is_none(),
+                "Message {} parsed",
+                id
+            );
+
+            assert!(
+                PeerMessage::parse(&frame(id, &vec![0; least])).is_some(),
+                "Message {} not parsed",
+                id
+            );
+        }
+
+        assert!(matches!(
+            PeerMessage::parse(&frame(5, &[])),
+            Some(PeerMessage::Bitfield(bitfield)) if bitfield.is_empty()
+        ));
+
+        assert!(matches!(
+            PeerMessage::parse(&[0, 0, 0, 0]),
+            Some(PeerMessage::KeepAlive)
+        ));
+    }
+
+    #[test]
+    fn frames_are_taken_whole_and_bounded() {
+        let have = frame(4, &[0, 0, 0, 9]);
+
+        assert_eq!(frame_size(&have[..3]).unwrap(), None);
+        assert_eq!(frame_size(&have[..8]).unwrap(), None);
+        assert_eq!(frame_size(&have).unwrap(), Some(9));
+        assert_eq!(frame_size(&[have.clone(), have].concat()).unwrap(), Some(9));
+
+        // A peer cannot have us wait for gigabytes
+        assert!(matches!(
+            frame_size(&u32::MAX.to_be_bytes()),
+            Err(DownloadError::Protocol)
+        ));
+
+        let longest = (MAX_MESSAGE_LENGTH as u32).to_be_bytes();
+        assert_eq!(frame_size(&longest).unwrap(), None);
+    }
+
+    #[test]
+    fn file_priorities_leave_out_padding_files() {
+        let file = |name: &str, padding| File {
+            path: vec![String::from(name)],
+            length: 1,
+            attributes: Attributes {
+                padding,
+                ..Attributes::default()
+            },
+            priority: Priority::Normal,
+        };
+
+        let mut torrent = Torrent {
+            announce: String::new(),
+            info_hash: Sha1::digest(b"info"),
+            piece_length: 4,
+            pieces: vec![PieceHash::Sha1(Sha1::digest(b"piece"))],
+            private: false,
+            payload: Payload::Multi {
+                name: String::from("payload"),
+                files: vec![
+                    file("first", false),
+                    file(".pad", true),
+                    file("second", false),
+                ],
+            },
+            info: RawInfo(Arc::new(Vec::new())),
+            web_seeds: Vec::new(),
+        };
+
+        assert!(torrent.set_file_priority(1, Priority::Skip));
+        assert!(!torrent.set_file_priority(2, Priority::Skip));
+
+        let Payload::Multi { files, .. } = &torrent.payload else {
+            unreachable!()
+        };
+        let priorities: Vec<Priority> = files.iter().map(|file| file.priority).collect();
+        assert_eq!(
+            priorities,
+            [Priority::Normal, Priority::Normal, Priority::Skip]
+        );
+
+        torrent.payload = Payload::Single {
+            name: String::from("payload"),
+            length: 4,
+        };
+        assert!(!torrent.set_file_priority(0, Priority::Skip));
+    }
+
+    #[tokio::test]
+    async fn have_messages_past_the_last_piece_are_ignored() {
+        let (peer, mut remote) = connected().await;
+        let mut peer = peer.with_piece_count(10);
+
+        for index in [9, 10, u32::MAX] {
+            let have = Vec::from(PeerMessage::Have(index));
+            remote.write_all(&have).await.unwrap();
+            peer.recv().await.unwrap();
+        }
+
+        assert!(peer.has_piece(9));
+        assert!(!peer.has_piece(10));
+        assert_eq!(peer.bitfield.0.len(), 2);
+    }
+
+    /// Ask the download for a block, and wait for the answer
+    async fn ask(remote: &mut TcpStream, index: u32, begin: u32, length: u32) -> PeerMessage {
+        send_message(
+            remote,
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            },
+        )
+        .await;
+
+        loop {
+            match read_message(remote).await {
+                message @ (PeerMessage::Piece { .. } | PeerMessage::RejectRequest { .. }) => {
+                    return message
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    fn fast_extension() -> [u8; 8] {
+        let mut reserved = [0; 8];
+        reserved[FAST_EXTENSION.0] |= FAST_EXTENSION.1;
+        reserved
+    }
+
+    #[tokio::test]
+    async fn requests_are_served_from_verified_pieces_only() {
+        let (torrent, data) = small_torrent(30, 8);
+
+        // Piece 2 is corrupt, and so not ours to serve
+        let mut storage = stored(&torrent, &data);
+        storage.write_block(2, 0, &[0; 8]).unwrap();
+
+        let (download, peers) = start(&torrent, storage, false, 1).await;
+        let mut remote = join(&peers[0], &torrent, fast_extension()).await;
+        send_message(&mut remote, PeerMessage::HaveNone).await;
+
+        assert!(matches!(
+            ask(&mut remote, 0, 2, 6).await,
+            PeerMessage::Piece { index: 0, begin: 2, piece } if piece == data[2..8]
+        ));
+
+        assert!(matches!(
+            ask(&mut remote, 3, 4, 2).await,
+            PeerMessage::Piece { index: 3, begin: 4, piece } if piece == data[28..]
+        ));
+
+        // Unverified and missing pieces, blocks past the end of a piece and
+        // empty blocks are all refused
+        for (index, begin, length) in [(2, 0, 8), (9, 0, 8), (1, 4, 8), (3, 0, 8), (1, 0, 0)] {
+            assert!(
+                matches!(
+                    ask(&mut remote, index, begin, length).await,
+                    PeerMessage::RejectRequest { .. }
+                ),
+                "Request for {} bytes at {} of piece {} served",
+                length,
+                begin,
+                index
+            );
+        }
+
+        download.abort();
+    }
+
+    #[tokio::test]
+    async fn seeds_keep_serving_once_complete() {
+        let (torrent, data) = small_torrent(30, 8);
+
+        // Without seeding, there is nothing left to do
+        let (download, _peers) = start(&torrent, stored(&torrent, &data), false, 1).await;
+        let stats = timeout(Duration::from_secs(5), download).await.unwrap();
+        assert!(stats.unwrap().is_ok());
+
+        let (download, peers) = start(&torrent, stored(&torrent, &data), true, 1).await;
+        let mut remote = join(&peers[0], &torrent, fast_extension()).await;
+        send_message(&mut remote, PeerMessage::HaveNone).await;
+
+        // Everything is announced at once
+        assert!(matches!(
+            read_message(&mut remote).await,
+            PeerMessage::HaveAll
+        ));
+
+        assert!(matches!(
+            ask(&mut remote, 1, 0, 8).await,
+            PeerMessage::Piece { index: 1, begin: 0, piece } if piece == data[8..16]
+        ));
+
+        assert!(!download.is_finished());
+        download.abort();
+    }
+
+    #[tokio::test]
+    async fn nothing_is_requested_while_choked() {
+        // A single piece of two blocks
+        let length = 2 * BLOCK_SIZE as usize;
+        let (torrent, _) = small_torrent(length, length);
+        let storage = MemoryStorage::new(Layout::new(&torrent));
+
+        let (download, peers) = start(&torrent, storage, false, 1).await;
+        let mut remote = join(&peers[0], &torrent, [0; 8]).await;
+        send_message(&mut remote, PeerMessage::Bitfield(vec![0x80])).await;
+
+        assert!(matches!(
+            read_message(&mut remote).await,
+            PeerMessage::Interested
+        ));
+
+        let requested = timeout(Duration::from_secs(1), async {
+            while !matches!(read_message(&mut remote).await, PeerMessage::Request { .. }) {}
+        });
+        assert!(requested.await.is_err());
+
+        send_message(&mut remote, PeerMessage::Unchoke).await;
+
+        assert!(matches!(
+            read_message(&mut remote).await,
+            PeerMessage::Request { index: 0, .. }
+        ));
+
+        download.abort();
+    }
+
+    #[tokio::test]
+    async fn pieces_of_departed_peers_go_back_to_the_pool() {
+        let block = BLOCK_SIZE as usize;
+        let (torrent, data) = small_torrent(2 * block, 2 * block);
+        let storage = MemoryStorage::new(Layout::new(&torrent));
+
+        let (download, peers) = start(&torrent, storage, false, 2).await;
+
+        // The first peer sends half of the piece, then goes away
+        let mut first = join(&peers[0], &torrent, [0; 8]).await;
+        send_message(&mut first, PeerMessage::Bitfield(vec![0x80])).await;
+        send_message(&mut first, PeerMessage::Unchoke).await;
+
+        while !matches!(
+            read_message(&mut first).await,
+            PeerMessage::Request { begin: 0, .. }
+        ) {}
+
+        let piece = data[..block].to_vec();
+        send_message(
+            &mut first,
+            PeerMessage::Piece {
+                index: 0,
+                begin: 0,
+                piece,
+            },
+        )
+        .await;
+        drop(first);
+
+        // The next one is only asked for the rest
+        let mut second = join(&peers[1], &torrent, [0; 8]).await;
+        send_message(&mut second, PeerMessage::Bitfield(vec![0x80])).await;
+        send_message(&mut second, PeerMessage::Unchoke).await;
+
+        let begin = loop {
+            if let PeerMessage::Request { begin, .. } = read_message(&mut second).await {
+                break begin;
+            }
+        };
+        assert_eq!(begin, BLOCK_SIZE);
+
+        let piece = data[block..].to_vec();
+        send_message(
+            &mut second,
+            PeerMessage::Piece {
+                index: 0,
+                begin,
+                piece,
+            },
+        )
+        .await;
+
+        let stats = timeout(Duration::from_secs(5), download).await.unwrap();
+        assert!(stats.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn busy_peers_are_still_sent_peer_exchange() {
+        let block = BLOCK_SIZE as usize;
+        let (torrent, data) = small_torrent(2 * block, 2 * block);
+
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_PROTOCOL.0] |= EXTENSION_PROTOCOL.1;
+
+        let (peer, mut remote) = connected().await;
+        let mut peer = peer.with_reserved(reserved).with_piece_count(1);
+
+        // Some other peer is worth telling about
+        let exchange = Arc::new(PeerExchange::new());
+        let other = PeerAddress::from_socket_addr(&"10.0.0.1:6881".parse().unwrap());
+        exchange.connected(other, 0);
+
+        let pex = UtPex::new(exchange, None);
+        peer.start_extensions(Extensions::new().with(Box::new(pex)))
+            .await;
+
+        let handshake = Bencoded::dict(vec![(
+            "m",
+            Bencoded::dict(vec![("ut_pex", Bencoded::Int(1))]),
+        )]);
+
+        send_message(
+            &mut remote,
+            PeerMessage::Extended {
+                id: 0,
+                payload: (&handshake).into(),
+            },
+        )
+        .await;
+        send_message(&mut remote, PeerMessage::Bitfield(vec![0x80])).await;
+        send_message(&mut remote, PeerMessage::Unchoke).await;
+
+        peer.set_interested(true).await;
+        peer.wait_for_unchoke(Duration::from_secs(5)).await.unwrap();
+
+        // The blocks are held back until peer exchange has had its turn
+        let seed = async {
+            while !matches!(
+                read_message(&mut remote).await,
+                PeerMessage::Extended { id: 1, .. }
+            ) {}
+
+            for (begin, piece) in data.chunks(block).enumerate() {
+                let message = PeerMessage::Piece {
+                    index: 0,
+                    begin: (begin * block) as u32,
+                    piece: piece.to_vec(),
+                };
+
+                send_message(&mut remote, message).await;
+            }
+        };
+
+        let mut progress = PartialPiece::new(0, 2 * block);
+        let endgame = Endgame::new();
+
+        let (downloaded, ()) = tokio::join!(peer.download(&torrent, &mut progress, &endgame), seed);
+        downloaded.unwrap();
+    }
+}
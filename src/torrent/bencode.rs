@@ -9,10 +9,25 @@ pub enum Bencoded {
 }
 
 impl Bencoded {
+    /// A dictionary with the given string keys
+    pub fn dict(pairs: Vec<(&str, Bencoded)>) -> Self {
+        Self::Dict(
+            pairs
+                .into_iter()
+                .map(|(key, value)| (Self::from(key), value))
+                .collect(),
+        )
+    }
+
     pub fn parse(encoded: &[u8]) -> Option<Self> {
         Bencoded::do_parse(encoded).map(|(value, _)| value)
     }
 
+    /// Parse a value at the start of `encoded`, returning whatever follows it
+    pub fn parse_prefix(encoded: &[u8]) -> Option<(Self, &[u8])> {
+        Bencoded::do_parse(encoded)
+    }
+
     fn do_parse(encoded: &[u8]) -> Option<(Self, &[u8])> {
         match encoded.iter().next() {
             Some(b'i') => {
@@ -20,8 +35,7 @@ impl Bencoded {
 
                 String::from_utf8(Vec::from(&encoded[1..end]))
                     .ok()
-                    .map(|string| string.parse::<i64>().ok())
-                    .flatten()
+                    .and_then(|string| string.parse::<i64>().ok())
                     .map(|int| (Self::Int(int), &encoded[end + 1..]))
             }
 
@@ -55,8 +69,7 @@ impl Bencoded {
 
                 let size = String::from_utf8(Vec::from(&encoded[..split_index]))
                     .ok()
-                    .map(|string| string.parse::<usize>().ok())
-                    .flatten()?;
+                    .and_then(|string| string.parse::<usize>().ok())?;
 
                 if encoded.len() - split_index - 1 < size {
                     return None;
@@ -115,6 +128,12 @@ impl fmt::Display for Bencoded {
     }
 }
 
+impl From<&str> for Bencoded {
+    fn from(string: &str) -> Self {
+        Self::Bstr(Vec::from(string.as_bytes()))
+    }
+}
+
 impl From<&Bencoded> for Vec<u8> {
     fn from(value: &Bencoded) -> Self {
         match value {
@@ -128,12 +147,12 @@ impl From<&Bencoded> for Vec<u8> {
                 .cloned()
                 .collect(),
 
-            Bencoded::Int(int) => format!("i{}e", int).as_bytes().iter().cloned().collect(),
+            Bencoded::Int(int) => format!("i{}e", int).as_bytes().to_vec(),
 
             Bencoded::List(list) => {
                 let mut vec = vec![b'l'];
 
-                for item in list.into_iter() {
+                for item in list.iter() {
                     let inner: Vec<u8> = item.into();
                     vec.extend_from_slice(&inner);
                 }
@@ -165,13 +184,13 @@ impl From<&Bencoded> for Vec<u8> {
 fn parse_bencoded_list(mut encoded: &[u8]) -> Option<(Vec<Bencoded>, &[u8])> {
     let mut list = Vec::new();
 
-    while encoded.len() > 0 && *encoded.iter().next().unwrap() != b'e' {
+    while !encoded.is_empty() && encoded[0] != b'e' {
         let (item, rest) = Bencoded::do_parse(encoded)?;
         list.push(item);
         encoded = rest;
     }
 
-    matches!(encoded.iter().next().as_deref(), Some(b'e')).then_some((list, &encoded[1..]))
+    matches!(encoded.first(), Some(b'e')).then_some((list, &encoded[1..]))
 }
 
 fn compare_bencoded(lhs: &Bencoded, rhs: &Bencoded) -> std::cmp::Ordering {
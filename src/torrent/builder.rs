@@ -0,0 +1,382 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{Bencoded, Sha1};
+
+// Bounds for the piece length picked when none is given
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+
+// Number of pieces aimed for when picking a piece length
+const TARGET_PIECE_COUNT: usize = 1500;
+
+/// Makes a .torrent file for a file or a directory
+#[derive(Debug)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: Option<usize>,
+    // Trackers in tiers, the first of which is the main announce URL
+    trackers: Vec<Vec<String>>,
+    comment: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+    web_seeds: Vec<String>,
+}
+
+impl TorrentBuilder {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let creation_date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs() as i64)
+            .ok();
+
+        Self {
+            path: path.as_ref().to_path_buf(),
+            piece_length: None,
+            trackers: Vec::new(),
+            comment: None,
+            creation_date,
+            private: false,
+            web_seeds: Vec::new(),
+        }
+    }
+
+    /// Use pieces of `piece_length` bytes, which must be a power of two of at
+    /// least 16 KiB, instead of picking a length from the payload size
+    pub fn with_piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Add a tier of trackers that are tried in turn
+    pub fn with_tracker_tier(mut self, trackers: Vec<String>) -> Self {
+        if !trackers.is_empty() {
+            self.trackers.push(trackers);
+        }
+
+        self
+    }
+
+    pub fn with_comment(mut self, comment: &str) -> Self {
+        self.comment = Some(String::from(comment));
+        self
+    }
+
+    /// Set the creation date in seconds since the Unix epoch, or leave it out
+    /// so that the same payload always gives the same torrent
+    pub fn with_creation_date(mut self, creation_date: Option<i64>) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+
+    /// Ask clients to only find peers through our trackers
+    pub fn with_private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Add a web seed (BEP 19) the payload can be downloaded from
+    pub fn with_web_seed(mut self, url: &str) -> Self {
+        self.web_seeds.push(String::from(url));
+        self
+    }
+
+    /// Hash the payload and encode the torrent
+    pub fn build(&self) -> io::Result<Vec<u8>> {
+        let name = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| invalid("Payload has no valid UTF-8 name"))?;
+
+        let metadata = fs::metadata(&self.path)?;
+
+        let files = if metadata.is_dir() {
+            let mut files = Vec::new();
+            walk(&self.path, &mut Vec::new(), &mut files)?;
+            files
+        } else {
+            vec![(Vec::new(), metadata.len() as usize)]
+        };
+
+        let total_length: usize = files.iter().map(|(_, length)| length).sum();
+
+        let piece_length = match self.piece_length {
+            Some(piece_length)
+                if piece_length.is_power_of_two() && piece_length >= MIN_PIECE_LENGTH =>
+            {
+                piece_length
+            }
+
+            Some(_) => {
+                return Err(invalid(
+                    "Piece length is not a power of two of 16 KiB or more",
+                ))
+            }
+
+            None => (total_length / TARGET_PIECE_COUNT)
+                .next_power_of_two()
+                .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH),
+        };
+
+        let paths: Vec<PathBuf> = files
+            .iter()
+            .map(|(path, _)| {
+                path.iter()
+                    .fold(self.path.clone(), |full, part| full.join(part))
+            })
+            .collect();
+
+        let pieces = hash_pieces(&paths, piece_length)?;
+
+        let mut info = vec![
+            ("name", Bencoded::from(name)),
+            ("piece length", Bencoded::Int(piece_length as i64)),
+            ("pieces", Bencoded::Bstr(pieces)),
+        ];
+
+        if metadata.is_dir() {
+            let files = files
+                .iter()
+                .map(|(path, length)| {
+                    Bencoded::dict(vec![
+                        ("length", Bencoded::Int(*length as i64)),
+                        (
+                            "path",
+                            Bencoded::List(
+                                path.iter()
+                                    .map(|part| Bencoded::from(part.as_str()))
+                                    .collect(),
+                            ),
+                        ),
+                    ])
+                })
+                .collect();
+
+            info.push(("files", Bencoded::List(files)));
+        } else {
+            info.push(("length", Bencoded::Int(total_length as i64)));
+        }
+
+        if self.private {
+            info.push(("private", Bencoded::Int(1)));
+        }
+
+        let created_by = format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+
+        let mut torrent = vec![
+            ("info", Bencoded::dict(info)),
+            ("created by", Bencoded::from(created_by.as_str())),
+        ];
+
+        if let Some(announce) = self.trackers.first().and_then(|tier| tier.first()) {
+            torrent.push(("announce", Bencoded::from(announce.as_str())));
+        }
+
+        // A single tracker is fully described by `announce`
+        if self.trackers.iter().map(Vec::len).sum::<usize>() > 1 {
+            let tiers = self
+                .trackers
+                .iter()
+                .map(|tier| {
+                    Bencoded::List(
+                        tier.iter()
+                            .map(|url| Bencoded::from(url.as_str()))
+                            .collect(),
+                    )
+                })
+                .collect();
+
+            torrent.push(("announce-list", Bencoded::List(tiers)));
+        }
+
+        if let Some(comment) = &self.comment {
+            torrent.push(("comment", Bencoded::from(comment.as_str())));
+        }
+
+        if let Some(creation_date) = self.creation_date {
+            torrent.push(("creation date", Bencoded::Int(creation_date)));
+        }
+
+        if !self.web_seeds.is_empty() {
+            let web_seeds = self
+                .web_seeds
+                .iter()
+                .map(|url| Bencoded::from(url.as_str()))
+                .collect();
+
+            torrent.push(("url-list", Bencoded::List(web_seeds)));
+        }
+
+        Ok((&Bencoded::dict(torrent)).into())
+    }
+}
+
+/// Collect the files below `directory` in a fixed order, as path components
+/// relative to where the walk started
+fn walk(
+    directory: &Path,
+    prefix: &mut Vec<String>,
+    files: &mut Vec<(Vec<String>, usize)>,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(directory)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|_| invalid("File name is not valid UTF-8"))?;
+
+        let metadata = fs::metadata(entry.path())?;
+        prefix.push(name);
+
+        if metadata.is_dir() {
+            walk(&entry.path(), prefix, files)?;
+        } else {
+            files.push((prefix.clone(), metadata.len() as usize));
+        }
+
+        prefix.pop();
+    }
+
+    Ok(())
+}
+
+/// Read the files back to back in pieces of `piece_length` bytes, and hash
+/// the pieces on all cores
+fn hash_pieces(paths: &[PathBuf], piece_length: usize) -> io::Result<Vec<u8>> {
+    let workers = thread::available_parallelism().map_or(1, |cores| cores.get());
+
+    // Bounded, so that reading does not run too far ahead of hashing
+    let (sender, receiver) = mpsc::sync_channel::<(usize, Vec<u8>)>(workers * 2);
+    let receiver = Mutex::new(receiver);
+    let digests = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let next = receiver.lock().unwrap().recv();
+
+                match next {
+                    Ok((index, piece)) => {
+                        let digest = Sha1::digest(&piece);
+                        digests.lock().unwrap().push((index, digest));
+                    }
+
+                    Err(_) => break,
+                }
+            });
+        }
+
+        let mut piece = Vec::with_capacity(piece_length);
+        let mut index = 0;
+
+        for path in paths {
+            let mut file = fs::File::open(path)?;
+
+            loop {
+                let start = piece.len();
+                piece.resize(piece_length, 0);
+
+                let read = file.read(&mut piece[start..])?;
+                piece.truncate(start + read);
+
+                if read == 0 {
+                    break;
+                }
+
+                if piece.len() == piece_length {
+                    let full = std::mem::replace(&mut piece, Vec::with_capacity(piece_length));
+                    sender.send((index, full)).ok();
+                    index += 1;
+                }
+            }
+        }
+
+        if !piece.is_empty() {
+            sender.send((index, piece)).ok();
+        }
+
+        // Lets the workers run out of pieces
+        drop(sender);
+        Ok::<(), io::Error>(())
+    })?;
+
+    let mut digests = digests.into_inner().unwrap();
+    digests.sort_by_key(|(index, _)| *index);
+
+    Ok(digests
+        .iter()
+        .flat_map(|(_, digest)| digest.as_ref().to_vec())
+        .collect())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Torrent, Verification};
+    use super::*;
+
+    #[test]
+    fn created_torrents_describe_their_payload() {
+        let root = std::env::temp_dir().join(format!("builder-{}", std::process::id()));
+        let payload = root.join("payload");
+
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(payload.join("nested")).unwrap();
+
+        fs::write(payload.join("b"), vec![1; 20_000]).unwrap();
+        fs::write(payload.join("a"), vec![2; 30_000]).unwrap();
+        fs::write(payload.join("nested").join("c"), vec![3; 5]).unwrap();
+
+        let encoded = TorrentBuilder::new(&payload)
+            .with_tracker_tier(vec![String::from("http://tracker.example/announce")])
+            .with_creation_date(None)
+            .with_web_seed("http://seed.example/")
+            .build()
+            .unwrap();
+
+        // Nothing varies between builds of the same payload
+        let again = TorrentBuilder::new(&payload)
+            .with_tracker_tier(vec![String::from("http://tracker.example/announce")])
+            .with_creation_date(None)
+            .with_web_seed("http://seed.example/")
+            .build()
+            .unwrap();
+
+        assert_eq!(encoded, again);
+
+        let path = root.join("payload.torrent");
+        fs::write(&path, encoded).unwrap();
+
+        let torrent = Torrent::from(&path);
+
+        assert_eq!(torrent.name(), "payload");
+        assert_eq!(torrent.piece_length, MIN_PIECE_LENGTH);
+        assert_eq!(torrent.piece_count(), 4);
+        assert_eq!(torrent.web_seeds, vec!["http://seed.example/"]);
+
+        let verification = Verification::check(&torrent, &root);
+
+        assert_eq!(verification.valid_pieces(), 4);
+        assert!(verification.files.iter().all(|file| file.is_complete()));
+
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn piece_lengths_must_be_powers_of_two() {
+        let builder = TorrentBuilder::new(std::env::temp_dir()).with_piece_length(20_000);
+
+        assert!(builder.build().is_err());
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+// How often the regular unchoke slots are reassigned
+const ROUND_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often the optimistic unchoke moves on to another peer
+const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What the choker needs to know about a connected peer
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerStats {
+    // Whether the peer wants pieces we have
+    pub interested: bool,
+    // Whether the peer has stopped sending us blocks it is supposed to send
+    pub snubbed: bool,
+    // Total bytes received from the peer
+    pub downloaded: usize,
+    // Total bytes sent to the peer
+    pub uploaded: usize,
+}
+
+/// Decides which peers we upload to, following the reference algorithm of
+/// BEP 3: peers that give us the most get the regular slots, while a single
+/// optimistic slot gives every peer a chance to prove itself
+#[derive(Debug)]
+pub struct Choker {
+    regular_slots: usize,
+    unchoked: HashSet<usize>,
+    optimistic: Option<usize>,
+
+    last_round: Option<Instant>,
+    last_rotation: Option<Instant>,
+
+    // Totals at the previous round, to derive rates from
+    previous: HashMap<usize, PeerStats>,
+    // When each peer was last given the optimistic slot
+    optimistic_history: HashMap<usize, Instant>,
+}
+
+impl Choker {
+    pub fn new(regular_slots: usize) -> Self {
+        Self {
+            regular_slots,
+            unchoked: HashSet::new(),
+            optimistic: None,
+            last_round: None,
+            last_rotation: None,
+            previous: HashMap::new(),
+            optimistic_history: HashMap::new(),
+        }
+    }
+
+    /// Run a choking round if one is due, returning the peers to unchoke.
+    /// Peers are ranked by how fast they send to us, or how fast we send to
+    /// them when `seeding`.
+    pub fn tick(
+        &mut self,
+        now: Instant,
+        peers: &HashMap<usize, PeerStats>,
+        seeding: bool,
+    ) -> Option<&HashSet<usize>> {
+        let elapsed = match self.last_round {
+            Some(last_round) if now - last_round < ROUND_INTERVAL => return None,
+            Some(last_round) => now - last_round,
+            None => ROUND_INTERVAL,
+        };
+
+        self.last_round = Some(now);
+
+        let rates = self.rates(peers, elapsed, seeding);
+        self.previous = peers.clone();
+
+        let mut ranked: Vec<usize> = peers
+            .iter()
+            // Peers that snub us get no regular slot while we still need them
+            .filter(|(_, stats)| seeding || !stats.snubbed)
+            .map(|(&peer, _)| peer)
+            .collect();
+
+        // Ties are broken by key to keep rounds deterministic
+        ranked.sort_by(|a, b| rates[b].total_cmp(&rates[a]).then(a.cmp(b)));
+
+        let mut unchoked = HashSet::new();
+        let mut interested = 0;
+
+        // Peers that rank better than the slowest unchoked downloader are
+        // unchoked as well, so they can start right away once interested
+        for peer in ranked {
+            if interested == self.regular_slots {
+                break;
+            }
+
+            if peers[&peer].interested {
+                interested += 1;
+            }
+
+            unchoked.insert(peer);
+        }
+
+        self.rotate_optimistic(now, peers, &unchoked);
+
+        if let Some(optimistic) = self.optimistic {
+            unchoked.insert(optimistic);
+        }
+
+        self.optimistic_history
+            .retain(|peer, _| peers.contains_key(peer));
+        self.unchoked = unchoked;
+
+        Some(&self.unchoked)
+    }
+
+    fn rates(
+        &self,
+        peers: &HashMap<usize, PeerStats>,
+        elapsed: Duration,
+        seeding: bool,
+    ) -> HashMap<usize, f64> {
+        peers
+            .iter()
+            .map(|(&peer, stats)| {
+                let previous = self.previous.get(&peer).copied().unwrap_or_default();
+
+                let bytes = if seeding {
+                    stats.uploaded.saturating_sub(previous.uploaded)
+                } else {
+                    stats.downloaded.saturating_sub(previous.downloaded)
+                };
+
+                (peer, bytes as f64 / elapsed.as_secs_f64())
+            })
+            .collect()
+    }
+
+    fn rotate_optimistic(
+        &mut self,
+        now: Instant,
+        peers: &HashMap<usize, PeerStats>,
+        regular: &HashSet<usize>,
+    ) {
+        let current_is_valid = self.optimistic.is_some_and(|optimistic| {
+            peers.get(&optimistic).is_some_and(|stats| stats.interested)
+                && !regular.contains(&optimistic)
+        });
+
+        let rotation_due = self
+            .last_rotation
+            .is_none_or(|last_rotation| now - last_rotation >= OPTIMISTIC_INTERVAL);
+
+        if current_is_valid && !rotation_due {
+            return;
+        }
+
+        // The interested peer that has waited the longest for its turn
+        self.optimistic = peers
+            .iter()
+            .filter(|(peer, stats)| stats.interested && !regular.contains(peer))
+            .map(|(&peer, _)| peer)
+            .min_by_key(|peer| (self.optimistic_history.get(peer).copied(), *peer));
+
+        if let Some(optimistic) = self.optimistic {
+            self.optimistic_history.insert(optimistic, now);
+        }
+
+        self.last_rotation = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(interested: bool, downloaded: usize, uploaded: usize) -> PeerStats {
+        PeerStats {
+            interested,
+            snubbed: false,
+            downloaded,
+            uploaded,
+        }
+    }
+
+    fn unchoked(
+        choker: &mut Choker,
+        now: Instant,
+        peers: &HashMap<usize, PeerStats>,
+    ) -> Vec<usize> {
+        let mut unchoked: Vec<usize> = choker
+            .tick(now, peers, false)
+            .expect("Choking round was not due")
+            .iter()
+            .copied()
+            .collect();
+
+        unchoked.sort();
+        unchoked
+    }
+
+    #[test]
+    fn fastest_downloaders_get_regular_slots() {
+        let mut choker = Choker::new(2);
+
+        let peers = HashMap::from([
+            (0, peer(true, 100, 0)),
+            (1, peer(true, 500, 0)),
+            (2, peer(true, 300, 0)),
+            (3, peer(true, 10, 0)),
+        ]);
+
+        let unchoked = unchoked(&mut choker, Instant::now(), &peers);
+
+        // 1 and 2 are the fastest, and 0 is the first in line for the
+        // optimistic slot
+        assert_eq!(unchoked, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn uninterested_fast_peers_are_unchoked_without_taking_a_slot() {
+        let mut choker = Choker::new(1);
+
+        let peers = HashMap::from([
+            (0, peer(false, 900, 0)),
+            (1, peer(true, 500, 0)),
+            (2, peer(true, 100, 0)),
+        ]);
+
+        let unchoked = unchoked(&mut choker, Instant::now(), &peers);
+
+        // 0 ranks best but is not interested, 1 gets the only slot, and 2 is
+        // the optimistic unchoke
+        assert_eq!(unchoked, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn rounds_only_happen_every_ten_seconds() {
+        let mut choker = Choker::new(1);
+        let peers = HashMap::from([(0, peer(true, 0, 0))]);
+        let start = Instant::now();
+
+        assert!(choker.tick(start, &peers, false).is_some());
+        assert!(choker
+            .tick(start + Duration::from_secs(5), &peers, false)
+            .is_none());
+        assert!(choker
+            .tick(start + Duration::from_secs(10), &peers, false)
+            .is_some());
+    }
+
+    #[test]
+    fn rates_are_measured_per_round() {
+        let mut choker = Choker::new(1);
+        let start = Instant::now();
+
+        let mut peers = HashMap::from([(0, peer(true, 10_000, 0)), (1, peer(true, 0, 0))]);
+        assert!(choker.tick(start, &peers, false).unwrap().contains(&0));
+
+        // Peer 0 sent a lot early on, but peer 1 is the faster one now
+        peers.insert(0, peer(true, 10_100, 0));
+        peers.insert(1, peer(true, 5_000, 0));
+
+        let round = start + ROUND_INTERVAL;
+        let unchoked = choker.tick(round, &peers, false).unwrap();
+
+        assert!(unchoked.contains(&1));
+        assert_eq!(choker.optimistic, Some(0));
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates_every_thirty_seconds() {
+        let mut choker = Choker::new(0);
+        let start = Instant::now();
+
+        let peers = HashMap::from([
+            (0, peer(true, 0, 0)),
+            (1, peer(true, 0, 0)),
+            (2, peer(true, 0, 0)),
+        ]);
+
+        let mut optimistic = Vec::new();
+
+        for round in 0..9 {
+            let now = start + ROUND_INTERVAL * round;
+            let unchoked = choker.tick(now, &peers, false).unwrap();
+
+            assert_eq!(unchoked.len(), 1);
+            optimistic.push(choker.optimistic.unwrap());
+        }
+
+        // Each peer keeps the slot for three rounds, and every peer gets a turn
+        assert_eq!(optimistic, vec![0, 0, 0, 1, 1, 1, 2, 2, 2]);
+    }
+
+    #[test]
+    fn seeding_ranks_by_upload_rate() {
+        let mut choker = Choker::new(1);
+
+        let peers = HashMap::from([
+            (0, peer(true, 900, 100)),
+            (1, peer(true, 0, 800)),
+            (2, peer(true, 0, 0)),
+        ]);
+
+        let unchoked = choker.tick(Instant::now(), &peers, true).unwrap();
+
+        assert!(unchoked.contains(&1));
+        assert_eq!(choker.optimistic, Some(0));
+    }
+
+    #[test]
+    fn snubbing_peers_lose_their_regular_slot() {
+        let mut choker = Choker::new(1);
+
+        let mut snubbing = peer(true, 900, 0);
+        snubbing.snubbed = true;
+
+        let peers = HashMap::from([
+            (0, snubbing),
+            (1, peer(true, 100, 0)),
+            (2, peer(true, 0, 0)),
+        ]);
+
+        let unchoked = unchoked(&mut choker, Instant::now(), &peers);
+
+        // Peer 0 may still be unchoked optimistically
+        assert!(unchoked.contains(&1));
+        assert_ne!(choker.optimistic, Some(1));
+    }
+}
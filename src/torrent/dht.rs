@@ -0,0 +1,539 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use super::utp::Datagrams;
+use super::{get_bstr, Bencoded, PeerAddress, Sha1};
+
+mod krpc;
+use krpc::{Body, Message, Query, Response, PROTOCOL_ERROR};
+
+mod routing;
+use routing::{Contact, NodeId, RoutingTable, K};
+
+mod store;
+use store::{PeerStore, Tokens};
+
+/// Well known nodes to join the DHT through
+pub const BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+// How long a node has to answer a query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Number of queries a lookup has in flight at once
+const ALPHA: usize = 3;
+
+/// A node of the mainline DHT (BEP 5), which finds peers for torrents
+/// without the help of a tracker
+#[derive(Clone)]
+pub struct Dht {
+    node: Arc<Node>,
+}
+
+struct Node {
+    socket: Arc<UdpSocket>,
+    table: Mutex<RoutingTable>,
+    tokens: Mutex<Tokens>,
+    peers: Mutex<PeerStore>,
+    pending: Mutex<Pending>,
+    next_transaction: AtomicU16,
+}
+
+// Queries awaiting an answer, by transaction id, along with the address the
+// answer must come from
+type Pending = HashMap<Vec<u8>, (SocketAddr, oneshot::Sender<Body>)>;
+
+/// What a lookup found on its way to the target
+struct Lookup {
+    peers: Vec<PeerAddress>,
+    // The closest nodes that answered, with the tokens they handed us
+    closest: Vec<(Contact, Option<Vec<u8>>)>,
+}
+
+impl Dht {
+    /// Take part in the DHT on UDP `port`, or any port if that is taken
+    pub async fn bind(port: u16) -> io::Result<Self> {
+        let socket = match UdpSocket::bind(("0.0.0.0", port)).await {
+            Ok(socket) => socket,
+            Err(_) => UdpSocket::bind(("0.0.0.0", 0)).await?,
+        };
+
+        let socket = Arc::new(socket);
+        let (sender, datagrams) = mpsc::unbounded_channel();
+
+        tokio::spawn(read(Arc::clone(&socket), sender));
+
+        Ok(Self::over(socket, datagrams))
+    }
+
+    /// Take part in the DHT through a socket shared with another protocol,
+    /// which hands over the `datagrams` meant for us
+    pub fn over(socket: Arc<UdpSocket>, datagrams: Datagrams) -> Self {
+        let node = Arc::new(Node {
+            socket,
+            table: Mutex::new(RoutingTable::new(NodeId::random())),
+            tokens: Mutex::new(Tokens::new()),
+            peers: Mutex::new(PeerStore::new()),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(0),
+        });
+
+        tokio::spawn(receive(Arc::clone(&node), datagrams));
+
+        Self { node }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.node
+            .socket
+            .local_addr()
+            .map(|address| address.port())
+            .unwrap_or_default()
+    }
+
+    /// Number of nodes in the routing table
+    pub fn node_count(&self) -> usize {
+        self.node.table.lock().unwrap().len()
+    }
+
+    /// Take up the node id and routing table saved by an earlier run
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Malformed DHT state");
+
+        let state = Bencoded::parse(&fs::read(path)?)
+            .filter(|state| matches!(state, Bencoded::Dict(_)))
+            .ok_or_else(invalid)?;
+
+        let id = get_bstr(&state, "id")
+            .and_then(|id| NodeId::from_bytes(&id))
+            .ok_or_else(invalid)?;
+
+        let mut table = RoutingTable::new(id);
+
+        for node in get_bstr(&state, "nodes")
+            .unwrap_or_default()
+            .chunks_exact(26)
+        {
+            let id = NodeId::from_bytes(&node[..20]).unwrap();
+            table.insert(id, PeerAddress::new(&node[20..]).to_socket_addr());
+        }
+
+        *self.node.table.lock().unwrap() = table;
+        Ok(())
+    }
+
+    /// Save the node id and routing table, so that the next run need not
+    /// start from the bootstrap nodes
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let table = self.node.table.lock().unwrap();
+
+        let nodes = table
+            .contacts()
+            .filter(|contact| contact.address.is_ipv4())
+            .flat_map(|contact| {
+                let address = PeerAddress::from_socket_addr(&contact.address).to_compact();
+                contact.id.0.iter().copied().chain(address)
+            })
+            .collect();
+
+        let state = Bencoded::dict(vec![
+            ("id", Bencoded::Bstr(table.own_id().0.to_vec())),
+            ("nodes", Bencoded::Bstr(nodes)),
+        ]);
+
+        fs::write(path, Vec::from(&state))
+    }
+
+    /// Get to know the nodes near us, starting from `hosts` and whatever is
+    /// in the routing table already
+    pub async fn bootstrap<S: AsRef<str>>(&self, hosts: &[S]) {
+        let own_id = self.node.table.lock().unwrap().own_id();
+        let mut queries = JoinSet::new();
+
+        for host in hosts {
+            let addresses = match lookup_host(host.as_ref()).await {
+                Ok(addresses) => addresses,
+                Err(_) => continue,
+            };
+
+            for address in addresses.filter(SocketAddr::is_ipv4) {
+                let dht = self.clone();
+
+                queries.spawn(async move {
+                    dht.query(address, None, Query::FindNode { target: own_id })
+                        .await
+                });
+            }
+        }
+
+        while let Some(response) = queries.join_next().await {
+            if let Ok(Some((_, response))) = response {
+                self.learn(&response);
+            }
+        }
+
+        self.lookup(own_id, Query::FindNode { target: own_id })
+            .await;
+    }
+
+    /// Look up ids in the buckets we have not heard from lately, so that
+    /// they fill up with live nodes again
+    pub async fn refresh(&self) {
+        let stale = self.node.table.lock().unwrap().stale_buckets();
+
+        for target in stale {
+            self.lookup(target, Query::FindNode { target }).await;
+        }
+    }
+
+    /// Find peers for a torrent
+    pub async fn get_peers(&self, info_hash: Sha1) -> Vec<PeerAddress> {
+        self.lookup(info_hash.into(), Query::GetPeers { info_hash })
+            .await
+            .peers
+    }
+
+    /// Find peers for a torrent, and tell the nodes closest to it that we
+    /// accept connections on `port`
+    pub async fn announce(&self, info_hash: Sha1, port: u16) -> Vec<PeerAddress> {
+        let lookup = self
+            .lookup(info_hash.into(), Query::GetPeers { info_hash })
+            .await;
+
+        let mut announces = JoinSet::new();
+
+        for (contact, token) in lookup.closest {
+            if let Some(token) = token {
+                let dht = self.clone();
+
+                let query = Query::AnnouncePeer {
+                    info_hash,
+                    port,
+                    implied_port: false,
+                    token,
+                };
+
+                announces.spawn(async move {
+                    dht.query(contact.address, Some(contact.id), query).await
+                });
+            }
+        }
+
+        while announces.join_next().await.is_some() {}
+
+        lookup.peers
+    }
+
+    /// Walk towards `target`, asking the closest nodes we know of for nodes
+    /// closer still, until the closest ones have all been asked
+    async fn lookup(&self, target: NodeId, query: Query) -> Lookup {
+        let mut candidates = self.node.table.lock().unwrap().closest(&target, K);
+        let mut queried = HashSet::new();
+        let mut closest = Vec::new();
+        let mut peers = Vec::new();
+
+        loop {
+            candidates.sort_by_key(|contact| contact.id.distance(&target));
+            candidates.truncate(K * 2);
+
+            let batch: Vec<Contact> = candidates
+                .iter()
+                .take(K)
+                .filter(|contact| !queried.contains(&contact.id))
+                .take(ALPHA)
+                .cloned()
+                .collect();
+
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut queries = JoinSet::new();
+
+            for contact in batch {
+                queried.insert(contact.id);
+
+                let dht = self.clone();
+                let query = query.clone();
+
+                queries.spawn(async move {
+                    let response = dht.query(contact.address, Some(contact.id), query).await;
+                    (contact, response)
+                });
+            }
+
+            while let Some(answer) = queries.join_next().await {
+                let (contact, response) = match answer {
+                    Ok((contact, Some((_, response)))) => (contact, response),
+                    Ok((contact, None)) => {
+                        candidates.retain(|candidate| candidate.id != contact.id);
+                        continue;
+                    }
+                    Err(_) => continue,
+                };
+
+                for (id, address) in &response.nodes {
+                    if !candidates.iter().any(|candidate| candidate.id == *id) {
+                        candidates.push(Contact::new(*id, *address));
+                    }
+                }
+
+                for peer in &response.values {
+                    if !peers.contains(peer) {
+                        peers.push(peer.clone());
+                    }
+                }
+
+                closest.push((contact, response.token));
+            }
+        }
+
+        closest.sort_by_key(|(contact, _)| contact.id.distance(&target));
+        closest.truncate(K);
+
+        Lookup { peers, closest }
+    }
+
+    /// Send a query and wait for the answer. Nodes that answer go into the
+    /// routing table; known nodes that do not are marked as failing.
+    async fn query(
+        &self,
+        address: SocketAddr,
+        id: Option<NodeId>,
+        query: Query,
+    ) -> Option<(SocketAddr, Response)> {
+        let transaction = self
+            .node
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+
+        let (sender, receiver) = oneshot::channel();
+
+        self.node
+            .pending
+            .lock()
+            .unwrap()
+            .insert(transaction.clone(), (address, sender));
+
+        let message = Message {
+            transaction: transaction.clone(),
+            body: Body::Query {
+                id: self.node.table.lock().unwrap().own_id(),
+                query,
+            },
+        };
+
+        self.node
+            .socket
+            .send_to(&message.encode(), address)
+            .await
+            .ok();
+
+        let answer = timeout(QUERY_TIMEOUT, receiver).await;
+        self.node.pending.lock().unwrap().remove(&transaction);
+
+        match answer {
+            Ok(Ok(Body::Response(response))) => {
+                self.node.table.lock().unwrap().insert(response.id, address);
+
+                Some((address, response))
+            }
+
+            _ => {
+                if let Some(id) = id {
+                    self.node.table.lock().unwrap().failed(&id);
+                }
+
+                None
+            }
+        }
+    }
+
+    /// Take note of the nodes a response tells us about
+    fn learn(&self, response: &Response) {
+        let mut table = self.node.table.lock().unwrap();
+
+        for (id, address) in &response.nodes {
+            table.insert(*id, *address);
+        }
+    }
+}
+
+/// Hand everything that arrives on a socket of our own to `receive`
+async fn read(socket: Arc<UdpSocket>, datagrams: mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>) {
+    let mut buffer = [0u8; 2048];
+
+    loop {
+        if let Ok((size, from)) = socket.recv_from(&mut buffer).await {
+            if datagrams.send((buffer[..size].to_vec(), from)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Answer queries, and hand answers to the queries waiting for them
+async fn receive(node: Arc<Node>, mut datagrams: Datagrams) {
+    while let Some((datagram, from)) = datagrams.recv().await {
+        let message = match Message::parse(&datagram) {
+            Some(message) => message,
+            None => continue,
+        };
+
+        match message.body {
+            Body::Query { id, query } => {
+                let reply = Message {
+                    transaction: message.transaction,
+                    body: node.answer(from, query),
+                };
+
+                node.table.lock().unwrap().insert(id, from);
+                node.socket.send_to(&reply.encode(), from).await.ok();
+            }
+
+            body => {
+                let waiting = node.pending.lock().unwrap().remove(&message.transaction);
+
+                match waiting {
+                    Some((address, sender)) if address == from => {
+                        sender.send(body).ok();
+                    }
+
+                    // Answers from elsewhere are not to be trusted
+                    Some(waiting) => {
+                        node.pending
+                            .lock()
+                            .unwrap()
+                            .insert(message.transaction, waiting);
+                    }
+
+                    None => (),
+                }
+            }
+        }
+    }
+}
+
+impl Node {
+    fn answer(&self, from: SocketAddr, query: Query) -> Body {
+        let table = self.table.lock().unwrap();
+        let mut response = Response::new(table.own_id());
+
+        match query {
+            Query::Ping => (),
+
+            Query::FindNode { target } => {
+                response.nodes = nodes(table.closest(&target, K));
+            }
+
+            Query::GetPeers { info_hash } => {
+                response.token = Some(self.tokens.lock().unwrap().issue(&from));
+                response.values = self.peers.lock().unwrap().get(&info_hash);
+
+                if response.values.is_empty() {
+                    response.nodes = nodes(table.closest(&info_hash.into(), K));
+                }
+            }
+
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if !self.tokens.lock().unwrap().is_valid(&token, &from) {
+                    return Body::Error(PROTOCOL_ERROR, String::from("Bad token"));
+                }
+
+                let port = if implied_port { from.port() } else { port };
+                let peer = PeerAddress::from_socket_addr(&SocketAddr::new(from.ip(), port));
+
+                self.peers.lock().unwrap().announce(info_hash, peer);
+            }
+        }
+
+        Body::Response(response)
+    }
+}
+
+fn nodes(contacts: Vec<Contact>) -> Vec<(NodeId, SocketAddr)> {
+    contacts
+        .into_iter()
+        .map(|contact| (contact.id, contact.address))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn swarm(size: usize) -> Vec<Dht> {
+        let mut nodes: Vec<Dht> = Vec::new();
+
+        for _ in 0..size {
+            let node = Dht::bind(0).await.unwrap();
+
+            if let Some(first) = nodes.first() {
+                node.bootstrap(&[format!("127.0.0.1:{}", first.port())])
+                    .await;
+            }
+
+            nodes.push(node);
+        }
+
+        nodes
+    }
+
+    #[tokio::test]
+    async fn announced_peers_are_found_by_other_nodes() {
+        let nodes = swarm(12).await;
+        let info_hash = Sha1::digest(b"torrent");
+
+        assert!(nodes.iter().all(|node| node.node_count() > 0));
+
+        nodes[4].announce(info_hash, 7000).await;
+
+        let found = nodes[11].get_peers(info_hash).await;
+
+        assert!(found.contains(&PeerAddress::from_socket_addr(
+            &"127.0.0.1:7000".parse().unwrap()
+        )));
+
+        assert!(nodes[7].get_peers(Sha1::digest(b"other")).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn routing_tables_survive_a_restart() {
+        let nodes = swarm(3).await;
+        let path = std::env::temp_dir().join(format!("dht-{}", std::process::id()));
+
+        nodes[2].save(&path).unwrap();
+
+        let restarted = Dht::bind(0).await.unwrap();
+        restarted.load(&path).unwrap();
+
+        assert_eq!(restarted.node_count(), nodes[2].node_count());
+        assert_eq!(
+            restarted.node.table.lock().unwrap().own_id(),
+            nodes[2].node.table.lock().unwrap().own_id()
+        );
+
+        fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,333 @@
+use std::net::{IpAddr, SocketAddr};
+
+use super::super::{get_bstr, get_dict, get_int, get_list, Bencoded, PeerAddress, Sha1};
+use super::routing::NodeId;
+
+// Error code of BEP 5 for malformed queries, and bad tokens
+pub const PROTOCOL_ERROR: i64 = 203;
+
+/// A KRPC message, which is what DHT nodes exchange over UDP
+#[derive(Debug, Clone)]
+pub struct Message {
+    // Chosen by the querying node, and echoed back in the reply
+    pub transaction: Vec<u8>,
+    pub body: Body,
+}
+
+#[derive(Debug, Clone)]
+pub enum Body {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error(i64, String),
+}
+
+#[derive(Debug, Clone)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: Sha1,
+    },
+    AnnouncePeer {
+        info_hash: Sha1,
+        port: u16,
+        // Use the port the query came from instead of `port`
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+/// The fields of a response; which are set depends on the query
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<(NodeId, SocketAddr)>,
+    pub values: Vec<PeerAddress>,
+    pub token: Option<Vec<u8>>,
+}
+
+impl Response {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            nodes: Vec::new(),
+            values: Vec::new(),
+            token: None,
+        }
+    }
+}
+
+impl Message {
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        let message = Bencoded::parse(packet)?;
+
+        if !matches!(message, Bencoded::Dict(_)) {
+            return None;
+        }
+
+        let transaction = get_bstr(&message, "t")?;
+
+        let body = match get_bstr(&message, "y")?.as_slice() {
+            b"q" => {
+                let arguments = get_dict(&message, "a")?;
+                let id = NodeId::from_bytes(&get_bstr(&arguments, "id")?)?;
+
+                let query = match get_bstr(&message, "q")?.as_slice() {
+                    b"ping" => Query::Ping,
+
+                    b"find_node" => Query::FindNode {
+                        target: NodeId::from_bytes(&get_bstr(&arguments, "target")?)?,
+                    },
+
+                    b"get_peers" => Query::GetPeers {
+                        info_hash: info_hash(&arguments)?,
+                    },
+
+                    b"announce_peer" => Query::AnnouncePeer {
+                        info_hash: info_hash(&arguments)?,
+                        port: u16::try_from(get_int(&arguments, "port")?).ok()?,
+                        implied_port: get_int(&arguments, "implied_port") == Some(1),
+                        token: get_bstr(&arguments, "token")?,
+                    },
+
+                    _ => return None,
+                };
+
+                Body::Query { id, query }
+            }
+
+            b"r" => {
+                let fields = get_dict(&message, "r")?;
+
+                let nodes = get_bstr(&fields, "nodes")
+                    .unwrap_or_default()
+                    .chunks_exact(26)
+                    .map(|node| {
+                        let id = NodeId::from_bytes(&node[..20]).unwrap();
+                        (id, PeerAddress::new(&node[20..]).to_socket_addr())
+                    })
+                    .collect();
+
+                let values = get_list(&fields, "values")
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|value| match value {
+                        Bencoded::Bstr(peer) if peer.len() == 6 || peer.len() == 18 => {
+                            Some(PeerAddress::new(&peer))
+                        }
+                        _ => None,
+                    })
+                    .collect();
+
+                Body::Response(Response {
+                    id: NodeId::from_bytes(&get_bstr(&fields, "id")?)?,
+                    nodes,
+                    values,
+                    token: get_bstr(&fields, "token"),
+                })
+            }
+
+            b"e" => match get_list(&message, "e")?.as_slice() {
+                [Bencoded::Int(code), Bencoded::Bstr(text)] => {
+                    Body::Error(*code, String::from_utf8_lossy(text).into_owned())
+                }
+                _ => return None,
+            },
+
+            _ => return None,
+        };
+
+        Some(Self { transaction, body })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let transaction = ("t", Bencoded::Bstr(self.transaction.clone()));
+
+        let message = match &self.body {
+            Body::Query { id, query } => {
+                let mut arguments = vec![("id", Bencoded::Bstr(id.0.to_vec()))];
+
+                let name = match query {
+                    Query::Ping => "ping",
+
+                    Query::FindNode { target } => {
+                        arguments.push(("target", Bencoded::Bstr(target.0.to_vec())));
+                        "find_node"
+                    }
+
+                    Query::GetPeers { info_hash } => {
+                        arguments.push(("info_hash", bytes(info_hash.as_ref())));
+                        "get_peers"
+                    }
+
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        arguments.push(("info_hash", bytes(info_hash.as_ref())));
+                        arguments.push(("port", Bencoded::Int(*port as i64)));
+                        arguments.push(("token", bytes(token)));
+
+                        if *implied_port {
+                            arguments.push(("implied_port", Bencoded::Int(1)));
+                        }
+
+                        "announce_peer"
+                    }
+                };
+
+                vec![
+                    transaction,
+                    ("y", Bencoded::from("q")),
+                    ("q", Bencoded::from(name)),
+                    ("a", Bencoded::dict(arguments)),
+                ]
+            }
+
+            Body::Response(response) => {
+                let mut fields = vec![("id", Bencoded::Bstr(response.id.0.to_vec()))];
+
+                if !response.nodes.is_empty() {
+                    let nodes = response
+                        .nodes
+                        .iter()
+                        .filter(|(_, address)| address.is_ipv4())
+                        .flat_map(|(id, address)| {
+                            let address = PeerAddress::from_socket_addr(address).to_compact();
+                            id.0.iter().copied().chain(address)
+                        })
+                        .collect();
+
+                    fields.push(("nodes", Bencoded::Bstr(nodes)));
+                }
+
+                if !response.values.is_empty() {
+                    let values = response
+                        .values
+                        .iter()
+                        .map(|peer| Bencoded::Bstr(peer.to_compact()))
+                        .collect();
+
+                    fields.push(("values", Bencoded::List(values)));
+                }
+
+                if let Some(token) = &response.token {
+                    fields.push(("token", bytes(token)));
+                }
+
+                vec![
+                    transaction,
+                    ("y", Bencoded::from("r")),
+                    ("r", Bencoded::dict(fields)),
+                ]
+            }
+
+            Body::Error(code, text) => vec![
+                transaction,
+                ("y", Bencoded::from("e")),
+                (
+                    "e",
+                    Bencoded::List(vec![Bencoded::Int(*code), Bencoded::from(text.as_str())]),
+                ),
+            ],
+        };
+
+        (&Bencoded::dict(message)).into()
+    }
+}
+
+fn info_hash(arguments: &Bencoded) -> Option<Sha1> {
+    let info_hash = get_bstr(arguments, "info_hash")?;
+    (info_hash.len() == 20).then(|| Sha1::new_raw(&info_hash))
+}
+
+fn bytes(bytes: &[u8]) -> Bencoded {
+    Bencoded::Bstr(bytes.to_vec())
+}
+
+/// The compact form of the address of whoever sent a query, for tokens
+pub fn compact_ip(address: &SocketAddr) -> Vec<u8> {
+    match address.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: &Message) -> Message {
+        Message::parse(&message.encode()).unwrap()
+    }
+
+    #[test]
+    fn queries_survive_encoding() {
+        let id = NodeId::random();
+        let info_hash = Sha1::digest(b"info");
+
+        let message = round_trip(&Message {
+            transaction: b"aa".to_vec(),
+            body: Body::Query {
+                id,
+                query: Query::AnnouncePeer {
+                    info_hash,
+                    port: 6881,
+                    implied_port: true,
+                    token: b"token".to_vec(),
+                },
+            },
+        });
+
+        assert_eq!(message.transaction, b"aa");
+
+        match message.body {
+            Body::Query {
+                id: parsed,
+                query:
+                    Query::AnnouncePeer {
+                        info_hash: parsed_hash,
+                        port: 6881,
+                        implied_port: true,
+                        token,
+                    },
+            } => {
+                assert_eq!(parsed, id);
+                assert_eq!(parsed_hash, info_hash);
+                assert_eq!(token, b"token");
+            }
+
+            body => panic!("Unexpected body {:?}", body),
+        }
+    }
+
+    #[test]
+    fn responses_survive_encoding() {
+        let node = (NodeId::random(), "10.0.0.1:6881".parse().unwrap());
+        let peer = PeerAddress::from_socket_addr(&"[::1]:51413".parse().unwrap());
+
+        let mut response = Response::new(NodeId::random());
+        response.nodes.push(node);
+        response.values.push(peer.clone());
+        response.token = Some(b"token".to_vec());
+
+        let message = round_trip(&Message {
+            transaction: b"bb".to_vec(),
+            body: Body::Response(response),
+        });
+
+        match message.body {
+            Body::Response(parsed) => {
+                assert_eq!(parsed.nodes, vec![node]);
+                assert!(parsed.values == vec![peer]);
+                assert_eq!(parsed.token.as_deref(), Some(&b"token"[..]));
+            }
+
+            body => panic!("Unexpected body {:?}", body),
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use super::super::Sha1;
+
+// Nodes kept in each bucket
+pub const K: usize = 8;
+
+// Number of unanswered queries after which a node is replaced by newcomers
+const MAX_FAILURES: usize = 2;
+
+// Nodes heard from within this time are known to be good (BEP 5)
+const GOOD_FOR: Duration = Duration::from_secs(15 * 60);
+
+/// Identifies a node, and places it in the same 160-bit space as info hashes
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        Self(rand::thread_rng().gen())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+
+    pub fn distance(&self, other: &NodeId) -> NodeId {
+        let mut distance = [0; 20];
+
+        for (byte, (a, b)) in distance.iter_mut().zip(self.0.iter().zip(&other.0)) {
+            *byte = a ^ b;
+        }
+
+        NodeId(distance)
+    }
+
+    /// Number of leading bits `other` shares with us
+    fn common_prefix(&self, other: &NodeId) -> usize {
+        let distance = self.distance(other);
+
+        distance
+            .0
+            .iter()
+            .position(|&byte| byte != 0)
+            .map_or(160, |byte| {
+                byte * 8 + distance.0[byte].leading_zeros() as usize
+            })
+    }
+}
+
+impl From<Sha1> for NodeId {
+    fn from(sha: Sha1) -> Self {
+        Self::from_bytes(sha.as_ref()).unwrap()
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeId({})", hex::encode(self.0))
+    }
+}
+
+/// A node we know of
+#[derive(Debug, Clone)]
+pub struct Contact {
+    pub id: NodeId,
+    pub address: SocketAddr,
+    last_seen: Instant,
+    // Queries in a row the node has not answered
+    failures: usize,
+}
+
+impl Contact {
+    pub fn new(id: NodeId, address: SocketAddr) -> Self {
+        Self {
+            id,
+            address,
+            last_seen: Instant::now(),
+            failures: 0,
+        }
+    }
+
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+
+    fn is_good(&self) -> bool {
+        self.failures == 0 && self.last_seen.elapsed() < GOOD_FOR
+    }
+}
+
+/// The nodes we know of, kept in buckets by how many leading bits their id
+/// shares with ours. Buckets close to us hold a larger share of the nodes
+/// near us than far ones do, which is what lookups need.
+#[derive(Debug)]
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Contact>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn own_id(&self) -> NodeId {
+        self.own_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn contacts(&self) -> impl Iterator<Item = &Contact> {
+        self.buckets.iter().flatten()
+    }
+
+    /// Note that a node has been heard from. Newcomers only get a place in a
+    /// full bucket by taking over from a node that stopped answering.
+    pub fn insert(&mut self, id: NodeId, address: SocketAddr) {
+        if id == self.own_id {
+            return;
+        }
+
+        let bucket = &mut self.buckets[self.own_id.common_prefix(&id).min(159)];
+
+        if let Some(position) = bucket.iter().position(|contact| contact.id == id) {
+            let mut contact = bucket.remove(position);
+
+            contact.address = address;
+            contact.last_seen = Instant::now();
+            contact.failures = 0;
+
+            // The most recently seen node goes last
+            bucket.push(contact);
+            return;
+        }
+
+        if bucket.len() >= K {
+            match bucket.iter().position(Contact::is_bad) {
+                Some(position) => {
+                    bucket.remove(position);
+                }
+                None => return,
+            }
+        }
+
+        bucket.push(Contact::new(id, address));
+    }
+
+    /// Note that a node did not answer a query
+    pub fn failed(&mut self, id: &NodeId) {
+        let bucket = &mut self.buckets[self.own_id.common_prefix(id).min(159)];
+
+        if let Some(contact) = bucket.iter_mut().find(|contact| contact.id == *id) {
+            contact.failures += 1;
+        }
+    }
+
+    /// The `count` nodes closest to `target` that are not known to be bad
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Contact> {
+        let mut contacts: Vec<&Contact> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|contact| !contact.is_bad())
+            .collect();
+
+        contacts.sort_by_key(|contact| contact.id.distance(target));
+
+        contacts.into_iter().take(count).cloned().collect()
+    }
+
+    /// Nodes in buckets that have not been refreshed lately, for a lookup
+    /// near them to liven up
+    pub fn stale_buckets(&self) -> Vec<NodeId> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| !bucket.is_empty() && !bucket.iter().any(Contact::is_good))
+            .map(|(prefix, _)| self.id_in_bucket(prefix))
+            .collect()
+    }
+
+    /// A random id sharing exactly `prefix` leading bits with ours
+    fn id_in_bucket(&self, prefix: usize) -> NodeId {
+        let mut id = NodeId::random();
+
+        for bit in 0..=prefix.min(159) {
+            let (byte, mask) = (bit / 8, 0x80 >> (bit % 8));
+            let own = self.own_id.0[byte] & mask;
+
+            // The bit after the prefix is the first to differ
+            let wanted = if bit == prefix { own ^ mask } else { own };
+            id.0[byte] = (id.0[byte] & !mask) | wanted;
+        }
+
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(first: u8) -> NodeId {
+        let mut id = [0; 20];
+        id[0] = first;
+        NodeId(id)
+    }
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn nodes_are_bucketed_by_shared_prefix() {
+        assert_eq!(id(0).common_prefix(&id(0x80)), 0);
+        assert_eq!(id(0).common_prefix(&id(0x01)), 7);
+        assert_eq!(id(0).common_prefix(&id(0)), 160);
+
+        let table = RoutingTable::new(NodeId::random());
+
+        for prefix in [0, 9, 159] {
+            let bucket_id = table.id_in_bucket(prefix);
+            assert_eq!(table.own_id.common_prefix(&bucket_id), prefix);
+        }
+    }
+
+    #[test]
+    fn full_buckets_only_take_over_from_bad_nodes() {
+        let mut table = RoutingTable::new(id(0));
+
+        // All of these share no leading bit with us
+        for node in 0..K as u8 {
+            table.insert(id(0x80 | node), address(node as u16));
+        }
+
+        table.insert(id(0xff), address(100));
+        assert_eq!(table.len(), K);
+
+        table.failed(&id(0x80));
+        table.failed(&id(0x80));
+        table.insert(id(0xff), address(100));
+
+        assert_eq!(table.len(), K);
+        assert!(table.contacts().any(|contact| contact.id == id(0xff)));
+        assert!(!table.contacts().any(|contact| contact.id == id(0x80)));
+    }
+
+    #[test]
+    fn closest_nodes_come_first() {
+        let mut table = RoutingTable::new(id(0));
+
+        for first in [0x80, 0x40, 0x41, 0x20, 0x01] {
+            table.insert(id(first), address(first as u16));
+        }
+
+        let closest: Vec<NodeId> = table
+            .closest(&id(0x43), 3)
+            .into_iter()
+            .map(|contact| contact.id)
+            .collect();
+
+        assert_eq!(closest, vec![id(0x41), id(0x40), id(0x01)]);
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use super::super::{PeerAddress, Sha1};
+use super::krpc::compact_ip;
+
+// How often the token secret changes; tokens stay valid for one more period
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+// How long an announced peer is handed out for
+const PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+// Most peers kept for, and handed out for, a single info hash
+const MAX_STORED_PEERS: usize = 100;
+const MAX_VALUES: usize = 50;
+
+/// Hands out the tokens a node has to present when it announces itself,
+/// which show that it can receive at the address it claims
+#[derive(Debug)]
+pub struct Tokens {
+    secret: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+impl Tokens {
+    pub fn new() -> Self {
+        Self {
+            secret: rand::thread_rng().gen(),
+            previous: rand::thread_rng().gen(),
+            rotated: Instant::now(),
+        }
+    }
+
+    pub fn issue(&mut self, address: &SocketAddr) -> Vec<u8> {
+        self.rotate();
+        make_token(&self.secret, address)
+    }
+
+    pub fn is_valid(&mut self, token: &[u8], address: &SocketAddr) -> bool {
+        self.rotate();
+
+        [&self.secret, &self.previous]
+            .iter()
+            .any(|secret| make_token(secret, address) == token)
+    }
+
+    fn rotate(&mut self) {
+        if self.rotated.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.secret;
+            self.secret = rand::thread_rng().gen();
+            self.rotated = Instant::now();
+        }
+    }
+}
+
+fn make_token(secret: &[u8; 16], address: &SocketAddr) -> Vec<u8> {
+    let mut message = compact_ip(address);
+    message.extend_from_slice(secret);
+
+    Sha1::digest(&message).as_ref()[..8].to_vec()
+}
+
+/// Peers that have announced themselves to us, by info hash
+#[derive(Debug, Default)]
+pub struct PeerStore {
+    peers: HashMap<Sha1, Vec<(PeerAddress, Instant)>>,
+}
+
+impl PeerStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn announce(&mut self, info_hash: Sha1, peer: PeerAddress) {
+        let peers = self.peers.entry(info_hash).or_default();
+
+        peers.retain(|(known, announced)| *known != peer && announced.elapsed() < PEER_LIFETIME);
+
+        if peers.len() >= MAX_STORED_PEERS {
+            peers.remove(0);
+        }
+
+        peers.push((peer, Instant::now()));
+    }
+
+    pub fn get(&self, info_hash: &Sha1) -> Vec<PeerAddress> {
+        self.peers
+            .get(info_hash)
+            .map(|peers| {
+                peers
+                    .iter()
+                    .rev()
+                    .filter(|(_, announced)| announced.elapsed() < PEER_LIFETIME)
+                    .take(MAX_VALUES)
+                    .map(|(peer, _)| peer.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
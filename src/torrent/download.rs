@@ -0,0 +1,1110 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::sleep;
+
+use super::lsd::ANNOUNCE_INTERVAL;
+use super::pex::{ENCRYPTION, REACHABLE, SEED, UTP};
+use super::pipeline::BLOCK_SIZE;
+use super::storage::check_pieces;
+use super::{
+    Availability, Bitfield, BlockSource, Choker, Dht, DownloadError, Encryption, Endgame,
+    Extensions, Lsd, PartialPiece, PeerAddress, PeerConnection, PeerId, PeerList, PeerMessage,
+    PeerStats, PiecePicker, Resume, ResumeData, Sha1, Startup, Storage, Torrent, UploadRequest,
+    UtMetadata, UtpSocket, WebSeed,
+};
+use super::{PeerExchange, UtPex};
+
+// Number of corrupt pieces we accept from a peer before giving up on it
+const MAX_SHA_MISMATCHES: usize = 3;
+
+// Number of pieces a peer may refuse to send us before we give up on it
+const MAX_REJECTED_PIECES: usize = 3;
+
+// How long we stay connected to a peer that has pieces we want, but will not
+// let us have them
+const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Number of peers we upload to at the same time, besides the optimistic
+// unchoke
+const UPLOAD_SLOTS: usize = 3;
+
+// Largest block a peer may ask for; larger requests are dropped
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+
+// Most peers we keep track of, however many we learn about
+const MAX_KNOWN_PEERS: usize = 1000;
+
+// How often the DHT is asked for peers, and told about us
+const DHT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// Least time between announces to the tracker, whatever it asks for
+const MIN_TRACKER_INTERVAL: Duration = Duration::from_secs(60);
+
+// Failed requests in a row after which a web seed is given up on, and how
+// long to wait after each
+const MAX_WEB_SEED_FAILURES: usize = 3;
+const WEB_SEED_RETRY: Duration = Duration::from_secs(10);
+
+// How often the resume file is brought up to date
+const RESUME_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct Downloader {
+    torrent: Torrent,
+    peer_list: PeerList,
+    max_peers: usize,
+    seeding: bool,
+    incoming: Option<UnboundedReceiver<PeerConnection>>,
+    resume: Option<(Resume, Startup)>,
+    // Port we accept connections on, if any
+    listen_port: Option<u16>,
+    dht: Option<Dht>,
+    lsd: Option<Lsd>,
+    utp: Option<UtpSocket>,
+    encryption: Encryption,
+}
+
+/// What the download shares with the tasks of its peers
+struct Swarm<S> {
+    torrent: Torrent,
+    queue: Mutex<WorkQueue>,
+    storage: Mutex<S>,
+    endgame: Endgame,
+    uploaded: AtomicUsize,
+    next_peer: AtomicUsize,
+    listen_port: Option<u16>,
+    pex: Arc<PeerExchange>,
+}
+
+#[derive(Debug)]
+pub struct DownloadStats {
+    // Bytes received for blocks we already had, mostly due to endgame
+    pub duplicate_bytes: usize,
+    pub uploaded_bytes: usize,
+}
+
+enum Event {
+    Piece(usize, Vec<u8>),
+    PeerLost,
+    // Peers found since the download started
+    Peers(Vec<PeerAddress>),
+}
+
+struct WorkQueue {
+    picker: PiecePicker,
+    complete: bool,
+    // What each connected peer has done for us lately, and which of them
+    // the choker lets us upload to
+    peers: HashMap<usize, PeerStats>,
+    unchoked: HashSet<usize>,
+}
+
+impl WorkQueue {
+    fn new(torrent: &Torrent) -> Self {
+        Self {
+            picker: PiecePicker::new(torrent),
+            complete: false,
+            peers: HashMap::new(),
+            unchoked: HashSet::new(),
+        }
+    }
+}
+
+/// The pieces we have told a peer about
+struct Announced(Option<Vec<bool>>);
+
+impl Announced {
+    fn new() -> Self {
+        Self(None)
+    }
+
+    /// Tell the peer about pieces we have completed since last time; the
+    /// first time around as a bitfield, or in short if the peer has the fast
+    /// extension and we have all or nothing
+    async fn update(&mut self, peer: &mut PeerConnection, have: &[bool]) {
+        let piece_count = have.len();
+
+        let announced = match &mut self.0 {
+            Some(announced) => announced,
+
+            None => {
+                let mut bitfield = Bitfield::new();
+                let mut announced = vec![false; piece_count];
+
+                for (piece, announced) in announced.iter_mut().enumerate() {
+                    if have[piece] {
+                        bitfield.set(piece);
+                        *announced = true;
+                    }
+                }
+
+                // Peers that have nothing may skip the bitfield, unless the
+                // fast extension is in use
+                if peer.supports_fast() && have.iter().all(|&have| have) {
+                    peer.send(PeerMessage::HaveAll).await;
+                } else if peer.supports_fast() && bitfield.0.is_empty() {
+                    peer.send(PeerMessage::HaveNone).await;
+                } else if !bitfield.0.is_empty() {
+                    bitfield.0.resize(piece_count.div_ceil(8), 0);
+                    peer.send(PeerMessage::Bitfield(bitfield.0)).await;
+                }
+
+                self.0 = Some(announced);
+                return;
+            }
+        };
+
+        for (piece, announced) in announced.iter_mut().enumerate() {
+            if !*announced && have[piece] {
+                peer.send(PeerMessage::Have(piece as u32)).await;
+                *announced = true;
+            }
+        }
+    }
+}
+
+impl Downloader {
+    pub fn new(torrent: &Torrent, peer_list: PeerList, max_peers: usize) -> Self {
+        Self {
+            torrent: torrent.clone(),
+            peer_list,
+            max_peers,
+            seeding: false,
+            incoming: None,
+            resume: None,
+            listen_port: None,
+            dht: None,
+            lsd: None,
+            utp: None,
+            encryption: Encryption::default(),
+        }
+    }
+
+    /// Keep serving peers after the download has completed
+    pub fn with_seeding(mut self, seeding: bool) -> Self {
+        self.seeding = seeding;
+        self
+    }
+
+    /// Also exchange pieces with the peers that connect to us
+    pub fn with_incoming(mut self, incoming: UnboundedReceiver<PeerConnection>) -> Self {
+        self.incoming = Some(incoming);
+        self
+    }
+
+    /// Tell peers they can connect to us on `port`
+    pub fn with_listen_port(mut self, port: u16) -> Self {
+        self.listen_port = Some(port);
+        self
+    }
+
+    /// Also find peers through the DHT, unless the torrent is private
+    pub fn with_dht(mut self, dht: Dht) -> Self {
+        self.dht = Some(dht);
+        self
+    }
+
+    /// Also find peers on the local network, unless the torrent is private
+    pub fn with_lsd(mut self, lsd: Lsd) -> Self {
+        self.lsd = Some(lsd);
+        self
+    }
+
+    /// Connect to peers over uTP where they accept it
+    pub fn with_utp(mut self, utp: UtpSocket) -> Self {
+        self.utp = Some(utp);
+        self
+    }
+
+    /// Encrypt connections to peers as far as `encryption` asks
+    pub fn with_encryption(mut self, encryption: Encryption) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Pick up from where the download was before, as `startup` describes,
+    /// and keep the resume file up to date
+    pub fn with_resume(mut self, resume: Resume, startup: Startup) -> Self {
+        self.resume = Some((resume, startup));
+        self
+    }
+
+    /// Download every piece, writing each to its place in `storage` as soon
+    /// as it has been verified, and serve the pieces we have to other peers.
+    /// Every peer is handled by a task of its own.
+    pub async fn run<S: Storage + Send + 'static>(
+        mut self,
+        storage: S,
+    ) -> Result<DownloadStats, DownloadError> {
+        let swarm = Arc::new(Swarm {
+            queue: Mutex::new(WorkQueue::new(&self.torrent)),
+            torrent: self.torrent,
+            storage: Mutex::new(storage),
+            endgame: Endgame::new(),
+            uploaded: AtomicUsize::new(0),
+            next_peer: AtomicUsize::new(0),
+            listen_port: self.listen_port,
+            pex: Arc::new(PeerExchange::new()),
+        });
+
+        let (event_sender, mut events) = mpsc::unbounded_channel();
+
+        let (resume, startup) = match self.resume {
+            Some((resume, startup)) => (Some(Arc::new(resume)), startup),
+            None => (None, Startup::Fresh),
+        };
+
+        let mut known_peers = self.peer_list.peers;
+
+        let resumed_peers = match startup {
+            Startup::Fresh => Vec::new(),
+
+            Startup::Resume(data) => {
+                let swarm = Arc::clone(&swarm);
+
+                tokio::task::spawn_blocking(move || {
+                    swarm.restore(&data);
+                    data.peers
+                })
+                .await
+                .expect("Cannot restore resumed pieces")
+            }
+
+            Startup::Recheck(peers) => {
+                let swarm = Arc::clone(&swarm);
+
+                tokio::task::spawn_blocking(move || swarm.recheck())
+                    .await
+                    .expect("Cannot check existing pieces");
+
+                peers
+            }
+        };
+
+        for peer in resumed_peers {
+            if !known_peers.contains(&peer) {
+                known_peers.push(peer);
+            }
+        }
+
+        let our_id = self.peer_list.our_id;
+        let info_hash = self.peer_list.expected_info_hash;
+        let mut addresses: VecDeque<PeerAddress> = known_peers.iter().cloned().collect();
+        let mut last_save = Instant::now();
+
+        // A tracker that answered is asked again once its interval is up
+        let searching_tracker = self.peer_list.interval > 0;
+
+        if searching_tracker {
+            tokio::spawn(search_tracker(
+                swarm.torrent.clone(),
+                our_id,
+                self.listen_port,
+                self.peer_list.interval,
+                event_sender.clone(),
+            ));
+        }
+
+        // Private torrents keep to the peers their trackers hand out
+        let dht = self.dht.filter(|_| !swarm.torrent.private);
+        let searching_dht = dht.is_some();
+
+        if let Some(dht) = dht {
+            tokio::spawn(search_dht(
+                dht,
+                info_hash,
+                self.listen_port,
+                event_sender.clone(),
+            ));
+        }
+
+        let lsd = self.lsd.filter(|_| !swarm.torrent.private);
+        let searching_lsd = lsd.is_some();
+
+        if let Some(lsd) = lsd {
+            tokio::spawn(search_lsd(
+                lsd,
+                info_hash,
+                self.listen_port,
+                event_sender.clone(),
+            ));
+        }
+
+        let mut choker = Choker::new(UPLOAD_SLOTS);
+        let mut active_peers = 0;
+
+        // Web seeds have every piece, and count as peers for as long as they
+        // serve them
+        for url in &swarm.torrent.web_seeds {
+            let seed = WebSeed::new(url, &swarm.torrent);
+            let swarm = Arc::clone(&swarm);
+            let events = event_sender.clone();
+
+            tokio::spawn(async move { swarm.work_web_seed(seed, events).await });
+            active_peers += 1;
+        }
+
+        let spawn_next = |active_peers: &mut usize, addresses: &mut VecDeque<PeerAddress>| {
+            if let Some(address) = addresses.pop_front() {
+                let swarm = Arc::clone(&swarm);
+                let events = event_sender.clone();
+                let utp = self.utp.clone();
+                let encryption = self.encryption;
+
+                tokio::spawn(async move {
+                    let peer = address
+                        .connect(our_id, info_hash, utp.as_ref(), encryption)
+                        .await;
+                    swarm.work(peer, events).await;
+                });
+
+                *active_peers += 1;
+            }
+        };
+
+        let result = loop {
+            let complete = swarm.queue.lock().unwrap().picker.is_complete();
+
+            if complete && !self.seeding {
+                break Ok(());
+            }
+
+            // Peers learned through peer exchange join the pool
+            join_pool(&mut known_peers, &mut addresses, swarm.pex.take_learned());
+
+            while active_peers < self.max_peers && !addresses.is_empty() {
+                spawn_next(&mut active_peers, &mut addresses);
+            }
+
+            // A seed can wait for peers to come to it, and a download for
+            // the DHT or the local network to turn some up
+            let waiting = if complete {
+                self.incoming.is_some()
+            } else {
+                searching_tracker || searching_dht || searching_lsd
+            };
+
+            if active_peers == 0 && !waiting {
+                break if complete {
+                    Ok(())
+                } else {
+                    Err(DownloadError::OutOfPeers)
+                };
+            }
+
+            swarm.choke(&mut choker, complete);
+
+            if let Some(resume) = &resume {
+                if last_save.elapsed() >= RESUME_INTERVAL {
+                    Arc::clone(&swarm)
+                        .save(Arc::clone(resume), known_peers.clone())
+                        .await;
+                    last_save = Instant::now();
+                }
+            }
+
+            tokio::select! {
+                event = events.recv() => match event {
+                    Some(Event::Piece(piece, bytes)) => {
+                        if let Err(e) = Arc::clone(&swarm).store(piece, bytes).await {
+                            break Err(DownloadError::Storage(e));
+                        }
+                    }
+
+                    // Another peer takes its place next time around
+                    Some(Event::PeerLost) => active_peers -= 1,
+
+                    Some(Event::Peers(peers)) => join_pool(&mut known_peers, &mut addresses, peers),
+
+                    None => break Err(DownloadError::OutOfPeers),
+                },
+
+                Some(peer) = next_incoming(&mut self.incoming) => {
+                    if active_peers < self.max_peers {
+                        let swarm = Arc::clone(&swarm);
+                        let events = event_sender.clone();
+
+                        tokio::spawn(async move { swarm.work(Some(peer), events).await });
+                        active_peers += 1;
+                    }
+                }
+
+                _ = tokio::signal::ctrl_c() => break Err(DownloadError::Interrupted),
+
+                // Keep the choker going when nothing happens
+                _ = sleep(Duration::from_secs(1)) => (),
+            }
+        };
+
+        // Tells the remaining peer tasks to wind down
+        swarm.queue.lock().unwrap().complete = true;
+        swarm.storage.lock().unwrap().flush().ok();
+
+        if let Some(resume) = &resume {
+            Arc::clone(&swarm)
+                .save(Arc::clone(resume), known_peers.clone())
+                .await;
+        }
+
+        result.map(|()| DownloadStats {
+            duplicate_bytes: swarm.endgame.duplicate_bytes(),
+            uploaded_bytes: swarm.uploaded.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Add newly found peers to those we know, and to those we have yet to
+/// connect to
+fn join_pool(
+    known_peers: &mut Vec<PeerAddress>,
+    addresses: &mut VecDeque<PeerAddress>,
+    peers: Vec<PeerAddress>,
+) {
+    for peer in peers {
+        if known_peers.len() < MAX_KNOWN_PEERS && !known_peers.contains(&peer) {
+            known_peers.push(peer.clone());
+            addresses.push_back(peer);
+        }
+    }
+}
+
+fn have(picker: &PiecePicker, piece_count: usize) -> Vec<bool> {
+    (0..piece_count).map(|piece| picker.has(piece)).collect()
+}
+
+/// Announce ourselves to the tracker again each time the interval it asked
+/// for is up, and report the peers it hands out
+async fn search_tracker(
+    torrent: Torrent,
+    our_id: PeerId,
+    listen_port: Option<u16>,
+    mut interval: usize,
+    events: UnboundedSender<Event>,
+) {
+    loop {
+        sleep(MIN_TRACKER_INTERVAL.max(Duration::from_secs(interval as u64))).await;
+
+        let peer_list = torrent
+            .get_peer_list(our_id, listen_port.unwrap_or(0))
+            .await;
+
+        // A tracker that does not answer is tried again as often as before
+        if peer_list.interval > 0 {
+            interval = peer_list.interval;
+        }
+
+        // The download is over once nobody listens
+        if events.send(Event::Peers(peer_list.peers)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Regularly look the torrent up in the DHT, announcing ourselves if we
+/// accept connections, and report the peers found
+async fn search_dht(
+    dht: Dht,
+    info_hash: Sha1,
+    listen_port: Option<u16>,
+    events: UnboundedSender<Event>,
+) {
+    loop {
+        dht.refresh().await;
+
+        let peers = match listen_port {
+            Some(port) => dht.announce(info_hash, port).await,
+            None => dht.get_peers(info_hash).await,
+        };
+
+        // The download is over once nobody listens
+        if events.send(Event::Peers(peers)).is_err() {
+            return;
+        }
+
+        sleep(DHT_INTERVAL).await;
+    }
+}
+
+/// Announce ourselves on the local network now and then if we accept
+/// connections, and report the local peers that announce the torrent
+async fn search_lsd(
+    lsd: Lsd,
+    info_hash: Sha1,
+    listen_port: Option<u16>,
+    events: UnboundedSender<Event>,
+) {
+    let mut found = lsd.watch(info_hash);
+
+    loop {
+        if let Some(port) = listen_port {
+            lsd.announce(info_hash, port).await;
+        }
+
+        let next_announce = sleep(ANNOUNCE_INTERVAL);
+        tokio::pin!(next_announce);
+
+        loop {
+            tokio::select! {
+                Some(peer) = found.recv() => {
+                    // The download is over once nobody listens
+                    if events.send(Event::Peers(vec![peer])).is_err() {
+                        return;
+                    }
+                }
+
+                _ = &mut next_announce => break,
+            }
+        }
+
+        if events.is_closed() {
+            return;
+        }
+    }
+}
+
+/// The next connection a peer made to us, if we accept any
+async fn next_incoming(
+    incoming: &mut Option<UnboundedReceiver<PeerConnection>>,
+) -> Option<PeerConnection> {
+    match incoming {
+        Some(incoming) => incoming.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+impl<S: Storage> Swarm<S> {
+    /// Take over the pieces and blocks the resume file says we have
+    fn restore(&self, data: &ResumeData) {
+        let mut partial = Vec::new();
+        let mut storage = self.storage.lock().unwrap();
+
+        for (piece, blocks) in &data.unfinished {
+            if data.have.get(*piece) == Some(&true) {
+                continue;
+            }
+
+            let size = self.torrent.piece_size(*piece);
+            let stored = match storage.read_block(*piece, 0, size) {
+                Ok(stored) => stored,
+                Err(_) => continue,
+            };
+
+            let mut progress = PartialPiece::new(*piece, size);
+
+            for block in blocks {
+                let begin = *block as usize;
+                let end = (begin + BLOCK_SIZE as usize).min(size);
+
+                if begin < end {
+                    progress.add_block(*block, &stored[begin..end]);
+                }
+            }
+
+            partial.push(progress);
+        }
+
+        drop(storage);
+        let mut queue = self.queue.lock().unwrap();
+
+        for (piece, _) in data.have.iter().enumerate().filter(|(_, &have)| have) {
+            queue.picker.complete(piece);
+        }
+
+        // Handing the pieces back keeps their blocks for whoever picks them
+        for progress in partial {
+            queue.picker.abandon(progress);
+        }
+    }
+
+    /// Find out which pieces the payload already holds by their hashes
+    fn recheck(&self)
+    where
+        S: Send,
+    {
+        let valid = check_pieces(
+            self.torrent.piece_count(),
+            || (),
+            |_, piece| {
+                let mut storage = self.storage.lock().unwrap();
+                storage.verify_piece(piece).unwrap_or(false)
+            },
+        );
+
+        let mut queue = self.queue.lock().unwrap();
+
+        for piece in (0..valid.len()).filter(|&piece| valid[piece]) {
+            queue.picker.complete(piece);
+        }
+    }
+
+    /// Bring the resume file up to date, away from the async threads. Blocks
+    /// of unfinished pieces are written to the payload, so that they need
+    /// not be downloaded again.
+    async fn save(self: Arc<Self>, resume: Arc<Resume>, peers: Vec<PeerAddress>)
+    where
+        S: Send + 'static,
+    {
+        // The peers only wait for the picker to be copied, not written out
+        let (have, partial) = {
+            let queue = self.queue.lock().unwrap();
+            let partial: Vec<PartialPiece> = queue.picker.partial_pieces().cloned().collect();
+
+            (have(&queue.picker, self.torrent.piece_count()), partial)
+        };
+
+        let swarm = Arc::clone(&self);
+
+        tokio::task::spawn_blocking(move || {
+            let mut storage = swarm.storage.lock().unwrap();
+
+            let unfinished = partial
+                .iter()
+                .filter(|progress| {
+                    storage
+                        .write_block(progress.index(), 0, progress.data())
+                        .is_ok()
+                })
+                .map(|progress| {
+                    let blocks = progress.received().map(|block| block.begin).collect();
+                    (progress.index(), blocks)
+                })
+                .collect();
+
+            if storage.flush().is_err() {
+                return;
+            }
+
+            drop(storage);
+
+            let data = ResumeData {
+                have,
+                unfinished,
+                peers,
+            };
+
+            // Without a resume file, the next start merely has to recheck
+            resume.save(&data).ok();
+        })
+        .await
+        .ok();
+    }
+
+    /// Write a verified piece to the payload, away from the async threads
+    async fn store(self: Arc<Self>, piece: usize, bytes: Vec<u8>) -> io::Result<()>
+    where
+        S: Send + 'static,
+    {
+        // In endgame, several peers may complete the same piece
+        if self.queue.lock().unwrap().picker.has(piece) {
+            return Ok(());
+        }
+
+        let swarm = Arc::clone(&self);
+
+        tokio::task::spawn_blocking(move || {
+            let mut storage = swarm.storage.lock().unwrap();
+
+            storage.write_block(piece, 0, &bytes)?;
+            storage.flush()?;
+
+            // What reads back is what gets served, so it has to match too
+            if storage.verify_piece(piece)? {
+                Ok(())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Piece does not read back as written",
+                ))
+            }
+        })
+        .await
+        .map_err(io::Error::other)??;
+
+        // Only now that it is stored can the piece be served
+        self.queue.lock().unwrap().picker.complete(piece);
+        self.endgame.finish(piece);
+
+        Ok(())
+    }
+
+    /// Which pieces we have
+    fn have(&self) -> Vec<bool> {
+        have(
+            &self.queue.lock().unwrap().picker,
+            self.torrent.piece_count(),
+        )
+    }
+
+    /// The extensions the connection to `peer` uses. Private torrents keep
+    /// to the peers their trackers hand out.
+    fn extensions(&self, peer: &PeerConnection) -> Extensions {
+        let metadata = Arc::clone(&self.torrent.info.0);
+
+        let extensions = Extensions::new()
+            .with_port(self.listen_port)
+            .with(Box::new(UtMetadata::serving(metadata)));
+
+        if self.torrent.private {
+            return extensions;
+        }
+
+        let peer_ip = peer.stream.peer_addr().ok().map(|address| address.ip());
+
+        extensions.with(Box::new(UtPex::new(Arc::clone(&self.pex), peer_ip)))
+    }
+
+    /// What peer exchange tells others about `peer`
+    fn pex_flags(&self, peer: &PeerConnection) -> u8 {
+        let mut flags = 0;
+
+        if (0..self.torrent.piece_count()).all(|piece| peer.has_piece(piece)) {
+            flags |= SEED;
+        }
+
+        // We reached it ourselves
+        if peer.slot.is_none() {
+            flags |= REACHABLE;
+        }
+
+        if peer.stream.is_encrypted() {
+            flags |= ENCRYPTION;
+        }
+
+        if peer.stream.is_utp() {
+            flags |= UTP;
+        }
+
+        flags
+    }
+
+    async fn work(self: Arc<Self>, peer: Option<PeerConnection>, events: UnboundedSender<Event>)
+    where
+        S: Send + 'static,
+    {
+        if let Some(peer) = peer {
+            let mut peer = peer
+                .with_piece_count(self.torrent.piece_count())
+                .with_source(Arc::clone(&self) as Arc<dyn BlockSource>);
+            let key = self.next_peer.fetch_add(1, Ordering::Relaxed);
+            let mut availability = Availability::new(self.torrent.piece_count());
+            let mut announced = Announced::new();
+
+            // The pieces we have go first, as the fast extension requires
+            announced.update(&mut peer, &self.have()).await;
+            peer.allow_fast(self.torrent.info_hash, self.torrent.piece_count())
+                .await;
+            peer.start_extensions(self.extensions(&peer)).await;
+
+            // Give the peer a chance to tell us which pieces it has, and
+            // where it accepts connections
+            if peer.pump().await.is_ok() {
+                let address = peer.listen_address();
+
+                if let Some(address) = &address {
+                    self.pex.connected(address.clone(), self.pex_flags(&peer));
+                }
+
+                self.exchange(key, &mut peer, &events, &mut availability, announced)
+                    .await;
+
+                if let Some(address) = &address {
+                    self.pex.disconnected(address);
+                }
+            }
+
+            let mut queue = self.queue.lock().unwrap();
+
+            availability.withdraw(&mut queue.picker);
+            queue.peers.remove(&key);
+            queue.unchoked.remove(&key);
+        }
+
+        events.send(Event::PeerLost).ok();
+    }
+
+    /// Fetch pieces from a web seed alongside the peers, until the download
+    /// is done or the seed stops answering
+    async fn work_web_seed(&self, seed: WebSeed, events: UnboundedSender<Event>) {
+        let mut failures = 0;
+
+        loop {
+            let progress = {
+                let mut queue = self.queue.lock().unwrap();
+
+                if queue.complete || queue.picker.is_complete() {
+                    break;
+                }
+
+                queue.picker.pick(|_| true).or_else(|| {
+                    if queue.picker.in_endgame() {
+                        self.endgame.activate();
+                        queue.picker.pick_endgame(|_| true)
+                    } else {
+                        None
+                    }
+                })
+            };
+
+            let progress = match progress {
+                Some(progress) => progress,
+
+                None => {
+                    sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            match seed.fetch(progress.index()).await {
+                Some(data) => {
+                    failures = 0;
+
+                    if events.send(Event::Piece(progress.index(), data)).is_err() {
+                        break;
+                    }
+                }
+
+                None => {
+                    self.queue.lock().unwrap().picker.abandon(progress);
+                    failures += 1;
+
+                    if failures >= MAX_WEB_SEED_FAILURES {
+                        break;
+                    }
+
+                    sleep(WEB_SEED_RETRY).await;
+                }
+            }
+        }
+
+        events.send(Event::PeerLost).ok();
+    }
+
+    async fn exchange(
+        &self,
+        key: usize,
+        peer: &mut PeerConnection,
+        events: &UnboundedSender<Event>,
+        availability: &mut Availability,
+        mut announced: Announced,
+    ) {
+        let mut sha_mismatches = 0;
+        let mut rejected_pieces = 0;
+        let mut unchoke_deadline = None;
+
+        loop {
+            let (interesting, have) = {
+                let mut queue = self.queue.lock().unwrap();
+                let piece_count = self.torrent.piece_count();
+
+                if queue.complete {
+                    return;
+                }
+
+                // Two seeds have nothing to say to each other
+                if queue.picker.is_complete() && (0..piece_count).all(|p| peer.has_piece(p)) {
+                    return;
+                }
+
+                availability.update(|piece| peer.has_piece(piece), &mut queue.picker);
+
+                let have = have(&queue.picker, piece_count);
+                let interesting = queue.picker.is_interesting(|piece| peer.has_piece(piece));
+
+                (interesting, have)
+            };
+
+            announced.update(peer, &have).await;
+            peer.set_interested(interesting).await;
+
+            self.update_choking(key, peer).await;
+
+            if !interesting {
+                // Nothing this peer can help with right now, but it may
+                // announce more pieces later, or ask for ours
+                if !peer.has_requests() {
+                    sleep(Duration::from_millis(100)).await;
+                }
+
+                if peer.pump().await.is_err() {
+                    return;
+                }
+
+                continue;
+            }
+
+            if peer.peer_choking {
+                let deadline =
+                    *unchoke_deadline.get_or_insert_with(|| Instant::now() + UNCHOKE_TIMEOUT);
+
+                if Instant::now() > deadline {
+                    return;
+                }
+
+                // Wait in short slices, so that we notice when the download
+                // completes and keep serving the peer meanwhile. Allowed fast
+                // pieces can be downloaded in the meantime.
+                if !peer.has_allowed_fast() {
+                    match peer.wait_for_unchoke(Duration::from_secs(1)).await {
+                        Ok(()) | Err(DownloadError::Choked) => continue,
+                        Err(_) => return,
+                    }
+                }
+            } else {
+                unchoke_deadline = None;
+            }
+
+            let progress = {
+                let mut queue = self.queue.lock().unwrap();
+                let has_piece = |piece| {
+                    peer.has_piece(piece) && (!peer.peer_choking || peer.is_allowed_fast(piece))
+                };
+
+                // Pieces the peer suggests are likely in its cache
+                queue
+                    .picker
+                    .pick(|piece| has_piece(piece) && peer.is_suggested(piece))
+                    .or_else(|| queue.picker.pick(has_piece))
+                    .or_else(|| {
+                        if queue.picker.in_endgame() {
+                            self.endgame.activate();
+                            queue.picker.pick_endgame(has_piece)
+                        } else {
+                            None
+                        }
+                    })
+            };
+
+            let mut progress = match progress {
+                Some(progress) => progress,
+
+                None => {
+                    // Everything this peer has is being downloaded from others,
+                    // but one of them may drop a piece
+                    sleep(Duration::from_millis(100)).await;
+
+                    if peer.pump().await.is_err() {
+                        return;
+                    }
+
+                    continue;
+                }
+            };
+
+            match peer
+                .download(&self.torrent, &mut progress, &self.endgame)
+                .await
+            {
+                Ok(()) => {
+                    let piece = progress.index();
+
+                    if events
+                        .send(Event::Piece(piece, progress.into_data()))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+
+                Err(error) => {
+                    let piece = progress.index() as u32;
+                    self.queue.lock().unwrap().picker.abandon(progress);
+
+                    match error {
+                        DownloadError::PeerDisconnect | DownloadError::Protocol => return,
+
+                        DownloadError::ShaMismatch => {
+                            sha_mismatches += 1;
+
+                            if sha_mismatches >= MAX_SHA_MISMATCHES {
+                                return;
+                            }
+                        }
+
+                        // Do not ask for the piece again while choked
+                        DownloadError::Rejected => {
+                            peer.allowed_fast.retain(|&allowed| allowed != piece);
+                            rejected_pieces += 1;
+
+                            if rejected_pieces >= MAX_REJECTED_PIECES {
+                                return;
+                            }
+                        }
+
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Let the choker reconsider whom we upload to
+    fn choke(&self, choker: &mut Choker, seeding: bool) {
+        let mut queue = self.queue.lock().unwrap();
+
+        if let Some(unchoked) = choker.tick(Instant::now(), &queue.peers, seeding) {
+            queue.unchoked = unchoked.clone();
+        }
+    }
+
+    /// Report how the peer is doing to the choker, and follow its decision
+    async fn update_choking(&self, key: usize, peer: &mut PeerConnection) {
+        let unchoked = {
+            let mut queue = self.queue.lock().unwrap();
+
+            queue.peers.insert(
+                key,
+                PeerStats {
+                    interested: peer.peer_interested,
+                    snubbed: peer.is_snubbing(),
+                    downloaded: peer.downloaded,
+                    uploaded: peer.uploaded,
+                },
+            );
+
+            queue.unchoked.contains(&key)
+        };
+
+        peer.set_choking(!unchoked).await;
+    }
+
+    fn is_valid_request(&self, request: &UploadRequest, picker: &PiecePicker) -> bool {
+        let piece = request.index as usize;
+
+        if piece >= self.torrent.piece_count() || !picker.has(piece) {
+            return false;
+        }
+
+        let end = request.begin as usize + request.length as usize;
+
+        request.length != 0
+            && request.length <= MAX_REQUEST_LENGTH
+            && end <= self.torrent.piece_size(piece)
+    }
+}
+
+/// Peers are served the pieces we have verified and stored
+impl<S: Storage + Send> BlockSource for Swarm<S> {
+    fn read_block(&self, request: &UploadRequest) -> Option<Vec<u8>> {
+        if !self.is_valid_request(request, &self.queue.lock().unwrap().picker) {
+            return None;
+        }
+
+        let block = self
+            .storage
+            .lock()
+            .unwrap()
+            .read_block(
+                request.index as usize,
+                request.begin as usize,
+                request.length as usize,
+            )
+            .ok()?;
+
+        self.uploaded.fetch_add(block.len(), Ordering::Relaxed);
+        Some(block)
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use super::pipeline::{BlockRequest, PartialPiece};
+
+/// Once every remaining block has been requested, the last pieces are
+/// requested from several peers at once. Blocks are shared between the peers
+/// working on the same piece through this board, so that each block is only
+/// waited for until the first copy arrives.
+#[derive(Debug, Default)]
+pub struct Endgame {
+    active: AtomicBool,
+    board: Mutex<Board>,
+    duplicate_bytes: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct Board {
+    pieces: HashMap<usize, PartialPiece>,
+    finished: HashSet<usize>,
+}
+
+/// Outcome of comparing notes with the other peers working on a piece
+pub enum Sync {
+    // Blocks that other peers have delivered since last time
+    Arrived(Vec<BlockRequest>),
+    // Another peer has already completed the piece
+    Finished,
+}
+
+impl Endgame {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn activate(&self) {
+        self.active.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Share the blocks of `progress` with the board and take over the blocks
+    /// others have received for the same piece
+    pub fn sync(&self, progress: &mut PartialPiece) -> Sync {
+        if !self.is_active() {
+            return Sync::Arrived(Vec::new());
+        }
+
+        let mut board = self.board.lock().unwrap();
+        let piece = progress.index();
+
+        if board.finished.contains(&piece) {
+            return Sync::Finished;
+        }
+
+        let shared = board
+            .pieces
+            .entry(piece)
+            .or_insert_with(|| PartialPiece::new(piece, progress.data().len()));
+
+        shared.merge(progress);
+        Sync::Arrived(progress.merge(shared))
+    }
+
+    pub fn finish(&self, piece: usize) {
+        let mut board = self.board.lock().unwrap();
+
+        board.pieces.remove(&piece);
+        board.finished.insert(piece);
+    }
+
+    /// Forget what the board holds of `piece`, after it failed its hash
+    /// check, so that no peer picks up the corrupt blocks again
+    pub fn discard(&self, piece: usize) {
+        self.board.lock().unwrap().pieces.remove(&piece);
+    }
+
+    /// Account for bytes received for a block we already had
+    pub fn wasted(&self, bytes: usize) {
+        self.duplicate_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn duplicate_bytes(&self) -> usize {
+        self.duplicate_bytes.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::pipeline::BLOCK_SIZE;
+    use super::*;
+
+    const BLOCK: usize = BLOCK_SIZE as usize;
+
+    fn block(begin: usize) -> BlockRequest {
+        BlockRequest {
+            begin: begin as u32,
+            length: BLOCK_SIZE,
+        }
+    }
+
+    fn arrived(sync: Sync) -> Vec<BlockRequest> {
+        match sync {
+            Sync::Arrived(blocks) => blocks,
+            Sync::Finished => panic!("Piece is finished"),
+        }
+    }
+
+    #[test]
+    fn blocks_are_shared_both_ways() {
+        let endgame = Endgame::new();
+        endgame.activate();
+
+        let mut first = PartialPiece::new(0, 3 * BLOCK);
+        let mut second = PartialPiece::new(0, 3 * BLOCK);
+
+        first.add_block(0, &[1; BLOCK]);
+        second.add_block(BLOCK_SIZE * 2, &[3; BLOCK]);
+
+        assert!(arrived(endgame.sync(&mut first)).is_empty());
+        assert_eq!(arrived(endgame.sync(&mut second)), vec![block(0)]);
+        assert_eq!(arrived(endgame.sync(&mut first)), vec![block(2 * BLOCK)]);
+
+        assert_eq!(&first.data()[2 * BLOCK..], &[3; BLOCK]);
+        assert_eq!(&second.data()[..BLOCK], &[1; BLOCK]);
+    }
+
+    #[test]
+    fn nothing_is_shared_before_the_endgame() {
+        let endgame = Endgame::new();
+
+        let mut first = PartialPiece::new(0, BLOCK);
+        let mut second = PartialPiece::new(0, BLOCK);
+        first.add_block(0, &[1; BLOCK]);
+
+        assert!(arrived(endgame.sync(&mut first)).is_empty());
+        assert!(arrived(endgame.sync(&mut second)).is_empty());
+        assert!(second.is_empty());
+    }
+
+    #[test]
+    fn finished_pieces_are_not_worked_on() {
+        let endgame = Endgame::new();
+        endgame.activate();
+
+        let mut progress = PartialPiece::new(4, BLOCK);
+        endgame.finish(4);
+
+        assert!(matches!(endgame.sync(&mut progress), Sync::Finished));
+    }
+
+    #[test]
+    fn discarded_blocks_are_not_shared_again() {
+        let endgame = Endgame::new();
+        endgame.activate();
+
+        let mut corrupt = PartialPiece::new(1, 2 * BLOCK);
+        corrupt.add_block(0, &[9; BLOCK]);
+        endgame.sync(&mut corrupt);
+
+        // The piece failed its hash check
+        endgame.discard(1);
+
+        let mut fresh = PartialPiece::new(1, 2 * BLOCK);
+        assert!(arrived(endgame.sync(&mut fresh)).is_empty());
+        assert!(fresh.is_empty());
+    }
+
+    #[test]
+    fn duplicate_bytes_add_up() {
+        let endgame = Endgame::new();
+
+        endgame.wasted(BLOCK);
+        endgame.wasted(100);
+
+        assert_eq!(endgame.duplicate_bytes(), BLOCK + 100);
+    }
+}
@@ -0,0 +1,361 @@
+use std::net::IpAddr;
+
+use super::{get_bstr, get_dict, get_int, Bencoded, PeerMessage};
+
+// Number of requests from a peer we are willing to queue
+pub const MAX_QUEUED_REQUESTS: usize = 250;
+
+/// A protocol extension negotiated through the extended handshake (BEP 10)
+pub trait Extension: Send {
+    /// Name the extension goes by in the handshake's `m` dictionary
+    fn name(&self) -> &'static str;
+
+    /// Add fields of the extension's own to our handshake
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// The peer's handshake has arrived, and it supports the extension
+    fn on_handshake(&mut self, _handshake: &ExtendedHandshake, _outbox: &mut Outbox) {}
+
+    /// A message for the extension has arrived
+    fn on_message(&mut self, payload: &[u8], outbox: &mut Outbox);
+
+    /// Called regularly, so that the extension can send messages of its own
+    /// accord
+    fn on_tick(&mut self, _outbox: &mut Outbox) {}
+}
+
+/// Payloads an extension wants sent to the peer
+pub type Outbox = Vec<Vec<u8>>;
+
+/// The payload of the extended handshake
+#[derive(Debug, Default, Clone)]
+pub struct ExtendedHandshake {
+    // Extension names, with the message ids the sender wants them sent under;
+    // an id of zero means the extension is disabled
+    pub m: Vec<(String, u8)>,
+    // Client name and version
+    pub v: Option<String>,
+    // Port the sender accepts connections on
+    pub p: Option<u16>,
+    // Number of outstanding requests the sender accepts
+    pub reqq: Option<usize>,
+    // The receiver's address as the sender sees it
+    pub yourip: Option<IpAddr>,
+    // Size of the info dictionary, for ut_metadata
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtendedHandshake {
+    fn parse(payload: &[u8]) -> Option<Self> {
+        let handshake = Bencoded::parse(payload)?;
+
+        if !matches!(handshake, Bencoded::Dict(_)) {
+            return None;
+        }
+
+        let m = match get_dict(&handshake, "m") {
+            Some(Bencoded::Dict(pairs)) => pairs
+                .into_iter()
+                .filter_map(|(name, id)| match (name, id) {
+                    (Bencoded::Bstr(name), Bencoded::Int(id)) => {
+                        Some((String::from_utf8(name).ok()?, u8::try_from(id).ok()?))
+                    }
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let yourip = get_bstr(&handshake, "yourip").and_then(|ip| match ip.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(ip).ok()?)),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(ip).ok()?)),
+            _ => None,
+        });
+
+        Some(Self {
+            m,
+            v: get_bstr(&handshake, "v").and_then(|v| String::from_utf8(v).ok()),
+            p: get_int(&handshake, "p").and_then(|p| u16::try_from(p).ok()),
+            reqq: get_int(&handshake, "reqq").and_then(|reqq| usize::try_from(reqq).ok()),
+            yourip,
+            metadata_size: get_int(&handshake, "metadata_size")
+                .and_then(|size| usize::try_from(size).ok()),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let m = Bencoded::Dict(
+            self.m
+                .iter()
+                .map(|(name, id)| (Bencoded::from(name.as_str()), Bencoded::Int(*id as i64)))
+                .collect(),
+        );
+
+        let mut fields = vec![("m", m)];
+
+        if let Some(v) = &self.v {
+            fields.push(("v", Bencoded::from(v.as_str())));
+        }
+
+        if let Some(p) = self.p {
+            fields.push(("p", Bencoded::Int(p as i64)));
+        }
+
+        if let Some(reqq) = self.reqq {
+            fields.push(("reqq", Bencoded::Int(reqq as i64)));
+        }
+
+        if let Some(yourip) = self.yourip {
+            let ip = match yourip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+
+            fields.push(("yourip", Bencoded::Bstr(ip)));
+        }
+
+        if let Some(size) = self.metadata_size {
+            fields.push(("metadata_size", Bencoded::Int(size as i64)));
+        }
+
+        (&Bencoded::dict(fields)).into()
+    }
+
+    /// The id the sender wants messages for `name` sent under, if it
+    /// supports the extension
+    fn id_of(&self, name: &str) -> Option<u8> {
+        self.m
+            .iter()
+            .find(|(supported, _)| supported == name)
+            .map(|(_, id)| *id)
+            .filter(|&id| id != 0)
+    }
+}
+
+/// The extensions in use on a connection. Each extension receives the
+/// messages the peer sends under the id we assigned it, which is its place
+/// in the registry counting from one.
+#[derive(Default)]
+pub struct Extensions {
+    handlers: Vec<Box<dyn Extension>>,
+    // Port we accept connections on, to tell peers
+    port: Option<u16>,
+    // The peer's handshake, once it has arrived
+    peer: Option<ExtendedHandshake>,
+}
+
+impl std::fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = self.handlers.iter().map(|handler| handler.name()).collect();
+
+        f.debug_struct("Extensions")
+            .field("handlers", &names)
+            .field("port", &self.port)
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_port(mut self, port: Option<u16>) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Register an extension
+    pub fn with(mut self, handler: Box<dyn Extension>) -> Self {
+        self.handlers.push(handler);
+        self
+    }
+
+    /// Our extended handshake, telling a peer at `peer_ip` what we support
+    pub fn handshake(&self, peer_ip: Option<IpAddr>) -> PeerMessage {
+        let mut handshake = ExtendedHandshake {
+            m: self
+                .handlers
+                .iter()
+                .enumerate()
+                .map(|(index, handler)| (String::from(handler.name()), index as u8 + 1))
+                .collect(),
+            v: Some(format!(
+                "{} {}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            )),
+            p: self.port,
+            reqq: Some(MAX_QUEUED_REQUESTS),
+            yourip: peer_ip,
+            metadata_size: None,
+        };
+
+        for handler in &self.handlers {
+            handler.extend_handshake(&mut handshake);
+        }
+
+        PeerMessage::Extended {
+            id: 0,
+            payload: handshake.encode(),
+        }
+    }
+
+    pub fn peer_handshake(&self) -> Option<&ExtendedHandshake> {
+        self.peer.as_ref()
+    }
+
+    /// Whether the peer has told us it supports the extension `name`
+    pub fn peer_supports(&self, name: &str) -> bool {
+        self.peer
+            .as_ref()
+            .is_some_and(|peer| peer.id_of(name).is_some())
+    }
+
+    /// Hand an extended message over to the extension it is meant for,
+    /// returning the messages to send in reply
+    pub fn receive(&mut self, id: u8, payload: &[u8]) -> Vec<PeerMessage> {
+        let mut replies = Vec::new();
+
+        if id == 0 {
+            let handshake = match ExtendedHandshake::parse(payload) {
+                Some(handshake) => handshake,
+                None => return replies,
+            };
+
+            for index in 0..self.handlers.len() {
+                if handshake.id_of(self.handlers[index].name()).is_some() {
+                    let mut outbox = Outbox::new();
+                    self.handlers[index].on_handshake(&handshake, &mut outbox);
+
+                    replies.extend(self.address(index, outbox, &handshake));
+                }
+            }
+
+            self.peer = Some(handshake);
+            return replies;
+        }
+
+        let index = id as usize - 1;
+
+        if let (Some(handler), Some(peer)) = (self.handlers.get_mut(index), &self.peer) {
+            let mut outbox = Outbox::new();
+            handler.on_message(payload, &mut outbox);
+
+            replies.extend(self.address(index, outbox, peer));
+        }
+
+        replies
+    }
+
+    /// Let every extension send what it wants to of its own accord
+    pub fn tick(&mut self) -> Vec<PeerMessage> {
+        let peer = match &self.peer {
+            Some(peer) => peer,
+            None => return Vec::new(),
+        };
+
+        let mut messages = Vec::new();
+
+        for index in 0..self.handlers.len() {
+            if peer.id_of(self.handlers[index].name()).is_some() {
+                let mut outbox = Outbox::new();
+                self.handlers[index].on_tick(&mut outbox);
+
+                messages.extend(self.address(index, outbox, peer));
+            }
+        }
+
+        messages
+    }
+
+    /// Turn the payloads of an extension into messages under the id the
+    /// peer asked for
+    fn address(&self, index: usize, outbox: Outbox, peer: &ExtendedHandshake) -> Vec<PeerMessage> {
+        match peer.id_of(self.handlers[index].name()) {
+            Some(id) => outbox
+                .into_iter()
+                .map(|payload| PeerMessage::Extended { id, payload })
+                .collect(),
+
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every message with the same payload
+    struct Echo;
+
+    impl Extension for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn on_message(&mut self, payload: &[u8], outbox: &mut Outbox) {
+            outbox.push(payload.to_vec());
+        }
+    }
+
+    fn peer_handshake(m: Vec<(&str, u8)>) -> Vec<u8> {
+        ExtendedHandshake {
+            m: m.into_iter()
+                .map(|(name, id)| (String::from(name), id))
+                .collect(),
+            reqq: Some(10),
+            ..Default::default()
+        }
+        .encode()
+    }
+
+    #[test]
+    fn handshakes_survive_encoding() {
+        let extensions = Extensions::new().with_port(Some(6881)).with(Box::new(Echo));
+
+        let payload = match extensions.handshake(Some(IpAddr::from([10, 0, 0, 2]))) {
+            PeerMessage::Extended { id: 0, payload } => payload,
+            message => panic!("Not an extended handshake: {:?}", message),
+        };
+
+        let handshake = ExtendedHandshake::parse(&payload).unwrap();
+
+        assert_eq!(handshake.m, vec![(String::from("echo"), 1)]);
+        assert_eq!(handshake.p, Some(6881));
+        assert_eq!(handshake.reqq, Some(MAX_QUEUED_REQUESTS));
+        assert_eq!(handshake.yourip, Some(IpAddr::from([10, 0, 0, 2])));
+        assert!(handshake.v.is_some());
+    }
+
+    #[test]
+    fn messages_are_dispatched_under_negotiated_ids() {
+        let mut extensions = Extensions::new().with(Box::new(Echo));
+
+        // Nothing is dispatched before the peer's handshake
+        assert!(extensions.receive(1, b"early").is_empty());
+
+        extensions.receive(0, &peer_handshake(vec![("other", 1), ("echo", 7)]));
+        assert!(extensions.peer_supports("echo"));
+
+        match extensions.receive(1, b"ping").as_slice() {
+            [PeerMessage::Extended { id: 7, payload }] => assert_eq!(payload, b"ping"),
+            replies => panic!("Unexpected replies: {:?}", replies),
+        }
+
+        // Ids we never assigned go nowhere
+        assert!(extensions.receive(2, b"ping").is_empty());
+    }
+
+    #[test]
+    fn disabled_extensions_get_nothing() {
+        let mut extensions = Extensions::new().with(Box::new(Echo));
+
+        extensions.receive(0, &peer_handshake(vec![("echo", 0)]));
+
+        assert!(!extensions.peer_supports("echo"));
+        assert!(extensions.receive(1, b"ping").is_empty());
+    }
+}
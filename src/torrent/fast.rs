@@ -0,0 +1,70 @@
+use std::net::IpAddr;
+
+use super::Sha1;
+
+// Byte and bit of the reserved handshake bytes announcing BEP 6 support
+pub const FAST_EXTENSION: (usize, u8) = (7, 0x04);
+
+// Number of pieces a peer may request from us while choked
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+// Most allowed fast pieces and suggestions we keep track of from a peer
+pub const MAX_FAST_PIECES: usize = 32;
+
+/// The pieces a peer at `ip` may request while choked, by the canonical
+/// algorithm of BEP 6, which only covers IPv4
+pub fn allowed_fast_set(ip: IpAddr, info_hash: Sha1, piece_count: usize, k: usize) -> Vec<u32> {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return Vec::new(),
+    };
+
+    let k = k.min(piece_count);
+    let mut allowed = Vec::with_capacity(k);
+
+    // Peers on the same /24 network share a set
+    let mut x = (u32::from(ip) & 0xffff_ff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash.as_ref());
+
+    while allowed.len() < k {
+        x = Sha1::digest(&x).as_ref().to_vec();
+
+        for chunk in x.chunks_exact(4) {
+            if allowed.len() >= k {
+                break;
+            }
+
+            let y = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            let index = (y as u64 % piece_count as u64) as u32;
+
+            if !allowed.contains(&index) {
+                allowed.push(index);
+            }
+        }
+    }
+
+    allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowed_fast_sets_match_the_specification() {
+        let ip = IpAddr::from([80, 4, 4, 200]);
+        let info_hash = Sha1::new_raw(&[0xaa; 20]);
+
+        assert_eq!(
+            allowed_fast_set(ip, info_hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+
+        assert_eq!(
+            allowed_fast_set(ip, info_hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+
+        assert!(allowed_fast_set(IpAddr::from([0u16; 8]), info_hash, 1313, 7).is_empty());
+    }
+}
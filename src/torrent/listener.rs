@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::{sleep, timeout};
+
+use super::mse::respond;
+use super::{
+    handshake, read_handshake, reserved_bytes, Encryption, PeerConnection, PeerId, PeerStream,
+    Sha1, Torrent, UtpSocket,
+};
+
+// Ports tried in turn before settling for whatever the system hands out
+const PORT_ATTEMPTS: u16 = 10;
+
+// Number of inbound connections we keep open across all torrents
+const MAX_INBOUND_CONNECTIONS: usize = 50;
+
+// How long a connecting peer has to send its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to wait after failing to accept a connection, typically for lack
+// of file descriptors, before trying again
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+struct Registration {
+    our_id: PeerId,
+    connections: UnboundedSender<PeerConnection>,
+}
+
+/// Accepts peer connections on a single port for any number of torrents,
+/// routing each connection to its torrent by the handshake's info hash
+pub struct Listener {
+    socket: Arc<TcpListener>,
+    // The same port for IPv6 peers, where the host has IPv6
+    socket6: Option<Arc<TcpListener>>,
+    torrents: Arc<Mutex<HashMap<Sha1, Registration>>>,
+    connections: Arc<AtomicUsize>,
+    encryption: Encryption,
+}
+
+/// Held by inbound connections for as long as they are open, to keep count of
+/// them
+#[derive(Debug)]
+pub struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Listener {
+    /// Listen on the first free port from `port` onwards, over IPv4 and,
+    /// where the host has it, IPv6
+    pub async fn bind(port: u16) -> io::Result<Self> {
+        let mut socket = None;
+
+        for port in port..port.saturating_add(PORT_ATTEMPTS) {
+            if let Ok(bound) = TcpListener::bind(("0.0.0.0", port)).await {
+                socket = Some(bound);
+                break;
+            }
+        }
+
+        let socket = match socket {
+            Some(socket) => socket,
+            None => TcpListener::bind(("0.0.0.0", 0)).await?,
+        };
+
+        // Hosts without IPv6, or with the port taken there, only miss out on
+        // IPv6 peers
+        let socket6 = bind_v6(socket.local_addr()?.port()).ok();
+
+        Ok(Self {
+            socket: Arc::new(socket),
+            socket6: socket6.map(Arc::new),
+            torrents: Arc::new(Mutex::new(HashMap::new())),
+            connections: Arc::new(AtomicUsize::new(0)),
+            encryption: Encryption::default(),
+        })
+    }
+
+    /// Which connections to take, by whether they are encrypted. Applies to
+    /// those accepted after `start`.
+    pub fn with_encryption(mut self, encryption: Encryption) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// The port actually bound, to be reported to trackers
+    pub fn port(&self) -> u16 {
+        self.socket
+            .local_addr()
+            .map(|address| address.port())
+            .unwrap_or_default()
+    }
+
+    /// Receive the connections made to us for `torrent`
+    pub fn register(&self, torrent: &Torrent, our_id: PeerId) -> UnboundedReceiver<PeerConnection> {
+        let (connections, receiver) = mpsc::unbounded_channel();
+
+        self.torrents.lock().unwrap().insert(
+            torrent.info_hash,
+            Registration {
+                our_id,
+                connections,
+            },
+        );
+
+        receiver
+    }
+
+    /// Accept connections in the background for as long as the program runs
+    pub fn start(&self) {
+        for socket in std::iter::once(&self.socket).chain(&self.socket6) {
+            let socket = Arc::clone(socket);
+            let torrents = Arc::clone(&self.torrents);
+            let connections = Arc::clone(&self.connections);
+            let encryption = self.encryption;
+
+            tokio::spawn(async move {
+                loop {
+                    let stream = match socket.accept().await {
+                        Ok((stream, _)) => stream,
+
+                        Err(_) => {
+                            sleep(ACCEPT_RETRY).await;
+                            continue;
+                        }
+                    };
+
+                    take_in(stream.into(), &connections, &torrents, encryption);
+                }
+            });
+        }
+    }
+
+    /// Also accept the uTP connections made to `utp`
+    pub fn start_utp(&self, utp: &UtpSocket) {
+        let mut incoming = utp.listen();
+        let torrents = Arc::clone(&self.torrents);
+        let connections = Arc::clone(&self.connections);
+        let encryption = self.encryption;
+
+        tokio::spawn(async move {
+            while let Some(stream) = incoming.recv().await {
+                take_in(stream.into(), &connections, &torrents, encryption);
+            }
+        });
+    }
+}
+
+/// Listen for IPv6 peers only on `port`, leaving IPv4 to the socket that
+/// already has it
+fn bind_v6(port: u16) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.listen(1024)?;
+
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Handle an inbound connection, if there is a slot for it
+fn take_in(
+    stream: PeerStream,
+    connections: &Arc<AtomicUsize>,
+    torrents: &Arc<Mutex<HashMap<Sha1, Registration>>>,
+    encryption: Encryption,
+) {
+    let acquired = connections
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+            (count < MAX_INBOUND_CONNECTIONS).then_some(count + 1)
+        })
+        .is_ok();
+
+    if !acquired {
+        return;
+    }
+
+    let slot = ConnectionSlot(Arc::clone(connections));
+    let torrents = Arc::clone(torrents);
+
+    // A slow handshake must not hold up the next connection
+    tokio::spawn(async move { accept(stream, slot, &torrents, encryption).await });
+}
+
+async fn accept(
+    mut stream: PeerStream,
+    slot: ConnectionSlot,
+    torrents: &Mutex<HashMap<Sha1, Registration>>,
+    encryption: Encryption,
+) -> Option<()> {
+    // An encrypted connection only says which torrent it is for by a hash
+    // of the info hash, so every one registered is tried
+    let info_hashes: Vec<Sha1> = torrents.lock().unwrap().keys().copied().collect();
+
+    timeout(
+        HANDSHAKE_TIMEOUT,
+        respond(&mut stream, &info_hashes, encryption),
+    )
+    .await
+    .ok()??;
+
+    let mut buffer = [0u8; 64 * 1024];
+    let mut received = timeout(HANDSHAKE_TIMEOUT, read_handshake(&mut stream, &mut buffer))
+        .await
+        .ok()??;
+
+    let info_hash = Sha1::new_raw(&received[28..48]);
+
+    let (our_id, connections) = torrents
+        .lock()
+        .unwrap()
+        .get(&info_hash)
+        .map(|registration| (registration.our_id, registration.connections.clone()))?;
+
+    stream.write_all(&handshake(info_hash, our_id)).await.ok()?;
+
+    let reserved = reserved_bytes(&received);
+    received.drain(..68);
+
+    let peer = PeerConnection::new(stream, buffer, received)
+        .with_slot(slot)
+        .with_reserved(reserved);
+    connections.send(peer).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    use super::super::{Payload, PieceHash, RawInfo};
+    use super::*;
+
+    fn torrent(name: &str) -> Torrent {
+        Torrent {
+            announce: String::new(),
+            info_hash: Sha1::digest(name.as_bytes()),
+            piece_length: 4,
+            pieces: vec![PieceHash::Sha1(Sha1::digest(b"piece"))],
+            private: false,
+            payload: Payload::Single {
+                name: String::from(name),
+                length: 4,
+            },
+            info: RawInfo(Arc::new(Vec::new())),
+            web_seeds: Vec::new(),
+        }
+    }
+
+    /// Both ends of a loopback connection, ours as a peer stream
+    async fn connected() -> (PeerStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ours = listener.accept();
+        let (ours, theirs) = tokio::join!(ours, TcpStream::connect(listener.local_addr().unwrap()));
+
+        (ours.unwrap().0.into(), theirs.unwrap())
+    }
+
+    /// Whether the other end has hung up on `stream`
+    async fn is_closed(stream: &mut TcpStream) -> bool {
+        let mut byte = [0u8; 1];
+
+        matches!(
+            timeout(Duration::from_secs(5), stream.read(&mut byte)).await,
+            Ok(Ok(0) | Err(_))
+        )
+    }
+
+    #[tokio::test]
+    async fn inbound_connections_hold_a_slot_while_open() {
+        let connections = Arc::new(AtomicUsize::new(MAX_INBOUND_CONNECTIONS - 1));
+        let torrents = Arc::new(Mutex::new(HashMap::new()));
+
+        let (stream, mut last) = connected().await;
+        take_in(stream, &connections, &torrents, Encryption::Prefer);
+        assert_eq!(connections.load(Ordering::Relaxed), MAX_INBOUND_CONNECTIONS);
+
+        // Beyond the limit, connections are closed right away
+        let (stream, mut refused) = connected().await;
+        take_in(stream, &connections, &torrents, Encryption::Prefer);
+        assert_eq!(connections.load(Ordering::Relaxed), MAX_INBOUND_CONNECTIONS);
+        assert!(is_closed(&mut refused).await);
+
+        // A handshake for no torrent of ours gives the slot back
+        last.write_all(&handshake(Sha1::digest(b"unknown"), PeerId::new()))
+            .await
+            .unwrap();
+        assert!(is_closed(&mut last).await);
+
+        let released = async {
+            while connections.load(Ordering::Relaxed) == MAX_INBOUND_CONNECTIONS {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(5), released).await.unwrap();
+        assert_eq!(
+            connections.load(Ordering::Relaxed),
+            MAX_INBOUND_CONNECTIONS - 1
+        );
+    }
+
+    #[tokio::test]
+    async fn connections_are_routed_by_info_hash() {
+        let listener = Listener::bind(0).await.unwrap();
+        listener.start();
+
+        let (first, second) = (torrent("first"), torrent("second"));
+        let second_id = PeerId::new();
+
+        let mut to_first = listener.register(&first, PeerId::new());
+        let mut to_second = listener.register(&second, second_id);
+
+        let mut stream = TcpStream::connect(("127.0.0.1", listener.port()))
+            .await
+            .unwrap();
+        stream
+            .write_all(&handshake(second.info_hash, PeerId::new()))
+            .await
+            .unwrap();
+
+        // We answer for the torrent asked for
+        let mut answer = [0u8; 68];
+        stream.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer, handshake(second.info_hash, second_id)[..]);
+
+        assert!(timeout(Duration::from_secs(5), to_second.recv())
+            .await
+            .unwrap()
+            .is_some());
+        assert!(to_first.try_recv().is_err());
+    }
+}
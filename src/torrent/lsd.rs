@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::{PeerAddress, Sha1};
+
+// Multicast groups of BEP 14, both on this port
+const LSD_PORT: u16 = 6771;
+const GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+
+/// How often a torrent is announced on the local network
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Local Service Discovery (BEP 14), which finds peers on the local network
+/// by multicasting announces for the torrents we take part in
+#[derive(Clone)]
+pub struct Lsd {
+    service: Arc<Service>,
+}
+
+struct Service {
+    sockets: Vec<(UdpSocket, SocketAddr)>,
+    // Sent along with our announces, to tell them apart from those of others
+    cookie: String,
+    // Where peers found for each torrent we watch go
+    watchers: Mutex<HashMap<Sha1, UnboundedSender<PeerAddress>>>,
+}
+
+/// A `BT-SEARCH` message
+#[derive(Debug, PartialEq)]
+struct Announce {
+    port: u16,
+    info_hashes: Vec<Sha1>,
+    cookie: Option<String>,
+}
+
+impl Lsd {
+    /// Join the IPv4 group, and the IPv6 group if the network has one
+    pub async fn bind() -> io::Result<Self> {
+        let mut sockets = vec![(
+            multicast_socket(IpAddr::V4(GROUP_V4))?,
+            SocketAddr::from((GROUP_V4, LSD_PORT)),
+        )];
+
+        if let Ok(socket) = multicast_socket(IpAddr::V6(GROUP_V6)) {
+            sockets.push((socket, SocketAddr::from((GROUP_V6, LSD_PORT))));
+        }
+
+        let service = Arc::new(Service {
+            sockets,
+            cookie: hex::encode(rand::thread_rng().gen::<[u8; 8]>()),
+            watchers: Mutex::new(HashMap::new()),
+        });
+
+        for index in 0..service.sockets.len() {
+            tokio::spawn(receive(Arc::clone(&service), index));
+        }
+
+        Ok(Self { service })
+    }
+
+    /// Peers announcing `info_hash` from now on, until the receiver is dropped
+    pub fn watch(&self, info_hash: Sha1) -> UnboundedReceiver<PeerAddress> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        self.service
+            .watchers
+            .lock()
+            .unwrap()
+            .insert(info_hash, sender);
+
+        receiver
+    }
+
+    /// Tell the local network we accept connections for `info_hash` on `port`
+    pub async fn announce(&self, info_hash: Sha1, port: u16) {
+        for (socket, group) in &self.service.sockets {
+            let announce = Announce {
+                port,
+                info_hashes: vec![info_hash],
+                cookie: Some(self.service.cookie.clone()),
+            };
+
+            socket.send_to(&announce.encode(group), group).await.ok();
+        }
+    }
+}
+
+impl Service {
+    /// Hand the peer behind an announce to whoever watches its torrents
+    fn deliver(&self, packet: &[u8], from: SocketAddr) {
+        let announce = match Announce::parse(packet) {
+            Some(announce) => announce,
+            None => return,
+        };
+
+        // Multicast comes back to us as well
+        if announce.cookie.as_ref() == Some(&self.cookie) {
+            return;
+        }
+
+        let peer = PeerAddress::from_socket_addr(&SocketAddr::new(from.ip(), announce.port));
+        let mut watchers = self.watchers.lock().unwrap();
+
+        for info_hash in &announce.info_hashes {
+            if let Some(watcher) = watchers.get(info_hash) {
+                if watcher.send(peer.clone()).is_err() {
+                    watchers.remove(info_hash);
+                }
+            }
+        }
+    }
+}
+
+async fn receive(service: Arc<Service>, index: usize) {
+    let mut buffer = [0u8; 1500];
+
+    loop {
+        match service.sockets[index].0.recv_from(&mut buffer).await {
+            Ok((size, from)) => service.deliver(&buffer[..size], from),
+            Err(_) => continue,
+        }
+    }
+}
+
+/// A socket on the LSD port that has joined `group`. Other clients on the
+/// same host share the port, and see our announces as we see theirs.
+fn multicast_socket(group: IpAddr) -> io::Result<UdpSocket> {
+    let domain = match group {
+        IpAddr::V4(_) => Domain::IPV4,
+        IpAddr::V6(_) => Domain::IPV6,
+    };
+
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+
+    match group {
+        IpAddr::V4(group) => {
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_PORT)).into())?;
+            socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+            socket.set_multicast_loop_v4(true)?;
+        }
+
+        IpAddr::V6(group) => {
+            socket.set_only_v6(true)?;
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, LSD_PORT)).into())?;
+            socket.join_multicast_v6(&group, 0)?;
+            socket.set_multicast_loop_v6(true)?;
+        }
+    }
+
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+impl Announce {
+    fn encode(&self, group: &SocketAddr) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            group, self.port
+        );
+
+        for info_hash in &self.info_hashes {
+            message += &format!("Infohash: {}\r\n", hex::encode(info_hash.as_ref()));
+        }
+
+        if let Some(cookie) = &self.cookie {
+            message += &format!("cookie: {}\r\n", cookie);
+        }
+
+        message += "\r\n\r\n";
+        message.into_bytes()
+    }
+
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let message = std::str::from_utf8(packet).ok()?;
+        let mut lines = message.lines();
+
+        if lines.next()?.trim_end() != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+
+        for line in lines {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+                None => continue,
+            };
+
+            match name.as_str() {
+                "port" => port = value.parse().ok().filter(|&port| port != 0),
+                "cookie" => cookie = Some(value.to_string()),
+
+                "infohash" => match hex::decode(value) {
+                    Ok(hash) if hash.len() == 20 => info_hashes.push(Sha1::new_raw(&hash)),
+                    _ => (),
+                },
+
+                _ => (),
+            }
+        }
+
+        Some(Self {
+            port: port?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announces_survive_encoding() {
+        let announce = Announce {
+            port: 6881,
+            info_hashes: vec![Sha1::digest(b"a"), Sha1::digest(b"b")],
+            cookie: Some("cookie".to_string()),
+        };
+
+        let group = SocketAddr::from((GROUP_V6, LSD_PORT));
+        let message = announce.encode(&group);
+
+        assert!(message.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: [ff15::efc0:988f]:6771\r\n"));
+        assert_eq!(Announce::parse(&message), Some(announce));
+    }
+
+    #[test]
+    fn announces_from_other_clients_are_understood() {
+        let message = b"BT-SEARCH * HTTP/1.1\r\n\
+            Host: 239.192.152.143:6771\r\n\
+            PORT: 51413\r\n\
+            infohash: AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\r\n\
+            \r\n\r\n";
+
+        let announce = Announce::parse(message).unwrap();
+
+        assert_eq!(announce.port, 51413);
+        assert_eq!(announce.info_hashes, vec![Sha1::new_raw(&[0xaa; 20])]);
+        assert_eq!(announce.cookie, None);
+
+        assert!(Announce::parse(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n").is_none());
+        assert!(Announce::parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 0\r\n\r\n").is_none());
+    }
+}
@@ -0,0 +1,202 @@
+use std::net::SocketAddr;
+
+use super::Sha1;
+
+/// The parts of a `magnet:` link we make use of
+#[derive(Debug)]
+pub struct Magnet {
+    pub info_hash: Sha1,
+    // Display name, until the metadata tells the real one
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    // Peers to contact directly
+    pub peers: Vec<SocketAddr>,
+    pub web_seeds: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum MagnetError {
+    NotAMagnet,
+    NoInfoHash,
+    InvalidInfoHash,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Self, MagnetError> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .ok_or(MagnetError::NotAMagnet)?;
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        let mut web_seeds = Vec::new();
+
+        for parameter in query.split('&') {
+            let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            let value = percent_decode(value);
+
+            // Numbered keys such as `tr.1` mean the same as the bare key
+            let key = match key.split_once('.') {
+                Some((key, number)) if number.parse::<usize>().is_ok() => key,
+                _ => key,
+            };
+
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        if info_hash.is_none() {
+                            info_hash = Some(parse_info_hash(hash)?);
+                        }
+                    }
+                }
+
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                "ws" => web_seeds.push(value),
+
+                "x.pe" => {
+                    if let Ok(peer) = value.parse() {
+                        peers.push(peer);
+                    }
+                }
+
+                _ => (),
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(MagnetError::NoInfoHash)?,
+            name,
+            trackers,
+            peers,
+            web_seeds,
+        })
+    }
+}
+
+/// Info hashes come as 40 hex digits, or as 32 base32 digits in older links
+fn parse_info_hash(hash: &str) -> Result<Sha1, MagnetError> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).ok(),
+        32 => base32_decode(hash),
+        _ => None,
+    };
+
+    bytes
+        .map(|bytes| Sha1::new_raw(&bytes))
+        .ok_or(MagnetError::InvalidInfoHash)
+}
+
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+
+    for digit in encoded.bytes() {
+        let value = match digit.to_ascii_uppercase() {
+            letter @ b'A'..=b'Z' => letter - b'A',
+            number @ b'2'..=b'7' => number - b'2' + 26,
+            _ => return None,
+        };
+
+        bits = (bits << 5) | value as u32;
+        bit_count += 5;
+
+        if bit_count >= 8 {
+            bit_count -= 8;
+            bytes.push((bits >> bit_count) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+fn percent_decode(encoded: &str) -> String {
+    let encoded = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut position = 0;
+
+    while position < encoded.len() {
+        let escaped = encoded
+            .get(position + 1..position + 3)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+
+        match (encoded[position], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                position += 3;
+            }
+
+            (b'+', _) => {
+                decoded.push(b' ');
+                position += 1;
+            }
+
+            (byte, _) => {
+                decoded.push(byte);
+                position += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX_HASH: &str = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+
+    #[test]
+    fn every_field_is_parsed() {
+        let magnet = Magnet::parse(&format!(
+            "magnet:?xt=urn:btih:{}&dn=sample+file%2Etxt\
+             &tr=http%3A%2F%2Ftracker.example%2Fannounce&tr.1=udp://other.example:80\
+             &x.pe=10.0.0.1:6881&x.pe=[::1]:51413&ws=http://seed.example/sample.txt",
+            HEX_HASH
+        ))
+        .unwrap();
+
+        assert_eq!(magnet.info_hash.as_ref(), hex::decode(HEX_HASH).unwrap());
+        assert_eq!(magnet.name.as_deref(), Some("sample file.txt"));
+        assert_eq!(
+            magnet.trackers,
+            vec!["http://tracker.example/announce", "udp://other.example:80"]
+        );
+        assert_eq!(
+            magnet.peers,
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[::1]:51413".parse().unwrap()
+            ]
+        );
+        assert_eq!(magnet.web_seeds, vec!["http://seed.example/sample.txt"]);
+    }
+
+    #[test]
+    fn base32_info_hashes_match_hex_ones() {
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7").unwrap();
+
+        assert_eq!(magnet.info_hash.as_ref(), hex::decode(HEX_HASH).unwrap());
+    }
+
+    #[test]
+    fn links_need_an_info_hash() {
+        assert!(matches!(
+            Magnet::parse("magnet:?dn=nothing"),
+            Err(MagnetError::NoInfoHash)
+        ));
+        assert!(matches!(
+            Magnet::parse("magnet:?xt=urn:btih:1234"),
+            Err(MagnetError::InvalidInfoHash)
+        ));
+        assert!(matches!(
+            Magnet::parse("http://example.com"),
+            Err(MagnetError::NotAMagnet)
+        ));
+    }
+}
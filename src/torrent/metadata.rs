@@ -0,0 +1,439 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::timeout;
+
+use super::extension::{ExtendedHandshake, Extension, Extensions, Outbox};
+use super::{
+    announce, get_int, Bencoded, Dht, DownloadError, Encryption, Magnet, PeerAddress,
+    PeerConnection, PeerId, PeerList, PeerMessage, Sha1, Torrent,
+};
+
+// Metadata is exchanged in pieces of this size
+const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+// Largest info dictionary we are willing to take
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+// How long a single peer gets to hand over the metadata
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+// ut_metadata message types
+const REQUEST: i64 = 0;
+const DATA: i64 = 1;
+const REJECT: i64 = 2;
+
+// Stands in for the unknown payload size when announcing; anything but zero
+// keeps trackers from taking us for a seed
+const UNKNOWN_BYTES_LEFT: usize = 16 * 1024;
+
+/// Find peers for a magnet link, through its trackers and the DHT if given,
+/// and fetch the info dictionary from them (BEP 9), returning the torrent it
+/// describes along with the peers found. Connections are encrypted as
+/// `encryption` has it.
+pub async fn fetch_metadata(
+    magnet: &Magnet,
+    our_id: PeerId,
+    port: u16,
+    dht: Option<&Dht>,
+    encryption: Encryption,
+) -> Option<(Torrent, PeerList)> {
+    let mut peers: Vec<PeerAddress> = magnet
+        .peers
+        .iter()
+        .map(PeerAddress::from_socket_addr)
+        .collect();
+
+    for tracker in &magnet.trackers {
+        let found = announce(tracker, magnet.info_hash, our_id, port, UNKNOWN_BYTES_LEFT).await;
+
+        for peer in found.map(|list| list.peers).unwrap_or_default() {
+            if !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+    }
+
+    if let Some(dht) = dht {
+        for peer in dht.get_peers(magnet.info_hash).await {
+            if !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+    }
+
+    for peer in &peers {
+        let mut connection = match peer
+            .connect(our_id, magnet.info_hash, None, encryption)
+            .await
+        {
+            Some(connection) => connection,
+            None => continue,
+        };
+
+        let fetched = timeout(
+            METADATA_TIMEOUT,
+            fetch_from(&mut connection, magnet.info_hash),
+        )
+        .await;
+
+        if let Ok(Some(info)) = fetched {
+            let announce = magnet.trackers.first().cloned().unwrap_or_default();
+            let mut torrent = Torrent::from_info(announce, &info, None);
+            torrent.web_seeds = magnet.web_seeds.clone();
+
+            let peer_list = PeerList {
+                our_id,
+                expected_info_hash: magnet.info_hash,
+                interval: 0,
+                peers,
+            };
+
+            return Some((torrent, peer_list));
+        }
+    }
+
+    None
+}
+
+/// Exchanges the info dictionary with a peer (ut_metadata, BEP 9)
+pub struct UtMetadata {
+    state: Arc<Mutex<MetadataState>>,
+}
+
+/// How far along we are with the info dictionary of a connection
+enum MetadataState {
+    // Waiting for the peer to tell its size
+    Unknown,
+    Fetching { data: Vec<u8>, received: Vec<bool> },
+    // Complete, but not necessarily verified
+    Known(Arc<Vec<u8>>),
+    // The peer cannot or will not hand it over
+    Failed,
+}
+
+impl UtMetadata {
+    /// Hand `metadata` to peers that ask for it
+    pub fn serving(metadata: Arc<Vec<u8>>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(MetadataState::Known(metadata))),
+        }
+    }
+
+    /// Ask the peer for the metadata, keeping track of it in `state`
+    fn fetching(state: Arc<Mutex<MetadataState>>) -> Self {
+        Self { state }
+    }
+}
+
+impl Extension for UtMetadata {
+    fn name(&self) -> &'static str {
+        "ut_metadata"
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        if let MetadataState::Known(metadata) = &*self.state.lock().unwrap() {
+            handshake.metadata_size = Some(metadata.len());
+        }
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtendedHandshake, outbox: &mut Outbox) {
+        let mut state = self.state.lock().unwrap();
+
+        if !matches!(*state, MetadataState::Unknown) {
+            return;
+        }
+
+        let size = match handshake.metadata_size {
+            Some(size) if size > 0 && size <= MAX_METADATA_SIZE => size,
+            _ => {
+                *state = MetadataState::Failed;
+                return;
+            }
+        };
+
+        let piece_count = size.div_ceil(METADATA_PIECE_SIZE);
+
+        for piece in 0..piece_count {
+            outbox.push(message(REQUEST, piece, None));
+        }
+
+        *state = MetadataState::Fetching {
+            data: vec![0; size],
+            received: vec![false; piece_count],
+        };
+    }
+
+    fn on_message(&mut self, payload: &[u8], outbox: &mut Outbox) {
+        // Piece data follows right after the bencoded header
+        let (header, data) = match Bencoded::parse_prefix(payload) {
+            Some((header @ Bencoded::Dict(_), data)) => (header, data),
+            _ => return,
+        };
+
+        let piece = match get_int(&header, "piece").and_then(|piece| usize::try_from(piece).ok()) {
+            Some(piece) => piece,
+            None => return,
+        };
+
+        let mut state = self.state.lock().unwrap();
+
+        match (get_int(&header, "msg_type"), &mut *state) {
+            (Some(REQUEST), MetadataState::Known(metadata)) => {
+                if piece < metadata.len().div_ceil(METADATA_PIECE_SIZE) {
+                    let start = piece * METADATA_PIECE_SIZE;
+                    let end = (start + METADATA_PIECE_SIZE).min(metadata.len());
+                    let mut reply = message(DATA, piece, Some(metadata.len()));
+
+                    reply.extend_from_slice(&metadata[start..end]);
+                    outbox.push(reply);
+                } else {
+                    outbox.push(message(REJECT, piece, None));
+                }
+            }
+
+            (Some(REQUEST), _) => outbox.push(message(REJECT, piece, None)),
+
+            (
+                Some(DATA),
+                MetadataState::Fetching {
+                    data: metadata,
+                    received,
+                },
+            ) => {
+                if piece >= received.len() {
+                    *state = MetadataState::Failed;
+                    return;
+                }
+
+                let start = piece * METADATA_PIECE_SIZE;
+                let end = (start + METADATA_PIECE_SIZE).min(metadata.len());
+
+                if data.len() != end - start {
+                    *state = MetadataState::Failed;
+                    return;
+                }
+
+                metadata[start..end].copy_from_slice(data);
+                received[piece] = true;
+
+                if !received.contains(&false) {
+                    *state = MetadataState::Known(Arc::new(std::mem::take(metadata)));
+                }
+            }
+
+            (Some(REJECT), MetadataState::Fetching { .. }) => *state = MetadataState::Failed,
+
+            _ => (),
+        }
+    }
+}
+
+/// Encode the header of a ut_metadata message
+fn message(msg_type: i64, piece: usize, total_size: Option<usize>) -> Vec<u8> {
+    let mut header = vec![
+        ("msg_type", Bencoded::Int(msg_type)),
+        ("piece", Bencoded::Int(piece as i64)),
+    ];
+
+    if let Some(total_size) = total_size {
+        header.push(("total_size", Bencoded::Int(total_size as i64)));
+    }
+
+    (&Bencoded::dict(header)).into()
+}
+
+async fn fetch_from(peer: &mut PeerConnection, info_hash: Sha1) -> Option<Bencoded> {
+    if !peer.supports_extensions() {
+        return None;
+    }
+
+    let state = Arc::new(Mutex::new(MetadataState::Unknown));
+    let handler = UtMetadata::fetching(Arc::clone(&state));
+
+    // Peers with the fast extension expect to hear which pieces we have first
+    if peer.supports_fast() {
+        peer.send(PeerMessage::HaveNone).await;
+    }
+
+    peer.start_extensions(Extensions::new().with(Box::new(handler)))
+        .await;
+
+    let metadata = loop {
+        match &*state.lock().unwrap() {
+            MetadataState::Known(metadata) => break Arc::clone(metadata),
+            MetadataState::Failed => return None,
+            _ => (),
+        }
+
+        // A peer without ut_metadata never answers the handshake of ours
+        if peer.extensions.peer_handshake().is_some()
+            && !peer.extensions.peer_supports("ut_metadata")
+        {
+            return None;
+        }
+
+        match peer.recv().await {
+            Ok(_) | Err(DownloadError::Timeout) => (),
+            Err(_) => return None,
+        }
+    };
+
+    if Sha1::digest(&metadata) != info_hash {
+        return None;
+    }
+
+    Bencoded::parse(&metadata).filter(|info| matches!(info, Bencoded::Dict(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::super::{get_dict, handshake};
+    use super::*;
+
+    async fn read_message(stream: &mut TcpStream) -> PeerMessage {
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length).await.unwrap();
+
+        let mut frame = length.to_vec();
+        frame.resize(4 + u32::from_be_bytes(length) as usize, 0);
+        stream.read_exact(&mut frame[4..]).await.unwrap();
+
+        PeerMessage::parse(&frame).unwrap()
+    }
+
+    /// A peer that has nothing but the metadata to offer
+    async fn serve_metadata(listener: TcpListener, info_hash: Sha1, metadata: Vec<u8>) {
+        // Like any peer that knows nothing of encryption, it hangs up on an
+        // encrypted handshake
+        let mut stream = loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut theirs = [0u8; 68];
+            stream.read_exact(&mut theirs).await.unwrap();
+
+            if theirs.starts_with(b"\x13BitTorrent protocol") {
+                break stream;
+            }
+        };
+
+        stream
+            .write_all(&handshake(info_hash, PeerId::new()))
+            .await
+            .unwrap();
+
+        let handshake = Bencoded::dict(vec![
+            ("m", Bencoded::dict(vec![("ut_metadata", Bencoded::Int(3))])),
+            ("metadata_size", Bencoded::Int(metadata.len() as i64)),
+        ]);
+
+        let message = PeerMessage::Extended {
+            id: 0,
+            payload: (&handshake).into(),
+        };
+        stream.write_all(&Vec::from(message)).await.unwrap();
+
+        // Messages to us go under the id our handshake asks for
+        let our_id = loop {
+            if let PeerMessage::Extended { id: 0, payload } = read_message(&mut stream).await {
+                let handshake = Bencoded::parse(&payload).unwrap();
+                let m = get_dict(&handshake, "m").unwrap();
+
+                break get_int(&m, "ut_metadata").unwrap() as u8;
+            }
+        };
+
+        loop {
+            let payload = match read_message(&mut stream).await {
+                PeerMessage::Extended { id: 3, payload } => payload,
+                _ => continue,
+            };
+
+            let request = Bencoded::parse(&payload).unwrap();
+            let piece = get_int(&request, "piece").unwrap() as usize;
+
+            let start = piece * METADATA_PIECE_SIZE;
+            let end = (start + METADATA_PIECE_SIZE).min(metadata.len());
+
+            let header = Bencoded::dict(vec![
+                ("msg_type", Bencoded::Int(DATA)),
+                ("piece", Bencoded::Int(piece as i64)),
+                ("total_size", Bencoded::Int(metadata.len() as i64)),
+            ]);
+
+            let mut payload: Vec<u8> = (&header).into();
+            payload.extend_from_slice(&metadata[start..end]);
+
+            let message = PeerMessage::Extended {
+                id: our_id,
+                payload,
+            };
+            stream.write_all(&Vec::from(message)).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn metadata_is_assembled_from_pieces() {
+        // Large enough to take two pieces
+        let info = Bencoded::dict(vec![
+            ("name", Bencoded::from("payload")),
+            ("length", Bencoded::Int(20 * 1024 * 1000)),
+            ("piece length", Bencoded::Int(20 * 1024)),
+            ("pieces", Bencoded::Bstr(vec![7; 20 * 1000])),
+        ]);
+
+        let metadata: Vec<u8> = (&info).into();
+        let info_hash = Sha1::digest(&metadata);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(serve_metadata(listener, info_hash, metadata));
+
+        let magnet = Magnet::parse(&format!(
+            "magnet:?xt=urn:btih:{}&x.pe={}",
+            hex::encode(info_hash.as_ref()),
+            address
+        ))
+        .unwrap();
+
+        let (torrent, peer_list) =
+            fetch_metadata(&magnet, PeerId::new(), 0, None, Encryption::default())
+                .await
+                .unwrap();
+
+        assert_eq!(torrent.info_hash, info_hash);
+        assert_eq!(torrent.name(), "payload");
+        assert_eq!(torrent.piece_count(), 1000);
+        assert_eq!(peer_list.peers.len(), 1);
+    }
+
+    #[test]
+    fn pieces_past_the_end_fail_the_fetch() {
+        let huge = i64::MAX as usize;
+
+        let state = Arc::new(Mutex::new(MetadataState::Fetching {
+            data: vec![0; 100],
+            received: vec![false],
+        }));
+        let mut fetching = UtMetadata::fetching(Arc::clone(&state));
+
+        let mut data = message(DATA, huge, Some(100));
+        data.extend_from_slice(&[7; 100]);
+        fetching.on_message(&data, &mut Outbox::new());
+        assert!(matches!(*state.lock().unwrap(), MetadataState::Failed));
+    }
+
+    #[test]
+    fn requests_past_the_end_are_rejected() {
+        let huge = i64::MAX as usize;
+
+        let mut serving = UtMetadata::serving(Arc::new(vec![7; 100]));
+        let mut outbox = Outbox::new();
+        serving.on_message(&message(REQUEST, huge, None), &mut outbox);
+        assert_eq!(outbox, [message(REJECT, huge, None)]);
+    }
+}
@@ -0,0 +1,414 @@
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{PeerStream, Sha1};
+
+mod dh;
+use dh::{KeyPair, KEY_SIZE};
+
+mod rc4;
+pub use rc4::Rc4;
+
+// Sent encrypted by both sides, so that the other can tell it has the keys
+const VERIFICATION: [u8; 8] = [0; 8];
+
+// Most random padding either side may put after its public key
+const MAX_PAD: usize = 512;
+
+// Methods offered in `crypto_provide` and picked in `crypto_select`
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+// Key stream bytes thrown away before use, as RC4 leaks its key early on
+const DISCARD: usize = 1024;
+
+// How a plaintext handshake starts, which no public key is likely to
+const PROTOCOL: &[u8] = b"\x13BitTorrent protocol";
+
+/// Whether connections are encrypted (MSE/PE), which keeps traffic shaping
+/// that looks for BitTorrent from recognising it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    /// Never encrypt, and turn away peers that want to
+    Plaintext,
+    /// Encrypt where the peer can, and fall back to plaintext otherwise
+    #[default]
+    Prefer,
+    /// Only talk to peers that encrypt
+    Require,
+}
+
+impl Encryption {
+    fn provide(self) -> u32 {
+        match self {
+            Self::Plaintext => CRYPTO_PLAINTEXT,
+            Self::Prefer => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            Self::Require => CRYPTO_RC4,
+        }
+    }
+
+    /// The method to use, of those the connecting peer `provided`
+    fn select(self, provided: u32) -> Option<u32> {
+        if provided & CRYPTO_RC4 != 0 && self != Self::Plaintext {
+            Some(CRYPTO_RC4)
+        } else if provided & CRYPTO_PLAINTEXT != 0 && self != Self::Require {
+            Some(CRYPTO_PLAINTEXT)
+        } else {
+            None
+        }
+    }
+}
+
+/// Do the encryption handshake on a connection we made to a peer of the
+/// torrent with `info_hash`, leaving `stream` ready for the BitTorrent one
+pub async fn initiate(
+    stream: &mut PeerStream,
+    info_hash: Sha1,
+    encryption: Encryption,
+) -> Option<()> {
+    let keys = KeyPair::new();
+
+    stream
+        .write_all(&[&keys.public_key()[..], &pad()].concat())
+        .await
+        .ok()?;
+
+    let mut received = Vec::new();
+    fill(stream, &mut received, KEY_SIZE).await?;
+
+    let secret = keys.shared_secret(&received[..KEY_SIZE])?;
+    let mut incoming = keystream(b"keyB", &secret, info_hash);
+    let mut outgoing = keystream(b"keyA", &secret, info_hash);
+
+    // No padding and no initial payload; the BitTorrent handshake follows
+    // once this one is done
+    let mut negotiation = [
+        &VERIFICATION[..],
+        &encryption.provide().to_be_bytes(),
+        &0u16.to_be_bytes(),
+        &0u16.to_be_bytes(),
+    ]
+    .concat();
+    outgoing.apply(&mut negotiation);
+
+    let message = [
+        hash(b"req1", &secret).as_ref(),
+        &xor(hash(b"req2", info_hash.as_ref()), hash(b"req3", &secret)),
+        &negotiation,
+    ]
+    .concat();
+    stream.write_all(&message).await.ok()?;
+
+    // The peer's verification constant marks the end of its padding
+    let mut verification = VERIFICATION;
+    incoming.apply(&mut verification);
+
+    let start = find(stream, &mut received, KEY_SIZE, &verification).await?;
+    fill(stream, &mut received, start + 6).await?;
+
+    let mut selection = received[start..start + 6].to_vec();
+    incoming.apply(&mut selection);
+
+    let select = u32::from_be_bytes([selection[0], selection[1], selection[2], selection[3]]);
+    let pad_length = u16::from_be_bytes([selection[4], selection[5]]) as usize;
+
+    if select & encryption.provide() == 0 || select.count_ones() != 1 || pad_length > MAX_PAD {
+        return None;
+    }
+
+    let end = start + 6 + pad_length;
+    fill(stream, &mut received, end).await?;
+    incoming.skip(pad_length);
+
+    let rest = received.split_off(end);
+    finish(stream, select, incoming, outgoing, Vec::new(), rest);
+
+    Some(())
+}
+
+/// Do the encryption handshake, if there is one, on a connection a peer
+/// made to us for one of the torrents with `info_hashes`. Peers that start
+/// right away with the BitTorrent handshake are let through unless
+/// encryption is required.
+pub async fn respond(
+    stream: &mut PeerStream,
+    info_hashes: &[Sha1],
+    encryption: Encryption,
+) -> Option<()> {
+    let mut received = Vec::new();
+    fill(stream, &mut received, PROTOCOL.len()).await?;
+
+    if received.starts_with(PROTOCOL) {
+        if encryption == Encryption::Require {
+            return None;
+        }
+
+        stream.unread(received);
+        return Some(());
+    }
+
+    if encryption == Encryption::Plaintext {
+        return None;
+    }
+
+    fill(stream, &mut received, KEY_SIZE).await?;
+
+    let keys = KeyPair::new();
+    let secret = keys.shared_secret(&received[..KEY_SIZE])?;
+
+    stream
+        .write_all(&[&keys.public_key()[..], &pad()].concat())
+        .await
+        .ok()?;
+
+    // A hash of the secret marks the end of the peer's padding
+    let start = find(
+        stream,
+        &mut received,
+        KEY_SIZE,
+        hash(b"req1", &secret).as_ref(),
+    )
+    .await?;
+    fill(stream, &mut received, start + 34).await?;
+
+    // The torrent is only given away to those who know its info hash
+    let mut obfuscated = [0u8; 20];
+    obfuscated.copy_from_slice(&received[start..start + 20]);
+    let wanted = xor(Sha1::new_raw(&obfuscated), hash(b"req3", &secret));
+
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| hash(b"req2", info_hash.as_ref()).as_ref() == wanted)?;
+
+    let mut incoming = keystream(b"keyA", &secret, info_hash);
+    let mut outgoing = keystream(b"keyB", &secret, info_hash);
+
+    let mut negotiation = received[start + 20..start + 34].to_vec();
+    incoming.apply(&mut negotiation);
+
+    let provided = u32::from_be_bytes([
+        negotiation[8],
+        negotiation[9],
+        negotiation[10],
+        negotiation[11],
+    ]);
+    let pad_length = u16::from_be_bytes([negotiation[12], negotiation[13]]) as usize;
+
+    if negotiation[..8] != VERIFICATION || pad_length > MAX_PAD {
+        return None;
+    }
+
+    let pad_end = start + 34 + pad_length;
+    fill(stream, &mut received, pad_end + 2).await?;
+    incoming.skip(pad_length);
+
+    let mut initial_length = received[pad_end..pad_end + 2].to_vec();
+    incoming.apply(&mut initial_length);
+    let initial_length = u16::from_be_bytes([initial_length[0], initial_length[1]]) as usize;
+
+    // The peer may have started its BitTorrent handshake already, and that
+    // is always encrypted
+    let end = pad_end + 2 + initial_length;
+    fill(stream, &mut received, end).await?;
+
+    let mut initial = received[pad_end + 2..end].to_vec();
+    incoming.apply(&mut initial);
+
+    let select = encryption.select(provided)?;
+
+    let mut selection = [
+        &VERIFICATION[..],
+        &select.to_be_bytes(),
+        &0u16.to_be_bytes(),
+    ]
+    .concat();
+    outgoing.apply(&mut selection);
+    stream.write_all(&selection).await.ok()?;
+
+    let rest = received.split_off(end);
+    finish(stream, select, incoming, outgoing, initial, rest);
+
+    Some(())
+}
+
+/// Leave `stream` encrypted or not, as agreed, with what was read past the
+/// handshake put back
+fn finish(
+    stream: &mut PeerStream,
+    select: u32,
+    mut incoming: Rc4,
+    outgoing: Rc4,
+    initial: Vec<u8>,
+    mut rest: Vec<u8>,
+) {
+    if select == CRYPTO_RC4 {
+        incoming.apply(&mut rest);
+        stream.encrypt(incoming, outgoing);
+    }
+
+    stream.unread([initial, rest].concat());
+}
+
+/// Read until `received` holds at least `size` bytes
+async fn fill(stream: &mut PeerStream, received: &mut Vec<u8>, size: usize) -> Option<()> {
+    let mut buffer = [0u8; 1024];
+
+    while received.len() < size {
+        let read = stream.read(&mut buffer).await.ok()?;
+
+        if read == 0 {
+            return None;
+        }
+
+        received.extend_from_slice(&buffer[..read]);
+    }
+
+    Some(())
+}
+
+/// Read until `pattern` shows up in `received` after `from`, within the
+/// most padding there may be, returning where what follows it starts
+async fn find(
+    stream: &mut PeerStream,
+    received: &mut Vec<u8>,
+    from: usize,
+    pattern: &[u8],
+) -> Option<usize> {
+    let limit = from + MAX_PAD + pattern.len();
+
+    loop {
+        let found = received[from..]
+            .windows(pattern.len())
+            .position(|window| window == pattern);
+
+        if let Some(position) = found {
+            return Some(from + position + pattern.len());
+        }
+
+        if received.len() >= limit {
+            return None;
+        }
+
+        fill(stream, received, received.len() + 1).await?;
+    }
+}
+
+fn keystream(name: &[u8], secret: &[u8], info_hash: Sha1) -> Rc4 {
+    let mut rc4 = Rc4::new(Sha1::digest(&[name, secret, info_hash.as_ref()].concat()).as_ref());
+    rc4.skip(DISCARD);
+    rc4
+}
+
+fn hash(name: &[u8], value: &[u8]) -> Sha1 {
+    Sha1::digest(&[name, value].concat())
+}
+
+fn xor(a: Sha1, b: Sha1) -> [u8; 20] {
+    let mut result = [0u8; 20];
+
+    for (byte, (a, b)) in result.iter_mut().zip(a.as_ref().iter().zip(b.as_ref())) {
+        *byte = a ^ b;
+    }
+
+    result
+}
+
+/// Random bytes of random length, so that the handshake has no fixed size
+fn pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let length = rng.gen_range(0..=MAX_PAD);
+
+    (0..length).map(|_| rng.gen()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn connected() -> (PeerStream, PeerStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ours = TcpStream::connect(listener.local_addr().unwrap());
+        let (ours, theirs) = tokio::join!(ours, listener.accept());
+
+        (ours.unwrap().into(), theirs.unwrap().0.into())
+    }
+
+    #[tokio::test]
+    async fn encrypted_streams_carry_data_both_ways() {
+        let info_hash = Sha1::digest(b"torrent");
+        let others = [Sha1::digest(b"other"), info_hash];
+
+        for (ours, theirs) in [
+            (Encryption::Require, Encryption::Prefer),
+            (Encryption::Prefer, Encryption::Require),
+            (Encryption::Prefer, Encryption::Prefer),
+        ] {
+            let (mut initiator, mut responder) = connected().await;
+
+            let (initiated, responded) = tokio::join!(
+                initiate(&mut initiator, info_hash, ours),
+                respond(&mut responder, &others, theirs),
+            );
+            assert_eq!((initiated, responded), (Some(()), Some(())));
+            assert!(initiator.is_encrypted() && responder.is_encrypted());
+
+            let message = vec![7u8; 100_000];
+            let mut received = vec![0u8; message.len()];
+
+            let (written, read) = tokio::join!(
+                initiator.write_all(&message),
+                responder.read_exact(&mut received),
+            );
+            written.unwrap();
+            read.unwrap();
+            assert_eq!(received, message);
+
+            responder.write_all(b"reply").await.unwrap();
+            let mut reply = [0u8; 5];
+            initiator.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply, b"reply");
+        }
+    }
+
+    #[tokio::test]
+    async fn policies_are_enforced() {
+        let info_hash = Sha1::digest(b"torrent");
+
+        // A plaintext handshake is let through for the BitTorrent one to read
+        let (mut initiator, mut responder) = connected().await;
+        initiator
+            .write_all(&[PROTOCOL, &[0; 48]].concat())
+            .await
+            .unwrap();
+        respond(&mut responder, &[info_hash], Encryption::Prefer)
+            .await
+            .unwrap();
+
+        let mut handshake = [0u8; 68];
+        responder.read_exact(&mut handshake).await.unwrap();
+        assert!(handshake.starts_with(PROTOCOL));
+
+        let (mut initiator, mut responder) = connected().await;
+        initiator
+            .write_all(&[PROTOCOL, &[0; 48]].concat())
+            .await
+            .unwrap();
+        assert_eq!(
+            respond(&mut responder, &[info_hash], Encryption::Require).await,
+            None
+        );
+
+        // Nor do peers get anywhere without the info hash
+        let (mut initiator, mut responder) = connected().await;
+        let (_, responded) = tokio::join!(
+            initiate(&mut initiator, Sha1::digest(b"unknown"), Encryption::Prefer),
+            async {
+                let responded = respond(&mut responder, &[info_hash], Encryption::Prefer).await;
+                drop(responder);
+                responded
+            },
+        );
+        assert_eq!(responded, None);
+    }
+}
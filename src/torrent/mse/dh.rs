@@ -0,0 +1,183 @@
+use rand::RngCore;
+
+// Bytes in a public key or shared secret
+pub const KEY_SIZE: usize = 96;
+
+// The 768 bit prime MSE does its Diffie-Hellman exchange in, with generator 2
+const PRIME: &str = "ffffffffffffffffc90fdaa22168c234c4c6628b80dc1cd129024e088a67cc74\
+                     020bbea63b139b22514a08798e3404ddef9519b3cd3a431b302b0a6df25f1437\
+                     4fe1356d6d51c245e485b576625e7ec6f44c42e9a63a36210000000000090563";
+
+const LIMBS: usize = KEY_SIZE / 8;
+
+// Bits of the private key; more adds nothing to the strength of the prime
+const PRIVATE_BITS: usize = 160;
+
+/// An integer below the prime, least significant limb first
+type Number = [u64; LIMBS];
+
+/// One side of a Diffie-Hellman key exchange
+pub struct KeyPair {
+    private: Number,
+    public: Number,
+}
+
+impl KeyPair {
+    pub fn new() -> Self {
+        let mut private = [0u64; LIMBS];
+        let mut rng = rand::thread_rng();
+
+        for limb in &mut private[..PRIVATE_BITS / 64] {
+            *limb = rng.next_u64();
+        }
+
+        private[PRIVATE_BITS / 64] = rng.next_u64() >> (64 - PRIVATE_BITS % 64);
+
+        let mut generator = [0u64; LIMBS];
+        generator[0] = 2;
+
+        Self {
+            private,
+            public: pow_mod(&generator, &private),
+        }
+    }
+
+    /// What we send the other side
+    pub fn public_key(&self) -> [u8; KEY_SIZE] {
+        to_bytes(&self.public)
+    }
+
+    /// The secret both sides end up with, from the other side's public key.
+    /// Keys that are not below the prime are refused.
+    pub fn shared_secret(&self, public_key: &[u8]) -> Option<[u8; KEY_SIZE]> {
+        let public = from_bytes(public_key);
+
+        if !below(&public, &prime()) || public.iter().all(|&limb| limb == 0) {
+            return None;
+        }
+
+        Some(to_bytes(&pow_mod(&public, &self.private)))
+    }
+}
+
+fn prime() -> Number {
+    from_bytes(&hex::decode(PRIME).expect("Malformed prime"))
+}
+
+fn from_bytes(bytes: &[u8]) -> Number {
+    let mut number = [0u64; LIMBS];
+
+    for (limb, chunk) in number.iter_mut().zip(bytes.rchunks(8)) {
+        let mut padded = [0u8; 8];
+        padded[8 - chunk.len()..].copy_from_slice(chunk);
+        *limb = u64::from_be_bytes(padded);
+    }
+
+    number
+}
+
+fn to_bytes(number: &Number) -> [u8; KEY_SIZE] {
+    let mut bytes = [0u8; KEY_SIZE];
+
+    for (chunk, limb) in bytes.rchunks_mut(8).zip(number) {
+        chunk.copy_from_slice(&limb.to_be_bytes());
+    }
+
+    bytes
+}
+
+fn below(a: &Number, b: &Number) -> bool {
+    a.iter().rev().lt(b.iter().rev())
+}
+
+/// `a + b` modulo the prime, for `a` and `b` below it
+fn add_mod(a: &Number, b: &Number, prime: &Number) -> Number {
+    let mut sum = [0u64; LIMBS];
+    let mut carry = false;
+
+    for i in 0..LIMBS {
+        let (partial, first) = a[i].overflowing_add(b[i]);
+        let (partial, second) = partial.overflowing_add(carry as u64);
+        sum[i] = partial;
+        carry = first || second;
+    }
+
+    // The true sum is below twice the prime, so one subtraction is enough,
+    // and one that wraps around makes up for the lost carry
+    if carry || !below(&sum, prime) {
+        let mut borrow = false;
+
+        for i in 0..LIMBS {
+            let (partial, first) = sum[i].overflowing_sub(prime[i]);
+            let (partial, second) = partial.overflowing_sub(borrow as u64);
+            sum[i] = partial;
+            borrow = first || second;
+        }
+    }
+
+    sum
+}
+
+/// `a * b` modulo the prime, by doubling and adding
+fn mul_mod(a: &Number, b: &Number, prime: &Number) -> Number {
+    let mut product = [0u64; LIMBS];
+
+    for bit in (0..LIMBS * 64).rev() {
+        product = add_mod(&product, &product, prime);
+
+        if b[bit / 64] >> (bit % 64) & 1 == 1 {
+            product = add_mod(&product, a, prime);
+        }
+    }
+
+    product
+}
+
+fn pow_mod(base: &Number, exponent: &Number) -> Number {
+    let prime = prime();
+    let mut result = [0u64; LIMBS];
+    result[0] = 1;
+
+    // Leading zero bits would only square the one
+    let bits = exponent
+        .iter()
+        .rposition(|&limb| limb != 0)
+        .map_or(0, |limb| {
+            limb * 64 + 64 - exponent[limb].leading_zeros() as usize
+        });
+
+    for bit in (0..bits).rev() {
+        result = mul_mod(&result, &result, &prime);
+
+        if exponent[bit / 64] >> (bit % 64) & 1 == 1 {
+            result = mul_mod(&result, base, &prime);
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_sides_agree_on_the_secret() {
+        let ours = KeyPair::new();
+        let theirs = KeyPair::new();
+
+        let secret = ours.shared_secret(&theirs.public_key()).unwrap();
+        assert_eq!(theirs.shared_secret(&ours.public_key()), Some(secret));
+        assert_ne!(ours.public_key(), theirs.public_key());
+
+        let mut three = [0u64; LIMBS];
+        three[0] = 3;
+        let mut five = [0u64; LIMBS];
+        five[0] = 5;
+        assert_eq!(pow_mod(&three, &five)[0], 243);
+
+        // Neither zero nor the prime itself make for a key
+        assert_eq!(ours.shared_secret(&[0; KEY_SIZE]), None);
+        assert_eq!(ours.shared_secret(&to_bytes(&prime())), None);
+    }
+}
@@ -0,0 +1,77 @@
+/// The RC4 stream cipher, which is all the obfuscation MSE offers
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        let mut j = 0u8;
+
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// Encrypt or decrypt `data` in place
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            *byte ^= self.next();
+        }
+    }
+
+    /// Move on `count` bytes into the key stream
+    pub fn skip(&mut self, count: usize) {
+        for _ in 0..count {
+            self.next();
+        }
+    }
+
+    fn next(&mut self) -> u8 {
+        self.i = self.i.wrapping_add(1);
+        self.j = self.j.wrapping_add(self.state[self.i as usize]);
+        self.state.swap(self.i as usize, self.j as usize);
+
+        let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+        self.state[index as usize]
+    }
+}
+
+impl std::fmt::Debug for Rc4 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rc4")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_known_key_streams() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+
+        let mut data = *b"Attack at dawn";
+        Rc4::new(b"Secret").apply(&mut data);
+        assert_eq!(hex::encode(data), "45a01f645fc35b383552544b9bf5");
+
+        let mut skipped = Rc4::new(b"Key");
+        skipped.skip(5);
+        let mut data = *b"text";
+        skipped.apply(&mut data);
+        assert_eq!(hex::encode(data), "40af0ad3");
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::extension::{Extension, Outbox};
+use super::{get_bstr, Bencoded, PeerAddress};
+
+// How often a peer is told about changes to our peers; BEP 11 asks for no
+// more than once a minute
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+// Messages arriving quicker than this after the last one are ignored
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// Most peers added or dropped in a single message, either way
+const MAX_PEX_PEERS: usize = 50;
+
+// Most peers we hold on to that have yet to be picked up by the download
+const MAX_LEARNED_PEERS: usize = 500;
+
+// Flags describing an added peer
+pub const ENCRYPTION: u8 = 0x01;
+pub const SEED: u8 = 0x02;
+pub const UTP: u8 = 0x04;
+pub const REACHABLE: u8 = 0x10;
+
+/// The peers of a download as far as peer exchange is concerned, shared by
+/// all of its connections
+#[derive(Debug, Default)]
+pub struct PeerExchange {
+    // Peers we are connected to, with their flags
+    connected: Mutex<HashMap<PeerAddress, u8>>,
+    // Peers other peers have told us about
+    learned: Mutex<Vec<PeerAddress>>,
+}
+
+impl PeerExchange {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connected(&self, peer: PeerAddress, flags: u8) {
+        self.connected.lock().unwrap().insert(peer, flags);
+    }
+
+    pub fn disconnected(&self, peer: &PeerAddress) {
+        self.connected.lock().unwrap().remove(peer);
+    }
+
+    /// Peers learned since last time
+    pub fn take_learned(&self) -> Vec<PeerAddress> {
+        std::mem::take(&mut *self.learned.lock().unwrap())
+    }
+
+    fn learn(&self, peers: impl Iterator<Item = PeerAddress>) {
+        let connected = self.connected.lock().unwrap();
+        let mut learned = self.learned.lock().unwrap();
+
+        for peer in peers {
+            if learned.len() >= MAX_LEARNED_PEERS {
+                break;
+            }
+
+            if !connected.contains_key(&peer) && !learned.contains(&peer) {
+                learned.push(peer);
+            }
+        }
+    }
+}
+
+/// Tells a peer about the other peers we are connected to, and learns about
+/// the peers it is connected to (ut_pex, BEP 11)
+pub struct UtPex {
+    exchange: Arc<PeerExchange>,
+    // IP of the peer itself, which it needs not be told about
+    peer_ip: Option<IpAddr>,
+    // Peers the peer has been told about
+    sent: HashSet<PeerAddress>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl UtPex {
+    pub fn new(exchange: Arc<PeerExchange>, peer_ip: Option<IpAddr>) -> Self {
+        Self {
+            exchange,
+            peer_ip,
+            sent: HashSet::new(),
+            last_sent: None,
+            last_received: None,
+        }
+    }
+}
+
+impl Extension for UtPex {
+    fn name(&self) -> &'static str {
+        "ut_pex"
+    }
+
+    fn on_message(&mut self, payload: &[u8], _outbox: &mut Outbox) {
+        if self
+            .last_received
+            .is_some_and(|last| last.elapsed() < MIN_RECEIVE_INTERVAL)
+        {
+            return;
+        }
+
+        let message = match Bencoded::parse(payload) {
+            Some(message @ Bencoded::Dict(_)) => message,
+            _ => return,
+        };
+
+        self.last_received = Some(Instant::now());
+
+        let added = get_bstr(&message, "added").unwrap_or_default();
+        let added6 = get_bstr(&message, "added6").unwrap_or_default();
+
+        let peers = added
+            .chunks_exact(6)
+            .chain(added6.chunks_exact(18))
+            .map(PeerAddress::new)
+            .filter(|peer| peer.to_socket_addr().port() != 0)
+            .take(MAX_PEX_PEERS);
+
+        self.exchange.learn(peers);
+    }
+
+    fn on_tick(&mut self, outbox: &mut Outbox) {
+        if self
+            .last_sent
+            .is_some_and(|last| last.elapsed() < PEX_INTERVAL)
+        {
+            return;
+        }
+
+        let connected: HashMap<PeerAddress, u8> = self
+            .exchange
+            .connected
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(peer, _)| Some(peer.to_socket_addr().ip()) != self.peer_ip)
+            .map(|(peer, flags)| (peer.clone(), *flags))
+            .collect();
+
+        let added: Vec<(&PeerAddress, u8)> = connected
+            .iter()
+            .filter(|(peer, _)| !self.sent.contains(peer))
+            .map(|(peer, flags)| (peer, *flags))
+            .take(MAX_PEX_PEERS)
+            .collect();
+
+        let dropped: Vec<PeerAddress> = self
+            .sent
+            .iter()
+            .filter(|peer| !connected.contains_key(peer))
+            .take(MAX_PEX_PEERS)
+            .cloned()
+            .collect();
+
+        self.last_sent = Some(Instant::now());
+
+        if added.is_empty() && dropped.is_empty() {
+            return;
+        }
+
+        let (added6, added): (Vec<_>, Vec<_>) =
+            added.into_iter().partition(|(peer, _)| peer.is_ipv6());
+        let (dropped6, dropped): (Vec<_>, Vec<_>) = dropped.iter().partition(|peer| peer.is_ipv6());
+
+        let message = Bencoded::dict(vec![
+            ("added", compact(added.iter().map(|(peer, _)| *peer))),
+            ("added.f", flags(&added)),
+            ("added6", compact(added6.iter().map(|(peer, _)| *peer))),
+            ("added6.f", flags(&added6)),
+            ("dropped", compact(dropped.iter().copied())),
+            ("dropped6", compact(dropped6.iter().copied())),
+        ]);
+
+        outbox.push((&message).into());
+
+        for (peer, _) in added.iter().chain(&added6) {
+            self.sent.insert((*peer).clone());
+        }
+
+        for peer in dropped.iter().chain(&dropped6) {
+            self.sent.remove(*peer);
+        }
+    }
+}
+
+/// Addresses back to back in compact form
+fn compact<'a>(peers: impl Iterator<Item = &'a PeerAddress>) -> Bencoded {
+    Bencoded::Bstr(peers.flat_map(PeerAddress::to_compact).collect())
+}
+
+/// The flags of added peers, one byte each
+fn flags(added: &[(&PeerAddress, u8)]) -> Bencoded {
+    Bencoded::Bstr(added.iter().map(|(_, flags)| *flags).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    fn peer(address: &str) -> PeerAddress {
+        PeerAddress::from_socket_addr(&address.parse::<SocketAddr>().unwrap())
+    }
+
+    #[test]
+    fn changes_to_connected_peers_are_sent() {
+        let exchange = Arc::new(PeerExchange::new());
+
+        exchange.connected(peer("10.0.0.1:6881"), SEED | REACHABLE);
+        exchange.connected(peer("[2001:db8::1]:51413"), REACHABLE);
+        exchange.connected(peer("10.0.0.9:6881"), 0);
+
+        // The peer the messages go to
+        let mut pex = UtPex::new(Arc::clone(&exchange), "10.0.0.9".parse().ok());
+
+        let mut outbox = Outbox::new();
+        pex.on_tick(&mut outbox);
+
+        let message = Bencoded::parse(&outbox[0]).unwrap();
+
+        assert_eq!(
+            get_bstr(&message, "added").unwrap(),
+            [10, 0, 0, 1, 0x1a, 0xe1]
+        );
+        assert_eq!(get_bstr(&message, "added.f").unwrap(), [SEED | REACHABLE]);
+        assert_eq!(get_bstr(&message, "added6").unwrap().len(), 18);
+        assert_eq!(get_bstr(&message, "added6.f").unwrap(), [REACHABLE]);
+
+        // Nothing more is sent within the interval
+        exchange.disconnected(&peer("10.0.0.1:6881"));
+        pex.on_tick(&mut outbox);
+        assert_eq!(outbox.len(), 1);
+
+        pex.last_sent = Some(Instant::now() - PEX_INTERVAL);
+        pex.on_tick(&mut outbox);
+
+        let message = Bencoded::parse(&outbox[1]).unwrap();
+
+        assert_eq!(get_bstr(&message, "added").unwrap(), []);
+        assert_eq!(
+            get_bstr(&message, "dropped").unwrap(),
+            [10, 0, 0, 1, 0x1a, 0xe1]
+        );
+    }
+
+    #[test]
+    fn received_peers_are_learned_at_a_limited_rate() {
+        let exchange = Arc::new(PeerExchange::new());
+        exchange.connected(peer("10.0.0.1:6881"), 0);
+
+        let mut pex = UtPex::new(Arc::clone(&exchange), None);
+
+        let message = |added: Vec<u8>| -> Vec<u8> {
+            (&Bencoded::dict(vec![("added", Bencoded::Bstr(added))])).into()
+        };
+
+        let mut added = vec![10, 0, 0, 1, 0x1a, 0xe1];
+        added.extend((0..100u8).flat_map(|host| [10, 0, 1, host, 0x1a, 0xe1]));
+
+        pex.on_message(&message(added), &mut Outbox::new());
+
+        let learned = exchange.take_learned();
+
+        // Peers we are connected to are left out, and there is a cap
+        assert_eq!(learned.len(), MAX_PEX_PEERS - 1);
+        assert!(!learned.contains(&peer("10.0.0.1:6881")));
+
+        pex.on_message(&message(vec![10, 0, 2, 1, 0x1a, 0xe1]), &mut Outbox::new());
+
+        assert!(exchange.take_learned().is_empty());
+    }
+}
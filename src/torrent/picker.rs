@@ -0,0 +1,444 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+use super::pipeline::PartialPiece;
+use super::storage::Layout;
+use super::{Priority, Torrent};
+
+// Number of pieces picked at random before switching to rarest first, so that
+// we quickly have something to offer other peers
+const RANDOM_FIRST_PIECES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceState {
+    Missing,
+    InProgress,
+    Have,
+}
+
+/// The pieces a peer has told us about, as far as the picker knows
+pub struct Availability(Vec<bool>);
+
+impl Availability {
+    pub fn new(piece_count: usize) -> Self {
+        Self(vec![false; piece_count])
+    }
+
+    /// Let the picker know about pieces the peer has announced since last time
+    pub fn update<F: Fn(usize) -> bool>(&mut self, peer_has: F, picker: &mut PiecePicker) {
+        for (piece, reported) in self.0.iter_mut().enumerate() {
+            if !*reported && peer_has(piece) {
+                picker.peer_has(piece);
+                *reported = true;
+            }
+        }
+    }
+
+    pub fn withdraw(&self, picker: &mut PiecePicker) {
+        for (piece, &reported) in self.0.iter().enumerate() {
+            if reported {
+                picker.peer_lost(piece);
+            }
+        }
+    }
+}
+
+/// Decides which piece to download next from a given peer
+#[derive(Debug)]
+pub struct PiecePicker {
+    // Number of connected peers known to have each piece
+    availability: Vec<usize>,
+    state: Vec<PieceState>,
+    // Pieces to skip are never picked, and the rest highest priority first
+    priority: Vec<Priority>,
+    // Number of peers currently downloading each piece; more than one only
+    // happens in endgame
+    downloaders: Vec<usize>,
+    // Pieces that were abandoned after some blocks had been received
+    partial: HashMap<usize, PartialPiece>,
+    random_first: usize,
+    rng: StdRng,
+    piece_length: usize,
+    last_piece_length: usize,
+}
+
+impl PiecePicker {
+    pub fn new(torrent: &Torrent) -> Self {
+        let piece_count = torrent.piece_count();
+
+        Self {
+            availability: vec![0; piece_count],
+            state: vec![PieceState::Missing; piece_count],
+            priority: Layout::new(torrent).piece_priorities(),
+            downloaders: vec![0; piece_count],
+            partial: HashMap::new(),
+            random_first: RANDOM_FIRST_PIECES,
+            rng: StdRng::from_entropy(),
+            piece_length: torrent.piece_length,
+            last_piece_length: torrent.piece_size(piece_count.saturating_sub(1)),
+        }
+    }
+
+    pub fn peer_has(&mut self, piece: usize) {
+        if let Some(count) = self.availability.get_mut(piece) {
+            *count += 1;
+        }
+    }
+
+    pub fn peer_lost(&mut self, piece: usize) {
+        if let Some(count) = self.availability.get_mut(piece) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// Pick a piece the peer has and we are not already downloading, along
+    /// with whatever blocks of it we already have
+    pub fn pick<F: Fn(usize) -> bool>(&mut self, peer_has: F) -> Option<PartialPiece> {
+        let wanted = |picker: &Self, piece: usize| {
+            picker.state[piece] == PieceState::Missing && picker.is_wanted(piece) && peer_has(piece)
+        };
+
+        // Finish what was started before spreading out further
+        let partial = self
+            .partial
+            .keys()
+            .copied()
+            .filter(|&piece| wanted(self, piece))
+            .min_by_key(|&piece| (Reverse(self.priority[piece]), self.availability[piece]));
+
+        if let Some(piece) = partial {
+            self.state[piece] = PieceState::InProgress;
+            self.downloaders[piece] = 1;
+            return self.partial.remove(&piece);
+        }
+
+        let mut candidates: Vec<usize> = (0..self.state.len())
+            .filter(|&piece| wanted(self, piece))
+            .collect();
+
+        // However rare the others, the most wanted pieces go first
+        let highest = candidates.iter().map(|&piece| self.priority[piece]).max()?;
+        candidates.retain(|&piece| self.priority[piece] == highest);
+
+        let piece = if self.random_first > 0 {
+            *candidates.choose(&mut self.rng)?
+        } else {
+            let rarest = candidates
+                .iter()
+                .map(|&piece| self.availability[piece])
+                .min()?;
+
+            let rarest: Vec<usize> = candidates
+                .into_iter()
+                .filter(|&piece| self.availability[piece] == rarest)
+                .collect();
+
+            *rarest.choose(&mut self.rng)?
+        };
+
+        self.state[piece] = PieceState::InProgress;
+        self.downloaders[piece] = 1;
+        Some(PartialPiece::new(piece, self.piece_size(piece)))
+    }
+
+    /// Whether the peer has any piece we still lack
+    pub fn is_interesting<F: Fn(usize) -> bool>(&self, peer_has: F) -> bool {
+        (0..self.state.len()).any(|piece| {
+            self.state[piece] != PieceState::Have && self.is_wanted(piece) && peer_has(piece)
+        })
+    }
+
+    /// Whether every piece we lack is already being downloaded
+    pub fn in_endgame(&self) -> bool {
+        self.partial.is_empty()
+            && (0..self.state.len())
+                .all(|piece| self.state[piece] != PieceState::Missing || !self.is_wanted(piece))
+    }
+
+    /// Pick a piece the peer has that is already being downloaded from
+    /// others, preferring the pieces with the fewest peers working on them
+    pub fn pick_endgame<F: Fn(usize) -> bool>(&mut self, peer_has: F) -> Option<PartialPiece> {
+        let piece = (0..self.state.len())
+            .filter(|&piece| self.state[piece] == PieceState::InProgress && peer_has(piece))
+            .min_by_key(|&piece| self.downloaders[piece])?;
+
+        self.downloaders[piece] += 1;
+        Some(PartialPiece::new(piece, self.piece_size(piece)))
+    }
+
+    /// Give a piece back, keeping any blocks already received for later
+    pub fn abandon(&mut self, progress: PartialPiece) {
+        let piece = progress.index();
+
+        if self.state[piece] == PieceState::Have {
+            return;
+        }
+
+        self.downloaders[piece] = self.downloaders[piece].saturating_sub(1);
+
+        // Others are still at it
+        if self.downloaders[piece] > 0 {
+            return;
+        }
+
+        self.state[piece] = PieceState::Missing;
+
+        if !progress.is_empty() {
+            self.partial.insert(piece, progress);
+        }
+    }
+
+    /// Whether the piece is to be downloaded at all
+    fn is_wanted(&self, piece: usize) -> bool {
+        self.priority[piece] != Priority::Skip
+    }
+
+    /// Pieces that were abandoned with some blocks received
+    pub fn partial_pieces(&self) -> impl Iterator<Item = &PartialPiece> {
+        self.partial.values()
+    }
+
+    pub fn has(&self, piece: usize) -> bool {
+        self.state[piece] == PieceState::Have
+    }
+
+    /// Whether we have every piece we want
+    pub fn is_complete(&self) -> bool {
+        (0..self.state.len())
+            .all(|piece| self.state[piece] == PieceState::Have || !self.is_wanted(piece))
+    }
+
+    pub fn complete(&mut self, piece: usize) {
+        self.state[piece] = PieceState::Have;
+        self.downloaders[piece] = 0;
+        self.partial.remove(&piece);
+        self.random_first = self.random_first.saturating_sub(1);
+    }
+
+    fn piece_size(&self, piece: usize) -> usize {
+        if piece + 1 == self.state.len() {
+            self.last_piece_length
+        } else {
+            self.piece_length
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    use super::super::pipeline::BLOCK_SIZE;
+    use super::super::{Attributes, Bitfield, File, Payload, PieceHash, RawInfo, Sha1};
+    use super::*;
+
+    const PIECE_LENGTH: usize = 2 * BLOCK_SIZE as usize;
+
+    fn torrent(payload: Payload) -> Torrent {
+        let length = match &payload {
+            Payload::Single { length, .. } => *length,
+            Payload::Multi { files, .. } => files.iter().map(|file| file.length).sum(),
+        };
+
+        Torrent {
+            announce: String::new(),
+            info_hash: Sha1::digest(b"info"),
+            piece_length: PIECE_LENGTH,
+            pieces: vec![PieceHash::Sha1(Sha1::digest(b"piece")); length.div_ceil(PIECE_LENGTH)],
+            private: false,
+            payload,
+            info: RawInfo(Arc::new(Vec::new())),
+            web_seeds: Vec::new(),
+        }
+    }
+
+    /// A picker for `pieces` pieces, past picking at random
+    fn picker(pieces: usize, seed: u64) -> PiecePicker {
+        let mut picker = PiecePicker::new(&torrent(Payload::Single {
+            name: String::from("payload"),
+            length: pieces * PIECE_LENGTH,
+        }));
+
+        picker.random_first = 0;
+        picker.rng = StdRng::seed_from_u64(seed);
+        picker
+    }
+
+    fn available(picker: &mut PiecePicker, availability: &[usize]) {
+        for (piece, &count) in availability.iter().enumerate() {
+            (0..count).for_each(|_| picker.peer_has(piece));
+        }
+    }
+
+    fn pick(picker: &mut PiecePicker) -> usize {
+        picker.pick(|_| true).unwrap().index()
+    }
+
+    #[test]
+    fn availability_follows_bitfields_haves_and_departures() {
+        let mut picker = picker(10, 0);
+
+        let mut first = Bitfield::from(&[0b1010_0000, 0b0100_0000]);
+        let mut first_availability = Availability::new(10);
+        first_availability.update(|piece| first.has(piece) == Some(true), &mut picker);
+
+        let second = Bitfield::from(&[0b1000_0000, 0]);
+        let mut second_availability = Availability::new(10);
+        second_availability.update(|piece| second.has(piece) == Some(true), &mut picker);
+
+        assert_eq!(picker.availability, vec![2, 0, 1, 0, 0, 0, 0, 0, 0, 1]);
+
+        // A Have adds to what was already reported, without counting it twice
+        first.set(3);
+        first_availability.update(|piece| first.has(piece) == Some(true), &mut picker);
+        assert_eq!(picker.availability, vec![2, 0, 1, 1, 0, 0, 0, 0, 0, 1]);
+
+        first_availability.withdraw(&mut picker);
+        assert_eq!(picker.availability, vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        // Pieces out of range and peers never counted change nothing
+        picker.peer_has(10);
+        picker.peer_lost(1);
+        assert_eq!(picker.availability, vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rarest_pieces_come_first() {
+        let mut picker = picker(4, 0);
+        available(&mut picker, &[3, 1, 2, 1]);
+
+        let rarest: HashSet<usize> = [pick(&mut picker), pick(&mut picker)].into();
+        assert_eq!(rarest, HashSet::from([1, 3]));
+
+        assert_eq!(pick(&mut picker), 2);
+        assert_eq!(pick(&mut picker), 0);
+        assert!(picker.pick(|_| true).is_none());
+    }
+
+    #[test]
+    fn equally_rare_pieces_are_picked_at_random() {
+        let picked: HashSet<usize> = (0..32)
+            .map(|seed| {
+                let mut picker = picker(8, seed);
+                available(&mut picker, &[1; 8]);
+                pick(&mut picker)
+            })
+            .collect();
+
+        assert!(picked.len() > 1);
+    }
+
+    #[test]
+    fn only_pieces_the_peer_has_are_picked() {
+        let mut picker = picker(4, 0);
+        available(&mut picker, &[1, 1, 1, 1]);
+
+        let piece = picker.pick(|piece| piece == 2).unwrap();
+        assert_eq!(piece.index(), 2);
+        assert!(picker.pick(|piece| piece == 2).is_none());
+    }
+
+    #[test]
+    fn started_pieces_are_finished_first() {
+        let mut picker = picker(4, 0);
+        available(&mut picker, &[5, 5, 1, 5]);
+
+        let mut progress = picker.pick(|piece| piece == 0).unwrap();
+        progress.add_block(0, &[7; BLOCK_SIZE as usize]);
+        picker.abandon(progress);
+
+        // Piece 0 is commonplace, but half of it is already here
+        let progress = picker.pick(|_| true).unwrap();
+        assert_eq!(progress.index(), 0);
+        assert_eq!(progress.missing().count(), 1);
+
+        // Pieces given back untouched are nothing special
+        let untouched = picker.pick(|piece| piece == 3).unwrap();
+        picker.abandon(untouched);
+        assert_eq!(pick(&mut picker), 2);
+    }
+
+    #[test]
+    fn picking_turns_rarest_first_after_a_few_pieces() {
+        // Piece 15 is by far the rarest
+        let availability = [[9; 15].as_slice(), &[1]].concat();
+
+        let first_picks: HashSet<usize> = (0..8)
+            .map(|seed| {
+                let mut picker = picker(16, seed);
+                picker.random_first = RANDOM_FIRST_PIECES;
+                available(&mut picker, &availability);
+                pick(&mut picker)
+            })
+            .collect();
+
+        // Random picks pass it by
+        assert!(first_picks.iter().any(|&piece| piece != 15));
+
+        let mut picker = picker(16, 0);
+        picker.random_first = RANDOM_FIRST_PIECES;
+        available(&mut picker, &availability);
+
+        for _ in 0..RANDOM_FIRST_PIECES {
+            let piece = picker.pick(|piece| piece != 15).unwrap().index();
+            picker.complete(piece);
+        }
+
+        assert_eq!(pick(&mut picker), 15);
+    }
+
+    #[test]
+    fn the_endgame_spreads_pieces_over_peers() {
+        let mut picker = picker(2, 0);
+        available(&mut picker, &[1, 1]);
+
+        let first = pick(&mut picker);
+        assert!(!picker.in_endgame());
+
+        let second = pick(&mut picker);
+        assert!(picker.in_endgame());
+
+        // Each piece gets a second peer before either gets a third
+        let again: HashSet<usize> = (0..2)
+            .map(|_| picker.pick_endgame(|_| true).unwrap().index())
+            .collect();
+        assert_eq!(again, HashSet::from([first, second]));
+
+        picker.complete(first);
+        picker.complete(second);
+        assert!(picker.is_complete());
+    }
+
+    #[test]
+    fn skipped_pieces_are_never_picked_and_high_ones_first() {
+        let file = |name: &str, length, priority| File {
+            path: vec![String::from(name)],
+            length,
+            attributes: Attributes::default(),
+            priority,
+        };
+
+        let mut picker = PiecePicker::new(&torrent(Payload::Multi {
+            name: String::from("payload"),
+            files: vec![
+                file("skipped", 2 * PIECE_LENGTH, Priority::Skip),
+                file("low", PIECE_LENGTH, Priority::Low),
+                file("high", PIECE_LENGTH, Priority::High),
+            ],
+        }));
+
+        assert_eq!(pick(&mut picker), 3);
+        assert_eq!(pick(&mut picker), 2);
+        assert!(picker.pick(|_| true).is_none());
+        assert!(!picker.is_interesting(|piece| piece < 2));
+
+        picker.complete(2);
+        picker.complete(3);
+        assert!(picker.is_complete());
+    }
+}
@@ -0,0 +1,418 @@
+use std::time::{Duration, Instant};
+
+// Size of each block request, as recommended by the protocol
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+const MIN_DEPTH: usize = 2;
+const INITIAL_DEPTH: usize = 5;
+const MAX_DEPTH: usize = 128;
+
+// How often the throughput estimate is updated
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+const MIN_REQUEST_TIMEOUT: Duration = Duration::from_secs(4);
+const MAX_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Times a block is requested before the peer is taken not to send it at all
+const MAX_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRequest {
+    pub begin: u32,
+    pub length: u32,
+}
+
+#[derive(Debug)]
+struct Outstanding {
+    block: BlockRequest,
+    sent: Instant,
+    // Number of times this block has been requested without an answer
+    attempts: usize,
+}
+
+/// Keeps several block requests in flight towards a single peer, sizing the
+/// queue after the bandwidth-delay product measured on the connection
+#[derive(Debug)]
+pub struct Pipeline {
+    depth: usize,
+    // Most requests the peer is willing to have outstanding
+    limit: usize,
+    outstanding: Vec<Outstanding>,
+
+    // Smoothed round trip time from request to block
+    rtt: Option<Duration>,
+
+    // Smoothed throughput in bytes per second
+    rate: f64,
+    window_start: Instant,
+    window_bytes: usize,
+}
+
+/// What became of a block that arrived from the peer
+#[derive(Debug, PartialEq, Eq)]
+pub enum Arrival {
+    // The block answered a single outstanding request
+    Expected,
+    // The block answered a request that was sent more than once; the copies
+    // still in flight should be cancelled
+    Duplicated,
+    // The block was not requested (anymore)
+    Unexpected,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self {
+            depth: INITIAL_DEPTH,
+            limit: MAX_DEPTH,
+            outstanding: Vec::new(),
+            rtt: None,
+            rate: 0.0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.outstanding.len() < self.depth.min(self.limit)
+    }
+
+    /// Never have more than `limit` requests outstanding, whatever the depth
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.clamp(1, MAX_DEPTH);
+    }
+
+    pub fn sent(&mut self, block: BlockRequest) {
+        self.outstanding.push(Outstanding {
+            block,
+            sent: Instant::now(),
+            attempts: 1,
+        });
+    }
+
+    pub fn received(&mut self, block: BlockRequest) -> Arrival {
+        let now = Instant::now();
+
+        self.window_bytes += block.length as usize;
+        self.update_rate(now);
+
+        let position = match self.outstanding.iter().position(|o| o.block == block) {
+            Some(position) => position,
+            None => return Arrival::Unexpected,
+        };
+
+        let outstanding = self.outstanding.remove(position);
+
+        // Only unambiguous answers say anything about the round trip time
+        if outstanding.attempts == 1 {
+            self.update_rtt(now - outstanding.sent);
+            Arrival::Expected
+        } else {
+            Arrival::Duplicated
+        }
+    }
+
+    /// Requests that have gone unanswered for too long. They are considered
+    /// sent again from now on, so the caller is expected to re-request them.
+    /// None once a request has gone unanswered `MAX_ATTEMPTS` times, as the
+    /// peer is not going to answer it.
+    pub fn timed_out(&mut self) -> Option<Vec<BlockRequest>> {
+        let now = Instant::now();
+        let timeout = self.request_timeout();
+
+        let mut expired = Vec::new();
+
+        for outstanding in self.outstanding.iter_mut() {
+            if now - outstanding.sent > timeout {
+                if outstanding.attempts >= MAX_ATTEMPTS {
+                    return None;
+                }
+
+                outstanding.sent = now;
+                outstanding.attempts += 1;
+                expired.push(outstanding.block);
+            }
+        }
+
+        if !expired.is_empty() {
+            // Back off; the peer is not keeping up with what we ask of it
+            self.depth = std::cmp::max(MIN_DEPTH, self.depth / 2);
+        }
+
+        Some(expired)
+    }
+
+    /// Forget an outstanding request, returning whether it was outstanding
+    pub fn cancel(&mut self, block: BlockRequest) -> bool {
+        let before = self.outstanding.len();
+        self.outstanding.retain(|o| o.block != block);
+
+        self.outstanding.len() != before
+    }
+
+    /// Forget every outstanding request, returning them
+    pub fn clear(&mut self) -> Vec<BlockRequest> {
+        self.outstanding.drain(..).map(|o| o.block).collect()
+    }
+
+    fn request_timeout(&self) -> Duration {
+        let timeout = self
+            .rtt
+            .map(|rtt| rtt * 4 + Duration::from_secs(1))
+            .unwrap_or(MIN_REQUEST_TIMEOUT);
+
+        timeout.clamp(MIN_REQUEST_TIMEOUT, MAX_REQUEST_TIMEOUT)
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    fn update_rate(&mut self, now: Instant) {
+        let elapsed = now - self.window_start;
+
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+
+        let sample = self.window_bytes as f64 / elapsed.as_secs_f64();
+
+        self.rate = if self.rate == 0.0 {
+            sample
+        } else {
+            0.75 * self.rate + 0.25 * sample
+        };
+
+        self.window_start = now;
+        self.window_bytes = 0;
+
+        self.adapt_depth();
+    }
+
+    fn adapt_depth(&mut self) {
+        let rtt = match self.rtt {
+            Some(rtt) => rtt.as_secs_f64(),
+            None => return,
+        };
+
+        // Enough requests to cover the bandwidth-delay product, plus a couple
+        // to absorb jitter
+        let bandwidth_delay = (self.rate * rtt / BLOCK_SIZE as f64).ceil() as usize;
+
+        self.depth = (bandwidth_delay + 2).clamp(MIN_DEPTH, MAX_DEPTH);
+    }
+}
+
+/// The blocks of a piece received so far
+#[derive(Debug, Clone)]
+pub struct PartialPiece {
+    index: usize,
+    data: Vec<u8>,
+    received: Vec<bool>,
+}
+
+impl PartialPiece {
+    pub fn new(index: usize, size: usize) -> Self {
+        let block_count = size.div_ceil(BLOCK_SIZE as usize);
+
+        Self {
+            index,
+            data: vec![0; size],
+            received: vec![false; block_count],
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.received.iter().any(|&received| received)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received.iter().all(|&received| received)
+    }
+
+    pub fn missing(&self) -> impl Iterator<Item = BlockRequest> + '_ {
+        self.received
+            .iter()
+            .enumerate()
+            .filter(|(_, &received)| !received)
+            .map(|(block, _)| self.block(block))
+    }
+
+    pub fn received(&self) -> impl Iterator<Item = BlockRequest> + '_ {
+        self.received
+            .iter()
+            .enumerate()
+            .filter(|(_, &received)| received)
+            .map(|(block, _)| self.block(block))
+    }
+
+    /// Store a block, returning whether it was one we were missing
+    pub fn add_block(&mut self, begin: u32, bytes: &[u8]) -> bool {
+        let block = (begin / BLOCK_SIZE) as usize;
+
+        if !begin.is_multiple_of(BLOCK_SIZE) || block >= self.received.len() || self.received[block]
+        {
+            return false;
+        }
+
+        if self.block(block).length as usize != bytes.len() {
+            return false;
+        }
+
+        let begin = begin as usize;
+        self.data[begin..begin + bytes.len()].copy_from_slice(bytes);
+        self.received[block] = true;
+
+        true
+    }
+
+    /// Copy over the blocks `other` has that we are missing, returning them
+    pub fn merge(&mut self, other: &PartialPiece) -> Vec<BlockRequest> {
+        let mut merged = Vec::new();
+
+        for block in 0..self.received.len() {
+            if !self.received[block] && other.received[block] {
+                let request = self.block(block);
+                let range = request.begin as usize..(request.begin + request.length) as usize;
+
+                self.data[range.clone()].copy_from_slice(&other.data[range]);
+                self.received[block] = true;
+
+                merged.push(request);
+            }
+        }
+
+        merged
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn reset(&mut self) {
+        self.received
+            .iter_mut()
+            .for_each(|received| *received = false);
+    }
+
+    fn block(&self, block: usize) -> BlockRequest {
+        let begin = block as u32 * BLOCK_SIZE;
+
+        BlockRequest {
+            begin,
+            length: std::cmp::min(BLOCK_SIZE, self.data.len() as u32 - begin),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(index: u32) -> BlockRequest {
+        BlockRequest {
+            begin: index * BLOCK_SIZE,
+            length: BLOCK_SIZE,
+        }
+    }
+
+    /// Make every outstanding request look older than any timeout
+    fn expire(pipeline: &mut Pipeline) {
+        for outstanding in pipeline.outstanding.iter_mut() {
+            outstanding.sent -= MAX_REQUEST_TIMEOUT * 2;
+        }
+    }
+
+    #[test]
+    fn depth_follows_the_bandwidth_delay_product() {
+        let mut pipeline = Pipeline::new();
+        let rtt = Duration::from_millis(100);
+
+        pipeline.rtt = Some(rtt);
+        pipeline.window_start -= RATE_WINDOW;
+        pipeline.window_bytes = 99 * BLOCK_SIZE as usize;
+
+        pipeline.sent(block(0));
+        pipeline.outstanding[0].sent -= rtt;
+
+        // 100 blocks a second for a tenth of a second, and two to spare
+        assert_eq!(pipeline.received(block(0)), Arrival::Expected);
+        assert!((11..=13).contains(&pipeline.depth), "{}", pipeline.depth);
+
+        // A slow peer gets one block in flight, and two to spare
+        pipeline.window_start -= RATE_WINDOW * 100;
+        pipeline.rate = 0.0;
+        pipeline.sent(block(1));
+        pipeline.received(block(1));
+        assert_eq!(pipeline.depth, 3);
+    }
+
+    #[test]
+    fn depth_never_exceeds_the_limit() {
+        let mut pipeline = Pipeline::new();
+        pipeline.set_limit(2);
+
+        pipeline.sent(block(0));
+        assert!(pipeline.has_room());
+
+        pipeline.sent(block(1));
+        assert!(!pipeline.has_room());
+    }
+
+    #[test]
+    fn unanswered_requests_are_repeated_then_given_up() {
+        let mut pipeline = Pipeline::new();
+
+        pipeline.sent(block(0));
+        pipeline.sent(block(1));
+        assert_eq!(pipeline.timed_out(), Some(Vec::new()));
+
+        expire(&mut pipeline);
+        assert_eq!(pipeline.timed_out(), Some(vec![block(0), block(1)]));
+        assert_eq!(pipeline.depth, INITIAL_DEPTH / 2);
+
+        // Answering one leaves the other to time out
+        assert_eq!(pipeline.received(block(1)), Arrival::Duplicated);
+
+        expire(&mut pipeline);
+        assert_eq!(pipeline.timed_out(), Some(vec![block(0)]));
+
+        expire(&mut pipeline);
+        assert_eq!(pipeline.timed_out(), None);
+    }
+
+    #[test]
+    fn duplicate_and_cancelled_blocks() {
+        let mut pipeline = Pipeline::new();
+
+        pipeline.sent(block(0));
+        pipeline.sent(block(1));
+
+        expire(&mut pipeline);
+        pipeline.timed_out();
+
+        // The first copy of a repeated request is a duplicate, the second
+        // was not asked for any more
+        assert_eq!(pipeline.received(block(0)), Arrival::Duplicated);
+        assert_eq!(pipeline.received(block(0)), Arrival::Unexpected);
+
+        assert!(pipeline.cancel(block(1)));
+        assert!(!pipeline.cancel(block(1)));
+        assert_eq!(pipeline.received(block(1)), Arrival::Unexpected);
+
+        pipeline.sent(block(2));
+        assert_eq!(pipeline.clear(), vec![block(2)]);
+        assert!(pipeline.outstanding.is_empty());
+    }
+}
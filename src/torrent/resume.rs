@@ -0,0 +1,340 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use super::storage::{Layout, Verification};
+use super::{get_bstr, get_int, get_list, Bencoded, Bitfield, PeerAddress, Sha1, Torrent};
+
+/// Keeps track of the resume file of a download, which lets a restarted
+/// download carry on from where it was instead of starting over
+#[derive(Debug)]
+pub struct Resume {
+    path: PathBuf,
+    files: Vec<PathBuf>,
+    info_hash: Sha1,
+    piece_count: usize,
+}
+
+/// What a download had achieved when its resume file was written
+#[derive(Debug)]
+pub struct ResumeData {
+    // Pieces that were verified and stored
+    pub have: Vec<bool>,
+    // Blocks stored for pieces that were not yet complete, by their offset
+    // into the piece
+    pub unfinished: Vec<(usize, Vec<u32>)>,
+    pub peers: Vec<PeerAddress>,
+}
+
+/// What to make of the data already on disk when a download starts
+#[derive(Debug)]
+pub enum Startup {
+    // Nothing has been downloaded yet
+    Fresh,
+    // The files are just as the resume file describes them
+    Resume(ResumeData),
+    // The files may hold pieces, but they have to be checked against their
+    // hashes to find out which; the peers we knew about are still useful
+    Recheck(Vec<PeerAddress>),
+}
+
+// Length and modification time of a payload file, if it exists
+type FileStats = Vec<Option<(i64, i64)>>;
+
+impl Resume {
+    /// Keep the resume file next to the payload, below `root`
+    pub fn new<P: AsRef<Path>>(torrent: &Torrent, root: P) -> Self {
+        let root = root.as_ref();
+
+        let name = Path::new(torrent.name())
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| String::from("download"));
+
+        Self {
+            path: root.join(format!("{}.resume", name)),
+            files: Layout::new(torrent)
+                .paths()
+                .map(|path| root.join(path))
+                .collect(),
+            info_hash: torrent.info_hash,
+            piece_count: torrent.piece_count(),
+        }
+    }
+
+    /// Decide how far along the download is. Must be called before the
+    /// payload files are opened, as opening them creates those missing.
+    pub fn check(&self) -> Startup {
+        let saved = fs::read(&self.path)
+            .ok()
+            .and_then(|bytes| Bencoded::parse(&bytes))
+            .and_then(|bencoded| self.parse(&bencoded));
+
+        match saved {
+            Some((data, files)) if files == self.file_stats() => Startup::Resume(data),
+            Some((data, _)) => Startup::Recheck(data.peers),
+            None if self.files.iter().any(|file| file.exists()) => Startup::Recheck(Vec::new()),
+            None => Startup::Fresh,
+        }
+    }
+
+    /// Write the resume file, recording the payload files as they are now.
+    /// The payload must have been flushed beforehand.
+    pub fn save(&self, data: &ResumeData) -> io::Result<()> {
+        let mut have = Bitfield::new();
+
+        for (piece, _) in data.have.iter().enumerate().filter(|(_, &have)| have) {
+            have.set(piece);
+        }
+
+        have.0.resize(self.piece_count.div_ceil(8), 0);
+
+        let unfinished = data
+            .unfinished
+            .iter()
+            .map(|(piece, blocks)| {
+                Bencoded::dict(vec![
+                    ("piece", Bencoded::Int(*piece as i64)),
+                    (
+                        "blocks",
+                        Bencoded::List(
+                            blocks
+                                .iter()
+                                .map(|&begin| Bencoded::Int(begin as i64))
+                                .collect(),
+                        ),
+                    ),
+                ])
+            })
+            .collect();
+
+        let files = self
+            .file_stats()
+            .into_iter()
+            .map(|stats| {
+                let (length, modified) = stats.unwrap_or((-1, -1));
+
+                Bencoded::dict(vec![
+                    ("length", Bencoded::Int(length)),
+                    ("mtime", Bencoded::Int(modified)),
+                ])
+            })
+            .collect();
+
+        let (peers6, peers): (Vec<&PeerAddress>, Vec<&PeerAddress>) =
+            data.peers.iter().partition(|peer| peer.is_ipv6());
+
+        let encoded: Vec<u8> = (&Bencoded::dict(vec![
+            (
+                "info-hash",
+                Bencoded::Bstr(Vec::from(self.info_hash.as_ref())),
+            ),
+            ("pieces", Bencoded::Bstr(have.0)),
+            ("unfinished", Bencoded::List(unfinished)),
+            ("files", Bencoded::List(files)),
+            ("peers", Bencoded::Bstr(compact(&peers))),
+            ("peers6", Bencoded::Bstr(compact(&peers6))),
+        ]))
+            .into();
+
+        // Write to the side first, so that a crash cannot leave a torn file
+        let partial = self.path.with_extension("resume.part");
+
+        fs::write(&partial, encoded)?;
+        fs::rename(partial, &self.path)
+    }
+
+    /// Record the pieces a verification found intact, so that the next
+    /// download starts from them
+    pub fn save_verified(&self, verification: &Verification) -> io::Result<()> {
+        // Peers we knew about are worth keeping
+        let peers = fs::read(&self.path)
+            .ok()
+            .and_then(|bytes| Bencoded::parse(&bytes))
+            .and_then(|bencoded| self.parse(&bencoded))
+            .map(|(data, _)| data.peers)
+            .unwrap_or_default();
+
+        self.save(&ResumeData {
+            have: verification.pieces.clone(),
+            unfinished: Vec::new(),
+            peers,
+        })
+    }
+
+    fn parse(&self, bencoded: &Bencoded) -> Option<(ResumeData, FileStats)> {
+        if !matches!(bencoded, Bencoded::Dict(_)) {
+            return None;
+        }
+
+        if get_bstr(bencoded, "info-hash")? != self.info_hash.as_ref() {
+            return None;
+        }
+
+        let have = Bitfield::from(&get_bstr(bencoded, "pieces")?);
+        let have = (0..self.piece_count)
+            .map(|piece| have.has(piece) == Some(true))
+            .collect();
+
+        let unfinished = get_list(bencoded, "unfinished")?
+            .iter()
+            .filter(|entry| matches!(entry, Bencoded::Dict(_)))
+            .map(|entry| {
+                let piece = get_int(entry, "piece")? as usize;
+
+                let blocks = get_list(entry, "blocks")?
+                    .into_iter()
+                    .map(|begin| match begin {
+                        Bencoded::Int(begin) => u32::try_from(begin).ok(),
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()?;
+
+                (piece < self.piece_count).then_some((piece, blocks))
+            })
+            .collect::<Option<_>>()?;
+
+        let files = get_list(bencoded, "files")?
+            .iter()
+            .filter(|entry| matches!(entry, Bencoded::Dict(_)))
+            .map(|entry| {
+                let length = get_int(entry, "length")?;
+                let modified = get_int(entry, "mtime")?;
+
+                Some((length >= 0).then_some((length, modified)))
+            })
+            .collect::<Option<_>>()?;
+
+        let peers = get_bstr(bencoded, "peers")?
+            .chunks_exact(6)
+            .chain(
+                get_bstr(bencoded, "peers6")
+                    .unwrap_or_default()
+                    .chunks_exact(18),
+            )
+            .map(PeerAddress::new)
+            .collect();
+
+        let data = ResumeData {
+            have,
+            unfinished,
+            peers,
+        };
+
+        Some((data, files))
+    }
+
+    fn file_stats(&self) -> FileStats {
+        self.files
+            .iter()
+            .map(|file| {
+                let metadata = fs::metadata(file).ok()?;
+
+                let modified = metadata
+                    .modified()
+                    .ok()?
+                    .duration_since(UNIX_EPOCH)
+                    .ok()?
+                    .as_nanos();
+
+                Some((metadata.len() as i64, modified as i64))
+            })
+            .collect()
+    }
+}
+
+/// Addresses back to back in compact form
+fn compact(peers: &[&PeerAddress]) -> Vec<u8> {
+    peers.iter().flat_map(|peer| peer.to_compact()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::super::{Payload, PieceHash, RawInfo};
+    use super::*;
+
+    fn torrent() -> Torrent {
+        Torrent {
+            announce: String::new(),
+            info_hash: Sha1::digest(b"info"),
+            piece_length: 4,
+            pieces: vec![PieceHash::Sha1(Sha1::digest(b"piece")); 3],
+            private: false,
+            payload: Payload::Single {
+                name: String::from("payload"),
+                length: 10,
+            },
+            info: RawInfo(Arc::new(Vec::new())),
+            web_seeds: Vec::new(),
+        }
+    }
+
+    fn root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("resume-{}-{}", name, std::process::id()));
+
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn unchanged_files_are_resumed() {
+        let root = root("unchanged");
+        let resume = Resume::new(&torrent(), &root);
+
+        assert!(matches!(resume.check(), Startup::Fresh));
+
+        fs::write(root.join("payload"), [0; 10]).unwrap();
+
+        let data = ResumeData {
+            have: vec![true, false, true],
+            unfinished: vec![(1, vec![0])],
+            peers: vec![
+                PeerAddress::new(&[127, 0, 0, 1, 0x1a, 0xe1]),
+                PeerAddress::new(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1]),
+            ],
+        };
+
+        resume.save(&data).unwrap();
+
+        match resume.check() {
+            Startup::Resume(resumed) => {
+                assert_eq!(resumed.have, data.have);
+                assert_eq!(resumed.unfinished, data.unfinished);
+                assert!(resumed.peers == data.peers);
+            }
+
+            startup => panic!("Expected to resume, got {:?}", startup),
+        }
+
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn changed_files_are_rechecked() {
+        let root = root("changed");
+        let resume = Resume::new(&torrent(), &root);
+
+        fs::write(root.join("payload"), [0; 10]).unwrap();
+
+        let data = ResumeData {
+            have: vec![true, true, true],
+            unfinished: Vec::new(),
+            peers: Vec::new(),
+        };
+
+        resume.save(&data).unwrap();
+        fs::write(root.join("payload"), [0; 4]).unwrap();
+
+        assert!(matches!(resume.check(), Startup::Recheck(_)));
+
+        // Files without a resume file may hold anything
+        fs::remove_file(root.join("payload.resume")).unwrap();
+        assert!(matches!(resume.check(), Startup::Recheck(_)));
+
+        fs::remove_dir_all(root).ok();
+    }
+}
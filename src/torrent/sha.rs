@@ -1,9 +1,8 @@
-use sha1;
 use sha1::Digest;
 
 use std::fmt;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Sha1([u8; 20]);
 
 impl Sha1 {
@@ -20,20 +19,6 @@ impl Sha1 {
 
         Self::new_raw(&hasher.finalize())
     }
-
-    pub fn url_safe(&self) -> String {
-        let mut url = String::with_capacity(30);
-
-        for (i, nibble) in hex::encode(&self.0).chars().enumerate() {
-            if i % 2 == 0 {
-                url.push_str("%");
-            }
-
-            url.push(nibble.to_ascii_uppercase());
-        }
-
-        url
-    }
 }
 
 impl AsRef<[u8]> for Sha1 {
@@ -48,14 +33,41 @@ impl fmt::Debug for Sha1 {
     }
 }
 
-impl PartialEq for Sha1 {
-    fn eq(&self, other: &Self) -> bool {
-        for (a, b) in self.0.iter().zip(other.0.iter()) {
-            if a != b {
-                return false;
-            }
-        }
+/// A SHA-256 digest, as v2 torrents (BEP 52) use for their info hash and
+/// merkle trees
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Sha256([u8; 32]);
+
+impl Sha256 {
+    pub fn new_raw(sha: &[u8]) -> Self {
+        let mut buffer = [0; 32];
+        buffer[..].copy_from_slice(&sha[0..32]);
+
+        Self(buffer)
+    }
+
+    pub fn digest(message: &[u8]) -> Self {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(message);
 
-        true
+        Self::new_raw(&hasher.finalize())
+    }
+
+    /// The first 20 bytes, which stand in for the whole where a SHA-1 info
+    /// hash is expected, such as in trackers and the DHT
+    pub fn truncated(&self) -> Sha1 {
+        Sha1::new_raw(&self.0[..20])
+    }
+}
+
+impl AsRef<[u8]> for Sha256 {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Sha256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Sha256({})", hex::encode(self.0))
     }
 }
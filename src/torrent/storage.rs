@@ -0,0 +1,683 @@
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+
+use super::{Payload, PieceHash, Priority, Torrent};
+
+mod file;
+pub use file::FileStorage;
+
+mod mmap;
+pub use mmap::MmapStorage;
+
+mod partfile;
+use partfile::PartFile;
+
+mod verify;
+pub use verify::{check_pieces, Verification};
+
+#[cfg(test)]
+mod memory;
+#[cfg(test)]
+pub use memory::MemoryStorage;
+
+/// Where the pieces of a torrent are kept, addressed by piece index and
+/// offset into the piece so that pieces can be stored in any order
+pub trait Storage {
+    fn layout(&self) -> &Layout;
+
+    fn read_block(&mut self, piece: usize, offset: usize, length: usize) -> io::Result<Vec<u8>>;
+
+    fn write_block(&mut self, piece: usize, offset: usize, data: &[u8]) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Whether the stored piece matches its hash
+    fn verify_piece(&mut self, piece: usize) -> io::Result<bool> {
+        let layout = self.layout();
+
+        if piece >= layout.pieces.len() {
+            return Err(out_of_bounds());
+        }
+
+        let length = layout.piece_size(piece);
+        let data = self.read_block(piece, 0, length)?;
+
+        Ok(self.layout().is_valid(piece, &data))
+    }
+}
+
+/// How the pieces of a torrent map onto its files
+#[derive(Debug, Clone)]
+pub struct Layout {
+    piece_length: usize,
+    pieces: Vec<PieceHash>,
+    // In payload order
+    files: Vec<LayoutFile>,
+    length: usize,
+}
+
+#[derive(Debug, Clone)]
+struct LayoutFile {
+    path: PathBuf,
+    length: usize,
+    kind: FileKind,
+    priority: Priority,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FileKind {
+    Regular,
+    Executable,
+    // Never put on disk, and all zeros
+    Padding,
+    // Links to another path of the payload, relative to the link itself
+    Symlink(PathBuf),
+}
+
+/// The part of a single file covered by a span of the payload
+#[derive(Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub file: usize,
+    pub offset: usize,
+    pub length: usize,
+}
+
+impl Layout {
+    pub fn new(torrent: &Torrent) -> Self {
+        let files = match &torrent.payload {
+            Payload::Single { name, length } => vec![LayoutFile {
+                path: sanitize(&[name]),
+                length: *length,
+                kind: FileKind::Regular,
+                priority: Priority::default(),
+            }],
+
+            Payload::Multi { name, files } => files
+                .iter()
+                .map(|file| {
+                    let path: Vec<&String> = std::iter::once(name).chain(&file.path).collect();
+                    let path = sanitize(&path);
+
+                    let attributes = &file.attributes;
+
+                    // A link holds no data, so one with a length is stored
+                    // like any other file
+                    let kind = match &attributes.symlink {
+                        _ if attributes.padding => FileKind::Padding,
+
+                        Some(target) if file.length == 0 => {
+                            let target: Vec<&String> =
+                                std::iter::once(name).chain(target).collect();
+                            FileKind::Symlink(relative_to(&path, &sanitize(&target)))
+                        }
+
+                        _ if attributes.executable => FileKind::Executable,
+                        _ => FileKind::Regular,
+                    };
+
+                    LayoutFile {
+                        path,
+                        length: file.length,
+                        kind,
+                        priority: file.priority,
+                    }
+                })
+                .collect(),
+        };
+
+        Self {
+            piece_length: torrent.piece_length,
+            pieces: torrent.pieces.clone(),
+            length: files.iter().map(|file| file.length).sum(),
+            files,
+        }
+    }
+
+    /// Paths of the payload files, relative to the download directory
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|file| file.path.as_path())
+    }
+
+    /// Whether `file` only pads the payload, and is never stored
+    pub fn is_padding(&self, file: usize) -> bool {
+        self.files[file].kind == FileKind::Padding
+    }
+
+    /// Whether `file` is left out of the download, and never created. What
+    /// it shares with pieces of wanted files goes to the part file instead.
+    pub fn is_skipped(&self, file: usize) -> bool {
+        let file = &self.files[file];
+        file.priority == Priority::Skip && file.kind != FileKind::Padding
+    }
+
+    /// How eagerly each piece is downloaded: as eagerly as the most wanted
+    /// file it is part of. Padding is no reason to download a piece, but a
+    /// piece of nothing else is downloaded as usual.
+    pub fn piece_priorities(&self) -> Vec<Priority> {
+        let mut priorities = vec![None; self.pieces.len()];
+
+        for (file, (_, pieces)) in self.file_pieces().enumerate() {
+            if self.is_padding(file) {
+                continue;
+            }
+
+            for piece in pieces {
+                priorities[piece] = priorities[piece].max(Some(self.files[file].priority));
+            }
+        }
+
+        priorities
+            .into_iter()
+            .map(Option::unwrap_or_default)
+            .collect()
+    }
+
+    /// Pieces that are part of both skipped and wanted files
+    fn shared_pieces(&self) -> Vec<usize> {
+        let mut skipped = vec![false; self.pieces.len()];
+        let mut wanted = vec![false; self.pieces.len()];
+
+        for (file, (_, pieces)) in self.file_pieces().enumerate() {
+            let part_of = if self.is_skipped(file) {
+                &mut skipped
+            } else if !self.is_padding(file) {
+                &mut wanted
+            } else {
+                continue;
+            };
+
+            part_of[pieces].fill(true);
+        }
+
+        (0..self.pieces.len())
+            .filter(|&piece| skipped[piece] && wanted[piece])
+            .collect()
+    }
+
+    /// Paths of the payload files, along with the pieces each is part of
+    pub fn file_pieces(&self) -> impl Iterator<Item = (&Path, Range<usize>)> {
+        let mut file_start = 0;
+
+        self.files.iter().map(move |file| {
+            let file_end = file_start + file.length;
+
+            // Empty files are part of no piece
+            let pieces = if file.length == 0 {
+                0..0
+            } else {
+                file_start / self.piece_length..file_end.div_ceil(self.piece_length)
+            };
+
+            file_start = file_end;
+            (file.path.as_path(), pieces)
+        })
+    }
+
+    pub fn piece_size(&self, piece: usize) -> usize {
+        let payload_left = self.length.saturating_sub(self.piece_length * piece);
+        std::cmp::min(self.piece_length, payload_left)
+    }
+
+    /// Whether `data` is the whole of `piece`, by its hash
+    pub fn is_valid(&self, piece: usize, data: &[u8]) -> bool {
+        self.pieces
+            .get(piece)
+            .is_some_and(|hash| hash.matches(data))
+    }
+
+    /// Offset into the payload of a block, provided it lies within its piece
+    pub fn locate(&self, piece: usize, offset: usize, length: usize) -> io::Result<usize> {
+        if piece >= self.pieces.len() || offset + length > self.piece_size(piece) {
+            return Err(out_of_bounds());
+        }
+
+        Ok(piece * self.piece_length + offset)
+    }
+
+    /// The files covered by `length` bytes from `start` into the payload
+    pub fn segments(&self, start: usize, length: usize) -> Vec<Segment> {
+        let end = start + length;
+        let mut segments = Vec::new();
+        let mut file_start = 0;
+
+        for (file, layout_file) in self.files.iter().enumerate() {
+            let file_end = file_start + layout_file.length;
+
+            let from = start.max(file_start);
+            let to = end.min(file_end);
+
+            if from < to {
+                segments.push(Segment {
+                    file,
+                    offset: from - file_start,
+                    length: to - from,
+                });
+            }
+
+            file_start = file_end;
+        }
+
+        segments
+    }
+}
+
+/// Build a relative path from the components of a metainfo path, leaving
+/// out anything that could escape the download directory
+fn sanitize<S: AsRef<str>>(components: &[S]) -> PathBuf {
+    components
+        .iter()
+        .flat_map(|component| Path::new(component.as_ref()).components())
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The path to `target` from the directory holding `path`, both relative
+/// to the download directory
+fn relative_to(path: &Path, target: &Path) -> PathBuf {
+    let depth = path.components().count().saturating_sub(1);
+
+    std::iter::repeat_n(Component::ParentDir.as_os_str(), depth)
+        .chain(target.iter())
+        .collect()
+}
+
+/// Open every file of the payload below `root`, creating those that are
+/// missing, at its full length. Padding files and links have no contents
+/// to open, and neither padding nor skipped files are created at all.
+fn open_files(layout: &Layout, root: &Path) -> io::Result<Vec<Option<fs::File>>> {
+    layout
+        .files
+        .iter()
+        .enumerate()
+        .map(
+            |(
+                index,
+                LayoutFile {
+                    path, length, kind, ..
+                },
+            )| {
+                if *kind == FileKind::Padding || layout.is_skipped(index) {
+                    return Ok(None);
+                }
+
+                // Links are only followed where they point within the payload,
+                // which a link further up the path could change
+                if through_symlink(root, path) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Payload path leads through a link",
+                    ));
+                }
+
+                let path = root.join(path);
+
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                if let FileKind::Symlink(target) = kind {
+                    // Whatever is already in place is left alone
+                    if fs::symlink_metadata(&path).is_err() {
+                        symlink(target, &path)?;
+                    }
+
+                    return Ok(None);
+                }
+
+                // Read access is needed to serve the pieces to other peers
+                let file = fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(path)?;
+
+                // Leave files that are already in place untouched, so they keep
+                // the modification time the resume data expects
+                if file.metadata()?.len() != *length as u64 {
+                    file.set_len(*length as u64)?;
+                }
+
+                if *kind == FileKind::Executable {
+                    set_executable(&file)?;
+                }
+
+                Ok(Some(file))
+            },
+        )
+        .collect()
+}
+
+/// Whether any directory leading to `path` below `root` is a link
+fn through_symlink(root: &Path, path: &Path) -> bool {
+    path.ancestors()
+        .skip(1)
+        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+        .any(|ancestor| {
+            fs::symlink_metadata(root.join(ancestor))
+                .is_ok_and(|metadata| metadata.file_type().is_symlink())
+        })
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+// Making links takes extra privileges elsewhere, so they are left out
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_executable(file: &fs::File) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = file.metadata()?.permissions();
+    // Executable by whoever may read it
+    permissions.set_mode(permissions.mode() | (permissions.mode() & 0o444) >> 2);
+    file.set_permissions(permissions)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_file: &fs::File) -> io::Result<()> {
+    Ok(())
+}
+
+fn out_of_bounds() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Block is outside of the torrent",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::super::{Attributes, File, Priority, RawInfo, Sha1};
+    use super::*;
+
+    fn layout(files: &[usize], piece_length: usize, data: &[u8]) -> Layout {
+        Layout {
+            piece_length,
+            pieces: data
+                .chunks(piece_length)
+                .map(|piece| PieceHash::Sha1(Sha1::digest(piece)))
+                .collect(),
+            files: files
+                .iter()
+                .enumerate()
+                .map(|(file, &length)| LayoutFile {
+                    path: PathBuf::from(file.to_string()),
+                    length,
+                    kind: FileKind::Regular,
+                    priority: Priority::default(),
+                })
+                .collect(),
+            length: files.iter().sum(),
+        }
+    }
+
+    #[test]
+    fn segments_span_file_boundaries() {
+        let layout = layout(&[5, 0, 3, 10], 4, &[0; 18]);
+
+        assert_eq!(
+            layout.segments(4, 6),
+            vec![
+                Segment {
+                    file: 0,
+                    offset: 4,
+                    length: 1
+                },
+                Segment {
+                    file: 2,
+                    offset: 0,
+                    length: 3
+                },
+                Segment {
+                    file: 3,
+                    offset: 0,
+                    length: 2
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn files_know_their_pieces() {
+        let layout = layout(&[5, 0, 3, 10], 4, &[0; 18]);
+
+        let pieces: Vec<Range<usize>> = layout.file_pieces().map(|(_, pieces)| pieces).collect();
+
+        assert_eq!(pieces, vec![0..2, 0..0, 1..2, 2..5]);
+    }
+
+    #[test]
+    fn blocks_must_lie_within_their_piece() {
+        let layout = layout(&[10], 4, &[0; 10]);
+
+        assert_eq!(layout.locate(1, 2, 2).unwrap(), 6);
+        assert!(layout.locate(1, 2, 3).is_err());
+        assert!(layout.locate(2, 0, 3).is_err());
+        assert!(layout.locate(3, 0, 1).is_err());
+    }
+
+    #[test]
+    fn pieces_can_be_written_in_any_order() {
+        let data: Vec<u8> = (0..10).collect();
+        let mut storage = MemoryStorage::new(layout(&[3, 7], 4, &data));
+
+        for piece in [2, 0, 1] {
+            assert!(!storage.verify_piece(piece).unwrap());
+
+            let start = piece * 4;
+            let end = (start + 4).min(data.len());
+            storage.write_block(piece, 0, &data[start..end]).unwrap();
+        }
+
+        for piece in 0..3 {
+            assert!(storage.verify_piece(piece).unwrap());
+        }
+
+        assert_eq!(storage.read_block(0, 2, 2).unwrap(), vec![2, 3]);
+        assert!(storage.write_block(2, 1, &[0; 2]).is_err());
+    }
+
+    #[test]
+    fn paths_cannot_escape_the_download_directory() {
+        assert_eq!(
+            sanitize(&["name", "../../etc", "/passwd"]),
+            PathBuf::from("name/etc/passwd")
+        );
+    }
+
+    fn file(path: &[&str], length: usize, attributes: Attributes) -> File {
+        File {
+            path: path.iter().map(|part| part.to_string()).collect(),
+            length,
+            attributes,
+            priority: Priority::default(),
+        }
+    }
+
+    /// A torrent named "payload" of `files`, in pieces of 4 bytes
+    fn multi_file(files: Vec<File>, data: &[u8]) -> Torrent {
+        Torrent {
+            announce: String::new(),
+            info_hash: Sha1::digest(b"info"),
+            piece_length: 4,
+            pieces: data
+                .chunks(4)
+                .map(|piece| PieceHash::Sha1(Sha1::digest(piece)))
+                .collect(),
+            private: false,
+            payload: Payload::Multi {
+                name: String::from("payload"),
+                files,
+            },
+            info: RawInfo(Arc::new(Vec::new())),
+            web_seeds: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn pieces_are_as_wanted_as_their_most_wanted_file() {
+        let mut layout = layout(&[5, 3, 0, 10, 2], 4, &[0; 20]);
+
+        let priorities = [
+            Priority::Low,
+            Priority::Skip,
+            Priority::High,
+            Priority::Skip,
+            Priority::High,
+        ];
+
+        for (file, priority) in layout.files.iter_mut().zip(priorities) {
+            file.priority = priority;
+        }
+
+        assert_eq!(
+            layout.piece_priorities(),
+            vec![
+                Priority::Low,
+                Priority::Low,
+                Priority::Skip,
+                Priority::Skip,
+                Priority::High
+            ]
+        );
+
+        assert_eq!(layout.shared_pieces(), vec![1, 4]);
+    }
+
+    #[test]
+    fn skipped_files_are_never_created() {
+        let root = std::env::temp_dir().join(format!("storage-skipped-{}", std::process::id()));
+
+        let data: Vec<u8> = (1..=20).collect();
+
+        let skipped = File {
+            priority: Priority::Skip,
+            ..file(&["skipped"], 10, Attributes::default())
+        };
+
+        let torrent = multi_file(
+            vec![
+                file(&["first"], 5, Attributes::default()),
+                skipped,
+                file(&["last"], 5, Attributes::default()),
+            ],
+            &data,
+        );
+
+        let mut storage = FileStorage::open(&torrent, &root).unwrap();
+
+        for piece in [0, 1, 3, 4] {
+            storage
+                .write_block(piece, 0, &data[piece * 4..piece * 4 + 4])
+                .unwrap();
+        }
+
+        // A piece of nothing but the skipped file has nowhere to go
+        assert!(storage.write_block(2, 0, &data[8..12]).is_err());
+
+        storage.flush().unwrap();
+
+        // The pieces shared with the skipped file read back whole
+        for piece in [0, 1, 3, 4] {
+            assert!(storage.verify_piece(piece).unwrap());
+        }
+
+        assert!(!root.join("payload/skipped").exists());
+        assert!(root.join(".payload.parts").exists());
+        assert_eq!(fs::read(root.join("payload/last")).unwrap(), &data[15..]);
+
+        let verification = Verification::check(&torrent, &root);
+        assert_eq!(verification.pieces, vec![true, true, false, true, true]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn padding_is_not_stored_and_attributes_are_applied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = std::env::temp_dir().join(format!("storage-attributes-{}", std::process::id()));
+
+        let link = |target: &[&str]| Attributes {
+            symlink: Some(target.iter().map(|part| part.to_string()).collect()),
+            ..Attributes::default()
+        };
+
+        let mut data: Vec<u8> = (1..=12).collect();
+        data[5..8].fill(0);
+
+        let torrent = multi_file(
+            vec![
+                file(
+                    &["tool"],
+                    5,
+                    Attributes {
+                        executable: true,
+                        ..Attributes::default()
+                    },
+                ),
+                file(
+                    &[".pad", "3"],
+                    3,
+                    Attributes {
+                        padding: true,
+                        ..Attributes::default()
+                    },
+                ),
+                file(&["data"], 4, Attributes::default()),
+                file(&["links", "tool"], 0, link(&["tool"])),
+                file(&["escape"], 0, link(&["..", "..", "etc"])),
+            ],
+            &data,
+        );
+
+        let mut storage = FileStorage::open(&torrent, &root).unwrap();
+
+        for (piece, chunk) in data.chunks(4).enumerate() {
+            storage.write_block(piece, 0, chunk).unwrap();
+            assert!(storage.verify_piece(piece).unwrap());
+        }
+
+        storage.flush().unwrap();
+
+        let payload = root.join("payload");
+        assert!(!payload.join(".pad").exists());
+
+        let mode = fs::metadata(payload.join("tool"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o111, (mode & 0o444) >> 2);
+
+        assert_eq!(fs::read(payload.join("links/tool")).unwrap(), &data[..5]);
+        assert_eq!(
+            fs::read_link(payload.join("escape")).unwrap(),
+            PathBuf::from("../payload/etc")
+        );
+
+        // Nothing is stored by way of a link
+        assert!(through_symlink(&root, Path::new("payload/links/tool/x")));
+        assert!(!through_symlink(&root, Path::new("payload/links/tool")));
+
+        let verification = Verification::check(&torrent, &root);
+        assert_eq!(verification.valid_pieces(), 3);
+        assert_eq!(verification.files.len(), 4);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
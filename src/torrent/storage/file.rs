@@ -0,0 +1,98 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::{open_files, Layout, PartFile, Storage, Torrent};
+
+/// Keeps the payload in its files on disk, laid out as the torrent describes
+#[derive(Debug)]
+pub struct FileStorage {
+    layout: Layout,
+    // None for files that are not stored, such as padding
+    files: Vec<Option<fs::File>>,
+    parts: PartFile,
+}
+
+impl FileStorage {
+    /// Open the payload files below `root`, creating any that are missing
+    pub fn open<P: AsRef<Path>>(torrent: &Torrent, root: P) -> io::Result<Self> {
+        let layout = Layout::new(torrent);
+        let files = open_files(&layout, root.as_ref())?;
+        let parts = PartFile::new(torrent, &layout, root.as_ref());
+
+        Ok(Self {
+            layout,
+            files,
+            parts,
+        })
+    }
+}
+
+impl Storage for FileStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read_block(&mut self, piece: usize, offset: usize, length: usize) -> io::Result<Vec<u8>> {
+        let start = self.layout.locate(piece, offset, length)?;
+        let mut block = vec![0; length];
+        let mut position = 0;
+
+        for segment in self.layout.segments(start, length) {
+            let buffer = &mut block[position..position + segment.length];
+
+            match &mut self.files[segment.file] {
+                Some(file) => {
+                    file.seek(SeekFrom::Start(segment.offset as u64))?;
+                    file.read_exact(buffer)?;
+                }
+
+                None if self.layout.is_skipped(segment.file) => {
+                    self.parts.read(piece, offset + position, buffer)?
+                }
+
+                // What is not stored reads as zeros
+                None => {}
+            }
+
+            position += segment.length;
+        }
+
+        Ok(block)
+    }
+
+    fn write_block(&mut self, piece: usize, offset: usize, data: &[u8]) -> io::Result<()> {
+        let start = self.layout.locate(piece, offset, data.len())?;
+        let mut position = 0;
+
+        for segment in self.layout.segments(start, data.len()) {
+            let data = &data[position..position + segment.length];
+
+            match &mut self.files[segment.file] {
+                Some(file) => {
+                    file.seek(SeekFrom::Start(segment.offset as u64))?;
+                    file.write_all(data)?;
+                }
+
+                None if self.layout.is_skipped(segment.file) => {
+                    self.parts.write(piece, offset + position, data)?
+                }
+
+                None => {}
+            }
+
+            position += segment.length;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.files
+            .iter_mut()
+            .flatten()
+            .try_for_each(|file| file.flush())?;
+
+        self.parts.flush()
+    }
+}
@@ -0,0 +1,40 @@
+use std::io;
+
+use super::{Layout, Storage};
+
+/// Keeps the whole payload in memory, so tests need no disk
+#[derive(Debug)]
+pub struct MemoryStorage {
+    layout: Layout,
+    data: Vec<u8>,
+}
+
+impl MemoryStorage {
+    pub fn new(layout: Layout) -> Self {
+        Self {
+            data: vec![0; layout.length],
+            layout,
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read_block(&mut self, piece: usize, offset: usize, length: usize) -> io::Result<Vec<u8>> {
+        let start = self.layout.locate(piece, offset, length)?;
+        Ok(self.data[start..start + length].to_vec())
+    }
+
+    fn write_block(&mut self, piece: usize, offset: usize, data: &[u8]) -> io::Result<()> {
+        let start = self.layout.locate(piece, offset, data.len())?;
+        self.data[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
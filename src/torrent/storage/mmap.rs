@@ -0,0 +1,107 @@
+use std::io;
+use std::path::Path;
+
+use memmap2::MmapMut;
+
+use super::{open_files, Layout, PartFile, Storage, Torrent};
+
+/// Keeps the payload in its files on disk, accessed through memory maps
+#[derive(Debug)]
+pub struct MmapStorage {
+    layout: Layout,
+    // Empty files cannot be mapped, and neither they nor padding files have
+    // anything to store anyway
+    maps: Vec<Option<MmapMut>>,
+    parts: PartFile,
+}
+
+impl MmapStorage {
+    /// Open the payload files below `root`, creating any that are missing,
+    /// and map them into memory
+    pub fn open<P: AsRef<Path>>(torrent: &Torrent, root: P) -> io::Result<Self> {
+        let layout = Layout::new(torrent);
+
+        let maps = open_files(&layout, root.as_ref())?
+            .iter()
+            .zip(&layout.files)
+            .map(|(file, layout_file)| match file {
+                Some(file) if layout_file.length > 0 => {
+                    // The files are ours alone for as long as the download runs
+                    unsafe { MmapMut::map_mut(file) }.map(Some)
+                }
+
+                _ => Ok(None),
+            })
+            .collect::<io::Result<_>>()?;
+
+        let parts = PartFile::new(torrent, &layout, root.as_ref());
+
+        Ok(Self {
+            layout,
+            maps,
+            parts,
+        })
+    }
+}
+
+impl Storage for MmapStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read_block(&mut self, piece: usize, offset: usize, length: usize) -> io::Result<Vec<u8>> {
+        let start = self.layout.locate(piece, offset, length)?;
+        let mut block = Vec::with_capacity(length);
+
+        for segment in self.layout.segments(start, length) {
+            let position = block.len();
+
+            match &self.maps[segment.file] {
+                Some(map) => {
+                    block.extend_from_slice(&map[segment.offset..segment.offset + segment.length])
+                }
+
+                None => {
+                    block.resize(position + segment.length, 0);
+
+                    if self.layout.is_skipped(segment.file) {
+                        self.parts
+                            .read(piece, offset + position, &mut block[position..])?;
+                    }
+                }
+            }
+        }
+
+        Ok(block)
+    }
+
+    fn write_block(&mut self, piece: usize, offset: usize, data: &[u8]) -> io::Result<()> {
+        let start = self.layout.locate(piece, offset, data.len())?;
+        let mut position = 0;
+
+        for segment in self.layout.segments(start, data.len()) {
+            let data = &data[position..position + segment.length];
+
+            match &mut self.maps[segment.file] {
+                Some(map) => {
+                    map[segment.offset..segment.offset + segment.length].copy_from_slice(data)
+                }
+
+                None if self.layout.is_skipped(segment.file) => {
+                    self.parts.write(piece, offset + position, data)?
+                }
+
+                None => {}
+            }
+
+            position += segment.length;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.maps.iter().flatten().try_for_each(|map| map.flush())?;
+        self.parts.flush()
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::{Layout, Torrent};
+
+/// Keeps the parts of skipped files that share a piece with wanted ones, so
+/// that the skipped files themselves are never created. Every such piece
+/// has a slot of a whole piece in the file, in piece order.
+#[derive(Debug)]
+pub struct PartFile {
+    path: PathBuf,
+    // Opened when first needed, so that there is no part file without
+    // anything to keep in it
+    file: Option<fs::File>,
+    slots: HashMap<usize, usize>,
+    piece_length: usize,
+}
+
+impl PartFile {
+    pub fn new(torrent: &Torrent, layout: &Layout, root: &Path) -> Self {
+        let slots = layout
+            .shared_pieces()
+            .into_iter()
+            .enumerate()
+            .map(|(slot, piece)| (piece, slot))
+            .collect();
+
+        Self {
+            path: root.join(format!(".{}.parts", torrent.name())),
+            file: None,
+            slots,
+            piece_length: layout.piece_length,
+        }
+    }
+
+    /// Fill `buffer` from `offset` into `piece`. What was never written
+    /// reads as zeros.
+    pub fn read(&mut self, piece: usize, offset: usize, buffer: &mut [u8]) -> io::Result<()> {
+        let position = self.position(piece, offset)?;
+
+        match self.open(false)? {
+            Some(file) => {
+                file.seek(SeekFrom::Start(position))?;
+                file.read_exact(buffer)
+            }
+
+            None => {
+                buffer.fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn write(&mut self, piece: usize, offset: usize, data: &[u8]) -> io::Result<()> {
+        let position = self.position(piece, offset)?;
+
+        if let Some(file) = self.open(true)? {
+            file.seek(SeekFrom::Start(position))?;
+            file.write_all(data)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().map_or(Ok(()), |file| file.flush())
+    }
+
+    fn position(&self, piece: usize, offset: usize) -> io::Result<u64> {
+        let slot = self.slots.get(&piece).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Piece is only part of skipped files",
+            )
+        })?;
+
+        Ok((slot * self.piece_length + offset) as u64)
+    }
+
+    fn open(&mut self, create: bool) -> io::Result<Option<&mut fs::File>> {
+        if self.file.is_none() {
+            let file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(create)
+                .truncate(false)
+                .open(&self.path);
+
+            let file = match file {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound && !create => return Ok(None),
+                Err(e) => return Err(e),
+            };
+
+            // Slots are read whether or not they were written
+            let length = (self.slots.len() * self.piece_length) as u64;
+
+            if create && file.metadata()?.len() < length {
+                file.set_len(length)?;
+            }
+
+            self.file = Some(file);
+        }
+
+        Ok(self.file.as_mut())
+    }
+}
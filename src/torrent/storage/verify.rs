@@ -0,0 +1,146 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use super::{Layout, PartFile, Torrent};
+
+/// Which pieces of a payload are on disk and match their hashes
+#[derive(Debug)]
+pub struct Verification {
+    // Whether each piece is intact, suitable for resuming from
+    pub pieces: Vec<bool>,
+    pub files: Vec<FileCompleteness>,
+}
+
+/// How many of the pieces a file is part of are intact
+#[derive(Debug)]
+pub struct FileCompleteness {
+    pub path: PathBuf,
+    pub valid_pieces: usize,
+    pub pieces: usize,
+}
+
+impl FileCompleteness {
+    pub fn is_complete(&self) -> bool {
+        self.valid_pieces == self.pieces
+    }
+}
+
+impl Verification {
+    /// Hash every piece of the payload below `root`, spreading the work over
+    /// all cores. Missing or short files only fail the pieces they are part of.
+    pub fn check<P: AsRef<Path>>(torrent: &Torrent, root: P) -> Self {
+        let layout = Layout::new(torrent);
+        let root = root.as_ref();
+
+        let pieces = check_pieces(
+            layout.pieces.len(),
+            || {
+                // Every worker reads through its own file handles
+                let files: Vec<Option<fs::File>> = layout
+                    .paths()
+                    .map(|path| fs::File::open(root.join(path)).ok())
+                    .collect();
+
+                (files, PartFile::new(torrent, &layout, root))
+            },
+            |(files, parts), piece| {
+                read_piece(&layout, files, parts, piece)
+                    .is_some_and(|data| layout.is_valid(piece, &data))
+            },
+        );
+
+        let files = layout
+            .file_pieces()
+            .enumerate()
+            .filter(|(file, _)| !layout.is_padding(*file))
+            .map(|(_, (path, range))| FileCompleteness {
+                path: root.join(path),
+                valid_pieces: pieces[range.clone()].iter().filter(|&&valid| valid).count(),
+                pieces: range.len(),
+            })
+            .collect();
+
+        Self { pieces, files }
+    }
+
+    pub fn valid_pieces(&self) -> usize {
+        self.pieces.iter().filter(|&&valid| valid).count()
+    }
+}
+
+/// Whether each of `piece_count` pieces is intact by `check`, spreading the
+/// work over all cores. Every worker checks with state of its own from
+/// `start`, such as file handles.
+pub fn check_pieces<T, S, C>(piece_count: usize, start: S, check: C) -> Vec<bool>
+where
+    S: Fn() -> T + Sync,
+    C: Fn(&mut T, usize) -> bool + Sync,
+{
+    let pieces = Mutex::new(vec![false; piece_count]);
+    let next_piece = AtomicUsize::new(0);
+
+    let workers = thread::available_parallelism().map_or(1, |cores| cores.get());
+
+    thread::scope(|scope| {
+        for _ in 0..workers.min(piece_count) {
+            scope.spawn(|| {
+                let mut state = start();
+
+                loop {
+                    let piece = next_piece.fetch_add(1, Ordering::Relaxed);
+
+                    if piece >= piece_count {
+                        break;
+                    }
+
+                    let valid = check(&mut state, piece);
+                    pieces.lock().unwrap()[piece] = valid;
+                }
+            });
+        }
+    });
+
+    pieces.into_inner().unwrap()
+}
+
+fn read_piece(
+    layout: &Layout,
+    files: &mut [Option<fs::File>],
+    parts: &mut PartFile,
+    piece: usize,
+) -> Option<Vec<u8>> {
+    let size = layout.piece_size(piece);
+    let start = layout.locate(piece, 0, size).ok()?;
+
+    let mut data = vec![0; size];
+    let mut position = 0;
+
+    for segment in layout.segments(start, size) {
+        // Padding is all zeros, whatever may be on disk
+        if layout.is_padding(segment.file) {
+            position += segment.length;
+            continue;
+        }
+
+        let buffer = &mut data[position..position + segment.length];
+
+        if layout.is_skipped(segment.file) {
+            parts.read(piece, position, buffer).ok()?;
+            position += segment.length;
+            continue;
+        }
+
+        let file = files[segment.file].as_mut()?;
+
+        file.seek(SeekFrom::Start(segment.offset as u64)).ok()?;
+        file.read_exact(buffer).ok()?;
+
+        position += segment.length;
+    }
+
+    Some(data)
+}
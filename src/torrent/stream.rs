@@ -0,0 +1,163 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use super::mse::Rc4;
+use super::UtpStream;
+
+/// The connection to a peer, over whichever transport it was made, and
+/// encrypted if that was agreed on
+#[derive(Debug)]
+pub struct PeerStream {
+    transport: Transport,
+    cipher: Option<Cipher>,
+    // Bytes read ahead during the encryption handshake, already decrypted
+    unread: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum Transport {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+#[derive(Debug)]
+struct Cipher {
+    incoming: Rc4,
+    outgoing: Rc4,
+}
+
+impl PeerStream {
+    fn new(transport: Transport) -> Self {
+        Self {
+            transport,
+            cipher: None,
+            unread: Vec::new(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match &self.transport {
+            Transport::Tcp(stream) => stream.peer_addr(),
+            Transport::Utp(stream) => stream.peer_addr(),
+        }
+    }
+
+    /// Decrypt everything read from here on with `incoming`, and encrypt
+    /// everything written with `outgoing`
+    pub fn encrypt(&mut self, incoming: Rc4, outgoing: Rc4) {
+        self.cipher = Some(Cipher { incoming, outgoing });
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub fn is_utp(&self) -> bool {
+        matches!(self.transport, Transport::Utp(_))
+    }
+
+    /// Have `bytes` read again, ahead of anything still in the transport
+    pub fn unread(&mut self, mut bytes: Vec<u8>) {
+        bytes.append(&mut self.unread);
+        self.unread = bytes;
+    }
+}
+
+impl From<TcpStream> for PeerStream {
+    fn from(stream: TcpStream) -> Self {
+        Self::new(Transport::Tcp(stream))
+    }
+}
+
+impl From<UtpStream> for PeerStream {
+    fn from(stream: UtpStream) -> Self {
+        Self::new(Transport::Utp(stream))
+    }
+}
+
+impl Transport {
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.unread.is_empty() {
+            let size = this.unread.len().min(buf.remaining());
+            buf.put_slice(&this.unread[..size]);
+            this.unread.drain(..size);
+
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+
+        let result = match &mut this.transport {
+            Transport::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        };
+
+        if let (Poll::Ready(Ok(())), Some(cipher)) = (&result, &mut this.cipher) {
+            cipher.incoming.apply(&mut buf.filled_mut()[filled..]);
+        }
+
+        result
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let cipher = match &mut this.cipher {
+            Some(cipher) => cipher,
+            None => return this.transport.poll_write(cx, buf),
+        };
+
+        // The transport may take only part of it, and the key stream must
+        // only move on by what was taken
+        let mut encrypted = buf.to_vec();
+        cipher.outgoing.clone().apply(&mut encrypted);
+
+        let result = this.transport.poll_write(cx, &encrypted);
+
+        if let Poll::Ready(Ok(size)) = result {
+            cipher.outgoing.skip(size);
+        }
+
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().transport {
+            Transport::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().transport {
+            Transport::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
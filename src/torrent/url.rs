@@ -14,7 +14,8 @@ impl Url {
     }
 
     pub fn with_param<P: UrlParamable>(mut self, name: &str, param: P) -> Self {
-        self.params.push((String::from(name), param.as_url_param()));
+        self.params
+            .push((String::from(name), param.into_url_param()));
         self
     }
 }
@@ -30,16 +31,16 @@ impl From<Url> for String {
         string.push('?');
 
         for (key, value) in &url.params[..url.params.len() - 1] {
-            string.push_str(&key);
+            string.push_str(key);
             string.push('=');
-            string.push_str(&value);
+            string.push_str(value);
             string.push('&');
         }
 
         if let Some((key, value)) = url.params.last() {
-            string.push_str(&key);
+            string.push_str(key);
             string.push('=');
-            string.push_str(&value);
+            string.push_str(value);
         }
 
         string
@@ -47,28 +48,28 @@ impl From<Url> for String {
 }
 
 pub trait UrlParamable {
-    fn as_url_param(self) -> String;
+    fn into_url_param(self) -> String;
 }
 
 impl UrlParamable for &str {
-    fn as_url_param(self) -> String {
+    fn into_url_param(self) -> String {
         String::from(self)
     }
 }
 
 impl UrlParamable for Sha1 {
-    fn as_url_param(self) -> String {
-        self.as_ref().as_url_param()
+    fn into_url_param(self) -> String {
+        self.as_ref().into_url_param()
     }
 }
 
 impl UrlParamable for &[u8] {
-    fn as_url_param(self) -> String {
+    fn into_url_param(self) -> String {
         let mut buffer = String::with_capacity(3 * self.len());
 
         for (i, nibble) in hex::encode(self).chars().enumerate() {
             if i % 2 == 0 {
-                buffer.push_str("%");
+                buffer.push('%');
             }
 
             buffer.push(nibble.to_ascii_uppercase());
@@ -79,7 +80,23 @@ impl UrlParamable for &[u8] {
 }
 
 impl UrlParamable for usize {
-    fn as_url_param(self) -> String {
+    fn into_url_param(self) -> String {
         format!("{}", self)
     }
 }
+
+/// Escape a path segment for use in a URL, keeping only the characters that
+/// never need it
+pub fn escape_segment(segment: &str) -> String {
+    let mut escaped = String::with_capacity(segment.len());
+
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    escaped
+}
@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::sleep;
+
+mod packet;
+use packet::{Kind, Packet};
+
+mod ledbat;
+
+mod connection;
+use connection::{Connection, Outbox};
+
+// How often connections check their timers
+const TICK: Duration = Duration::from_millis(50);
+
+/// Datagrams that arrived on the socket, along with where they came from
+pub type Datagrams = UnboundedReceiver<(Vec<u8>, SocketAddr)>;
+
+/// The uTP transport (BEP 29): reliable, ordered streams over UDP, which
+/// yield to other traffic on the link. The socket can be shared with
+/// another protocol, such as the DHT, which gets the packets that are not
+/// uTP.
+#[derive(Clone)]
+pub struct UtpSocket {
+    mux: Arc<Mux>,
+}
+
+// A connection, shared by its stream and the task driving it
+type Shared = Arc<Mutex<Connection>>;
+
+struct Mux {
+    socket: Arc<UdpSocket>,
+    // Connections by remote address and the connection id they receive on
+    connections: Mutex<HashMap<(SocketAddr, u16), Shared>>,
+    accepted: Mutex<Option<UnboundedSender<UtpStream>>>,
+    // Where the datagrams that are not uTP go
+    diverted: Mutex<Option<Outbox>>,
+    outbox: Outbox,
+}
+
+/// A uTP connection, read from and written to like a TCP stream
+pub struct UtpStream {
+    connection: Shared,
+    remote: SocketAddr,
+}
+
+impl UtpSocket {
+    pub async fn bind(port: u16) -> io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(("0.0.0.0", port)).await?);
+        let (outbox, packets) = mpsc::unbounded_channel();
+
+        let mux = Arc::new(Mux {
+            socket: Arc::clone(&socket),
+            connections: Mutex::new(HashMap::new()),
+            accepted: Mutex::new(None),
+            diverted: Mutex::new(None),
+            outbox,
+        });
+
+        tokio::spawn(send(socket, packets));
+        tokio::spawn(receive(Arc::clone(&mux)));
+
+        Ok(Self { mux })
+    }
+
+    #[cfg(test)]
+    pub fn port(&self) -> u16 {
+        self.mux
+            .socket
+            .local_addr()
+            .map(|address| address.port())
+            .unwrap_or_default()
+    }
+
+    /// Open a connection to `address`
+    pub async fn connect(&self, address: SocketAddr) -> io::Result<UtpStream> {
+        let connection = {
+            let mut connections = self.mux.connections.lock().unwrap();
+
+            let recv_id = loop {
+                let id = rand::thread_rng().gen();
+
+                if !connections.contains_key(&(address, id)) {
+                    break id;
+                }
+            };
+
+            let connection = Connection::connect(address, recv_id, self.mux.outbox.clone());
+            let connection = Arc::new(Mutex::new(connection));
+
+            connections.insert((address, recv_id), Arc::clone(&connection));
+            tokio::spawn(drive(
+                Arc::clone(&self.mux),
+                (address, recv_id),
+                Arc::clone(&connection),
+            ));
+
+            connection
+        };
+
+        let stream = UtpStream {
+            connection,
+            remote: address,
+        };
+
+        std::future::poll_fn(|cx| stream.connection.lock().unwrap().poll_connected(cx)).await?;
+        Ok(stream)
+    }
+
+    /// Accept the connections peers open to us from now on
+    pub fn listen(&self) -> UnboundedReceiver<UtpStream> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        *self.mux.accepted.lock().unwrap() = Some(sender);
+
+        receiver
+    }
+
+    /// Hand the datagrams that are not uTP to another protocol, along with
+    /// the socket to answer them on
+    pub fn divert(&self) -> (Arc<UdpSocket>, Datagrams) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        *self.mux.diverted.lock().unwrap() = Some(sender);
+
+        (Arc::clone(&self.mux.socket), receiver)
+    }
+}
+
+impl Mux {
+    fn dispatch(self: &Arc<Self>, packet: Packet, from: SocketAddr) {
+        let id = packet.connection_id;
+
+        let connection = {
+            let connections = self.connections.lock().unwrap();
+
+            match packet.kind {
+                // A repeated SYN goes to the connection it opened
+                Kind::Syn => connections.get(&(from, id.wrapping_add(1))).cloned(),
+
+                // A reset may carry either of the connection ids
+                Kind::Reset => connections.get(&(from, id)).cloned().or_else(|| {
+                    connections
+                        .iter()
+                        .find(|((address, _), connection)| {
+                            *address == from && connection.lock().unwrap().send_id == id
+                        })
+                        .map(|(_, connection)| Arc::clone(connection))
+                }),
+
+                _ => connections.get(&(from, id)).cloned(),
+            }
+        };
+
+        if let Some(connection) = connection {
+            connection.lock().unwrap().on_packet(packet);
+            return;
+        }
+
+        match packet.kind {
+            Kind::Syn => {
+                let accepted = self.accepted.lock().unwrap().clone();
+
+                match accepted.filter(|accepted| !accepted.is_closed()) {
+                    Some(accepted) => self.accept(packet, from, accepted),
+                    None => self.reset(&packet, from),
+                }
+            }
+
+            Kind::Reset => (),
+            _ => self.reset(&packet, from),
+        }
+    }
+
+    fn accept(
+        self: &Arc<Self>,
+        syn: Packet,
+        from: SocketAddr,
+        accepted: UnboundedSender<UtpStream>,
+    ) {
+        let connection = Connection::accept(from, &syn, self.outbox.clone());
+        let key = (from, connection.recv_id);
+        let connection = Arc::new(Mutex::new(connection));
+
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(key, Arc::clone(&connection));
+
+        tokio::spawn(drive(Arc::clone(self), key, Arc::clone(&connection)));
+
+        accepted
+            .send(UtpStream {
+                connection,
+                remote: from,
+            })
+            .ok();
+    }
+
+    /// Tell the sender of a packet for no connection of ours to stop
+    fn reset(&self, packet: &Packet, to: SocketAddr) {
+        let mut reset = Packet::new(Kind::Reset, packet.connection_id);
+        reset.ack_nr = packet.seq_nr;
+
+        self.outbox.send((reset.encode(), to)).ok();
+    }
+}
+
+async fn send(socket: Arc<UdpSocket>, mut packets: UnboundedReceiver<(Vec<u8>, SocketAddr)>) {
+    while let Some((packet, to)) = packets.recv().await {
+        socket.send_to(&packet, to).await.ok();
+    }
+}
+
+async fn receive(mux: Arc<Mux>) {
+    let mut buffer = [0u8; 2048];
+
+    loop {
+        let (size, from) = match mux.socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+
+        match Packet::parse(&buffer[..size]) {
+            Some(packet) => mux.dispatch(packet, from),
+
+            None => {
+                if let Some(diverted) = &*mux.diverted.lock().unwrap() {
+                    diverted.send((buffer[..size].to_vec(), from)).ok();
+                }
+            }
+        }
+    }
+}
+
+/// Keep the timers of a connection going until it is done with
+async fn drive(mux: Arc<Mux>, key: (SocketAddr, u16), connection: Shared) {
+    loop {
+        sleep(TICK).await;
+
+        if connection.lock().unwrap().tick() {
+            break;
+        }
+    }
+
+    mux.connections.lock().unwrap().remove(&key);
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.remote)
+    }
+}
+
+impl fmt::Debug for UtpStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UtpStream({})", self.remote)
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.connection.lock().unwrap().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.connection.lock().unwrap().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.connection.lock().unwrap().poll_shutdown(cx)
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.connection.lock().unwrap().close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::timeout;
+
+    /// Relay datagrams between whoever sends to the returned address and
+    /// `server`, dropping, delaying and duplicating some on the way
+    async fn lossy_proxy(server: SocketAddr) -> SocketAddr {
+        let front = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let back = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let address = front.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut rng = StdRng::seed_from_u64(29);
+            let mut client = None;
+            let (mut up, mut down) = ([0u8; 2048], [0u8; 2048]);
+
+            loop {
+                let (packet, to, via) = tokio::select! {
+                    Ok((size, from)) = front.recv_from(&mut up) => {
+                        client = Some(from);
+                        (up[..size].to_vec(), server, Arc::clone(&back))
+                    }
+
+                    Ok((size, _)) = back.recv_from(&mut down) => match client {
+                        Some(client) => (down[..size].to_vec(), client, Arc::clone(&front)),
+                        None => continue,
+                    },
+                };
+
+                match rng.gen_range(0..100) {
+                    0..=9 => (),
+
+                    // Held back, so that later packets overtake it
+                    10..=19 => {
+                        let delay = Duration::from_millis(rng.gen_range(5..50));
+
+                        tokio::spawn(async move {
+                            sleep(delay).await;
+                            via.send_to(&packet, to).await.ok();
+                        });
+                    }
+
+                    20..=24 => {
+                        via.send_to(&packet, to).await.ok();
+                        via.send_to(&packet, to).await.ok();
+                    }
+
+                    _ => {
+                        via.send_to(&packet, to).await.ok();
+                    }
+                }
+            }
+        });
+
+        address
+    }
+
+    #[tokio::test]
+    async fn streams_survive_a_lossy_link() {
+        let server = UtpSocket::bind(0).await.unwrap();
+        let client = UtpSocket::bind(0).await.unwrap();
+        let mut incoming = server.listen();
+
+        let proxy = lossy_proxy(SocketAddr::from(([127, 0, 0, 1], server.port()))).await;
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+
+        let sent = data.clone();
+        let sender = tokio::spawn(async move {
+            let mut stream = client.connect(proxy).await.unwrap();
+
+            stream.write_all(&sent).await.unwrap();
+            stream.shutdown().await.unwrap();
+
+            let mut answer = Vec::new();
+            stream.read_to_end(&mut answer).await.unwrap();
+            answer
+        });
+
+        let transfer = async {
+            let mut stream = incoming.recv().await.unwrap();
+
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            assert!(received == data);
+
+            stream.write_all(&received[..50_000]).await.unwrap();
+            stream.shutdown().await.unwrap();
+
+            sender.await.unwrap()
+        };
+
+        let answer = timeout(Duration::from_secs(60), transfer).await.unwrap();
+        assert!(answer == data[..50_000]);
+    }
+
+    #[tokio::test]
+    async fn other_datagrams_are_diverted() {
+        let utp = UtpSocket::bind(0).await.unwrap();
+        let (_, mut diverted) = utp.divert();
+
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ping = b"d1:ad2:id20:aaaaaaaaaaaaaaaaaaaae1:q4:ping1:t2:aa1:y1:qe";
+
+        other
+            .send_to(ping, ("127.0.0.1", utp.port()))
+            .await
+            .unwrap();
+
+        let (packet, from) = diverted.recv().await.unwrap();
+
+        assert_eq!(packet, ping);
+        assert_eq!(from, other.local_addr().unwrap());
+
+        // Sockets that do not listen turn connections down
+        let refusing = UtpSocket::bind(0).await.unwrap();
+        let address = SocketAddr::from(([127, 0, 0, 1], refusing.port()));
+
+        assert!(utp.connect(address).await.is_err());
+    }
+}
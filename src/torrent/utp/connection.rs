@@ -0,0 +1,651 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use tokio::io::ReadBuf;
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::ledbat::{Ledbat, MAX_PAYLOAD};
+use super::packet::{Kind, Packet};
+
+// Bytes written but not yet sent before writers are held up, and bytes
+// received but not yet read before the peer is told to hold off
+const SEND_BUFFER: usize = 256 * 1024;
+const RECEIVE_BUFFER: usize = 1024 * 1024;
+
+// Out of order packets we hold on to, counted from the one expected next,
+// and packets we have in flight at once
+const MAX_REORDER: u16 = 1024;
+const MAX_IN_FLIGHT: usize = 512;
+
+// Longest selective ACK bitmask we send, in bytes
+const MAX_SELECTIVE_ACK: usize = 32;
+
+// Times a packet is sent before we give up on the connection
+const MAX_TRANSMISSIONS: u32 = 8;
+const MAX_SYN_TRANSMISSIONS: u32 = 4;
+
+// Packets that must get through after a missing one before it counts as
+// lost, whether told by selective or duplicate ACKs
+const DUPLICATE_ACKS: usize = 3;
+
+const KEEPALIVE: Duration = Duration::from_secs(29);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+// How long a closed connection stays around to acknowledge packets the
+// peer sends again, such as its FIN
+const LINGER: Duration = Duration::from_secs(10);
+
+/// Where packets go to be sent, along with their destination
+pub type Outbox = UnboundedSender<(Vec<u8>, SocketAddr)>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    SynSent,
+    Connected,
+    Failed(io::ErrorKind),
+}
+
+/// A packet the peer has yet to acknowledge
+struct Sent {
+    seq_nr: u16,
+    kind: Kind,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+    // Waiting to be sent again
+    lost: bool,
+}
+
+/// One end of a uTP connection, fed packets and timer ticks by the socket
+/// it runs over, and read from and written to by its stream
+pub struct Connection {
+    state: State,
+    remote: SocketAddr,
+    // Connection ids in the packets we receive and send
+    pub recv_id: u16,
+    pub send_id: u16,
+    // Next packet we send, and last packet received in order
+    seq_nr: u16,
+    ack_nr: u16,
+    epoch: Instant,
+    // Delay measured on the last packet received, which the peer gets back
+    reply_delay: u32,
+    ledbat: Ledbat,
+    peer_window: usize,
+    in_flight: VecDeque<Sent>,
+    outgoing: VecDeque<u8>,
+    incoming: VecDeque<u8>,
+    reorder: HashMap<u16, Vec<u8>>,
+    fin_received: Option<u16>,
+    closing: bool,
+    fin_sent: bool,
+    // The stream is gone; we only stay to deliver what it wrote
+    dropped: bool,
+    closed_at: Option<Instant>,
+    last_ack: u16,
+    duplicate_acks: usize,
+    // Packets before this one have been resent for a loss once already,
+    // and are left to the timeout from then on
+    fast_resend: u16,
+    // Losses among packets sent before this one do not shrink the window
+    // again
+    recovery: Option<u16>,
+    // Packets sent before the last resend may have been acknowledged late
+    // because of it, which says nothing of the round trip
+    last_resend: Option<Instant>,
+    advertised: usize,
+    last_received: Instant,
+    last_sent: Instant,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+    outbox: Outbox,
+}
+
+/// Whether `a` comes before `b`, allowing for wrap around
+fn before(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) < 0
+}
+
+impl Connection {
+    fn new(remote: SocketAddr, recv_id: u16, send_id: u16, outbox: Outbox) -> Self {
+        let now = Instant::now();
+
+        Self {
+            state: State::SynSent,
+            remote,
+            recv_id,
+            send_id,
+            seq_nr: 1,
+            ack_nr: 0,
+            epoch: now,
+            reply_delay: 0,
+            ledbat: Ledbat::new(),
+            peer_window: MAX_PAYLOAD,
+            in_flight: VecDeque::new(),
+            outgoing: VecDeque::new(),
+            incoming: VecDeque::new(),
+            reorder: HashMap::new(),
+            fin_received: None,
+            closing: false,
+            fin_sent: false,
+            dropped: false,
+            closed_at: None,
+            last_ack: 0,
+            duplicate_acks: 0,
+            fast_resend: 1,
+            recovery: None,
+            last_resend: None,
+            advertised: RECEIVE_BUFFER,
+            last_received: now,
+            last_sent: now,
+            reader: None,
+            writer: None,
+            outbox,
+        }
+    }
+
+    /// Open a connection to `remote`, receiving under `recv_id`
+    pub fn connect(remote: SocketAddr, recv_id: u16, outbox: Outbox) -> Self {
+        let mut connection = Self::new(remote, recv_id, recv_id.wrapping_add(1), outbox);
+
+        connection.transmit(Kind::Syn, Vec::new());
+        connection
+    }
+
+    /// Take up the connection `syn` opens
+    pub fn accept(remote: SocketAddr, syn: &Packet, outbox: Outbox) -> Self {
+        let id = syn.connection_id;
+        let mut connection = Self::new(remote, id.wrapping_add(1), id, outbox);
+
+        connection.state = State::Connected;
+        connection.seq_nr = rand::thread_rng().gen();
+        connection.fast_resend = connection.seq_nr;
+        connection.ack_nr = syn.seq_nr;
+        connection.last_ack = connection.seq_nr.wrapping_sub(1);
+        connection.reply_delay = connection.now().wrapping_sub(syn.timestamp);
+        connection.send_state();
+        connection
+    }
+
+    pub fn on_packet(&mut self, packet: Packet) {
+        if let State::Failed(_) = self.state {
+            return;
+        }
+
+        self.last_received = Instant::now();
+        self.reply_delay = self.now().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.window as usize;
+
+        match (packet.kind, self.state) {
+            (Kind::Reset, _) => return self.fail(io::ErrorKind::ConnectionReset),
+
+            // Our answer to the SYN got lost
+            (Kind::Syn, State::Connected) => return self.send_state(),
+            (Kind::Syn, _) => return,
+
+            (Kind::State, State::SynSent) => {
+                self.state = State::Connected;
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+                self.last_ack = packet.ack_nr.wrapping_sub(1);
+            }
+
+            (_, State::SynSent) => return,
+            _ => (),
+        }
+
+        self.process_ack(&packet);
+
+        if matches!(packet.kind, Kind::Data | Kind::Fin) {
+            self.receive(packet);
+            self.send_state();
+        }
+
+        self.flush();
+        self.wake();
+    }
+
+    /// Check the timers; true once the connection can be forgotten
+    pub fn tick(&mut self) -> bool {
+        let now = Instant::now();
+
+        match self.state {
+            State::Failed(_) => return self.dropped,
+            State::SynSent if self.dropped => return true,
+            _ => (),
+        }
+
+        if now.duration_since(self.last_received) > IDLE_TIMEOUT {
+            self.fail(io::ErrorKind::TimedOut);
+            return self.dropped;
+        }
+
+        let oldest = self
+            .in_flight
+            .iter()
+            .filter(|sent| !sent.lost)
+            .map(|sent| sent.sent_at)
+            .min();
+
+        // Nothing got through for a whole timeout, so everything in flight
+        // goes again, starting from a window of one packet
+        if oldest.is_some_and(|sent_at| now.duration_since(sent_at) >= self.ledbat.timeout()) {
+            let limit = match self.state {
+                State::SynSent => MAX_SYN_TRANSMISSIONS,
+                _ => MAX_TRANSMISSIONS,
+            };
+
+            if self
+                .in_flight
+                .iter()
+                .any(|sent| sent.transmissions >= limit)
+            {
+                self.fail(io::ErrorKind::TimedOut);
+                return self.dropped;
+            }
+
+            for sent in &mut self.in_flight {
+                sent.lost = true;
+            }
+
+            self.ledbat.on_timeout();
+            self.recovery = Some(self.seq_nr);
+            self.flush();
+        }
+
+        if self.state == State::Connected && now.duration_since(self.last_sent) >= KEEPALIVE {
+            self.send_state();
+        }
+
+        if self.dropped && self.fin_sent && self.in_flight.is_empty() {
+            let closed_at = *self.closed_at.get_or_insert(now);
+            return now.duration_since(closed_at) >= LINGER;
+        }
+
+        false
+    }
+
+    /// The stream is gone: finish sending what it wrote, then close
+    pub fn close(&mut self) {
+        self.dropped = true;
+        self.closing = true;
+        self.flush();
+    }
+
+    pub fn poll_connected(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.state {
+            State::Connected => Poll::Ready(Ok(())),
+            State::Failed(kind) => Poll::Ready(Err(kind.into())),
+
+            State::SynSent => {
+                self.writer = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    pub fn poll_read(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.incoming.is_empty() {
+            let size = buf.remaining().min(self.incoming.len());
+            let bytes: Vec<u8> = self.incoming.drain(..size).collect();
+            buf.put_slice(&bytes);
+
+            // A peer told to hold off has to hear when there is room again
+            if self.advertised < RECEIVE_BUFFER / 2 && self.receive_window() >= RECEIVE_BUFFER / 2 {
+                self.send_state();
+            }
+
+            return Poll::Ready(Ok(()));
+        }
+
+        if self.fin_received == Some(self.ack_nr) {
+            return Poll::Ready(Ok(()));
+        }
+
+        if let State::Failed(kind) = self.state {
+            return Poll::Ready(Err(kind.into()));
+        }
+
+        self.reader = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if let State::Failed(kind) = self.state {
+            return Poll::Ready(Err(kind.into()));
+        }
+
+        if self.closing {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let room = SEND_BUFFER.saturating_sub(self.outgoing.len());
+
+        if room == 0 {
+            self.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let size = room.min(buf.len());
+        self.outgoing.extend(&buf[..size]);
+        self.flush();
+
+        Poll::Ready(Ok(size))
+    }
+
+    /// Send a FIN once everything written has gone out, and wait for the
+    /// peer to acknowledge it all
+    pub fn poll_shutdown(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let State::Failed(kind) = self.state {
+            return Poll::Ready(Err(kind.into()));
+        }
+
+        self.closing = true;
+        self.flush();
+
+        if self.fin_sent && self.in_flight.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+
+        self.writer = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn now(&self) -> u32 {
+        self.epoch.elapsed().as_micros() as u32
+    }
+
+    fn receive_window(&self) -> usize {
+        RECEIVE_BUFFER.saturating_sub(self.incoming.len())
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        self.state = State::Failed(kind);
+        self.in_flight.clear();
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+
+        if let Some(waker) = self.writer.take() {
+            waker.wake();
+        }
+    }
+
+    /// Drop the packets the peer has acknowledged, and look for the ones it
+    /// is missing
+    fn process_ack(&mut self, packet: &Packet) {
+        let now = Instant::now();
+
+        // Acknowledgements of packets we never sent are bogus
+        if !before(packet.ack_nr, self.seq_nr) {
+            return;
+        }
+
+        let mut acked = Vec::new();
+
+        while let Some(sent) = self.in_flight.front() {
+            if before(packet.ack_nr, sent.seq_nr) {
+                break;
+            }
+
+            acked.extend(self.in_flight.pop_front());
+        }
+
+        let mut selected = Vec::new();
+
+        if let Some(mask) = &packet.selective_ack {
+            for bit in 0..mask.len() * 8 {
+                if mask[bit / 8] & (1 << (bit % 8)) != 0 {
+                    selected.push(packet.ack_nr.wrapping_add(2 + bit as u16));
+                }
+            }
+
+            let (hit, kept): (Vec<Sent>, Vec<Sent>) = std::mem::take(&mut self.in_flight)
+                .into_iter()
+                .partition(|sent| selected.contains(&sent.seq_nr));
+
+            self.in_flight = kept.into();
+            acked.extend(hit);
+        }
+
+        let mut lost = None;
+
+        // Three packets after a missing one got through
+        if selected.len() >= DUPLICATE_ACKS {
+            let threshold = selected[selected.len() - DUPLICATE_ACKS];
+
+            for sent in &mut self.in_flight {
+                if before(sent.seq_nr, threshold) && !before(sent.seq_nr, self.fast_resend) {
+                    sent.lost = true;
+                    lost = Some(sent.seq_nr);
+                }
+            }
+        }
+
+        // Without selective ACKs, the same ACK three times says as much
+        let duplicate = acked.is_empty()
+            && packet.kind == Kind::State
+            && packet.ack_nr == self.last_ack
+            && !self.in_flight.is_empty();
+
+        if duplicate {
+            self.duplicate_acks += 1;
+
+            if self.duplicate_acks == DUPLICATE_ACKS {
+                if let Some(sent) = self.in_flight.front_mut() {
+                    if !before(sent.seq_nr, self.fast_resend) {
+                        sent.lost = true;
+                        lost = Some(sent.seq_nr);
+                    }
+                }
+            }
+        } else {
+            self.duplicate_acks = 0;
+        }
+
+        self.last_ack = packet.ack_nr;
+
+        if !acked.is_empty() {
+            for sent in &acked {
+                // Round trips of packets sent more than once are ambiguous
+                let sent_after_resend = self
+                    .last_resend
+                    .is_none_or(|resent_at| sent.sent_at > resent_at);
+
+                if sent.transmissions == 1 && sent_after_resend {
+                    self.ledbat.on_rtt(now.duration_since(sent.sent_at));
+                }
+            }
+
+            let bytes = acked.iter().map(|sent| sent.payload.len()).sum();
+            self.ledbat.on_ack(bytes, packet.timestamp_difference, now);
+        }
+
+        // One cut of the window per loss event
+        if let Some(seq_nr) = lost {
+            self.fast_resend = seq_nr.wrapping_add(1);
+
+            if self
+                .recovery
+                .is_none_or(|recovery| !before(seq_nr, recovery))
+            {
+                self.ledbat.on_loss();
+                self.recovery = Some(self.seq_nr);
+            }
+        }
+    }
+
+    /// Take in the payload of a data or FIN packet, in order
+    fn receive(&mut self, packet: Packet) {
+        let offset = packet.seq_nr.wrapping_sub(self.ack_nr);
+
+        // Already received, or too far ahead to keep
+        if offset == 0 || offset > MAX_REORDER {
+            return;
+        }
+
+        if self
+            .fin_received
+            .is_some_and(|fin| before(fin, packet.seq_nr))
+        {
+            return;
+        }
+
+        if packet.kind == Kind::Fin {
+            self.fin_received = Some(packet.seq_nr);
+        }
+
+        if offset > 1 {
+            self.reorder.entry(packet.seq_nr).or_insert(packet.payload);
+            return;
+        }
+
+        self.incoming.extend(packet.payload);
+        self.ack_nr = packet.seq_nr;
+
+        while let Some(payload) = self.reorder.remove(&self.ack_nr.wrapping_add(1)) {
+            self.incoming.extend(payload);
+            self.ack_nr = self.ack_nr.wrapping_add(1);
+        }
+    }
+
+    /// Send what the windows allow: lost packets first, then new data, and
+    /// finally the FIN
+    fn flush(&mut self) {
+        for index in 0..self.in_flight.len() {
+            if self.in_flight[index].lost {
+                if !self.has_room(self.in_flight[index].payload.len()) {
+                    return;
+                }
+
+                self.resend(index);
+            }
+        }
+
+        if self.state != State::Connected {
+            return;
+        }
+
+        let queued = self.outgoing.len();
+
+        while !self.outgoing.is_empty() && self.in_flight.len() < MAX_IN_FLIGHT {
+            let size = self.outgoing.len().min(MAX_PAYLOAD);
+
+            if !self.has_room(size) {
+                break;
+            }
+
+            let payload = self.outgoing.drain(..size).collect();
+            self.transmit(Kind::Data, payload);
+        }
+
+        if self.outgoing.len() < queued {
+            if let Some(waker) = self.writer.take() {
+                waker.wake();
+            }
+        }
+
+        if self.closing && self.outgoing.is_empty() && !self.fin_sent {
+            self.transmit(Kind::Fin, Vec::new());
+            self.fin_sent = true;
+        }
+    }
+
+    /// Whether `size` more bytes fit in the windows. A single packet always
+    /// goes, so that a closed window gets probed.
+    fn has_room(&self, size: usize) -> bool {
+        let in_flight: usize = self
+            .in_flight
+            .iter()
+            .filter(|sent| !sent.lost)
+            .map(|sent| sent.payload.len())
+            .sum();
+
+        let window = self.ledbat.window().min(self.peer_window);
+
+        in_flight == 0 || in_flight + size <= window
+    }
+
+    fn transmit(&mut self, kind: Kind, payload: Vec<u8>) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = seq_nr.wrapping_add(1);
+
+        self.send(kind, seq_nr, payload.clone());
+
+        self.in_flight.push_back(Sent {
+            seq_nr,
+            kind,
+            payload,
+            sent_at: Instant::now(),
+            transmissions: 1,
+            lost: false,
+        });
+    }
+
+    fn resend(&mut self, index: usize) {
+        let sent = &mut self.in_flight[index];
+
+        sent.lost = false;
+        sent.sent_at = Instant::now();
+        sent.transmissions += 1;
+        self.last_resend = Some(sent.sent_at);
+
+        let (kind, seq_nr, payload) = (sent.kind, sent.seq_nr, sent.payload.clone());
+        self.send(kind, seq_nr, payload);
+    }
+
+    fn send_state(&mut self) {
+        self.send(Kind::State, self.seq_nr, Vec::new());
+    }
+
+    fn send(&mut self, kind: Kind, seq_nr: u16, payload: Vec<u8>) {
+        let connection_id = match kind {
+            Kind::Syn => self.recv_id,
+            _ => self.send_id,
+        };
+
+        self.advertised = self.receive_window();
+
+        let mut packet = Packet::new(kind, connection_id);
+        packet.timestamp = self.now();
+        packet.timestamp_difference = self.reply_delay;
+        packet.window = self.advertised as u32;
+        packet.seq_nr = seq_nr;
+        packet.ack_nr = self.ack_nr;
+        packet.selective_ack = self.selective_ack();
+        packet.payload = payload;
+
+        self.outbox.send((packet.encode(), self.remote)).ok();
+        self.last_sent = Instant::now();
+    }
+
+    /// Which of the packets after the next expected one have arrived
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        let bits: Vec<usize> = self
+            .reorder
+            .keys()
+            .map(|seq_nr| seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize)
+            .filter(|&bit| bit < MAX_SELECTIVE_ACK * 8)
+            .collect();
+
+        let highest = *bits.iter().max()?;
+        let mut mask = vec![0; (highest / 32 + 1) * 4];
+
+        for bit in bits {
+            mask[bit / 8] |= 1 << (bit % 8);
+        }
+
+        Some(mask)
+    }
+}
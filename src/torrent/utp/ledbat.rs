@@ -0,0 +1,171 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::packet::HEADER_SIZE;
+
+// Largest payload we put in a packet, keeping clear of fragmentation
+pub const MAX_PAYLOAD: usize = 1400 - HEADER_SIZE;
+
+// Queuing delay LEDBAT aims for; above it we back off for other traffic
+const TARGET_DELAY: u32 = 100_000;
+
+// Bytes the window grows by per round trip when there is no queuing at all
+const GAIN: f64 = 3000.0;
+
+const MIN_WINDOW: usize = MAX_PAYLOAD;
+const MAX_WINDOW: usize = 1024 * 1024;
+
+// Base delays are kept per minute, for this many minutes, so that a route
+// change is noticed
+const BASE_DELAY_MINUTES: usize = 10;
+
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Congestion control by LEDBAT, which grows the window while the one-way
+/// delay stays close to the lowest seen, and shrinks it as queues build up
+#[derive(Debug)]
+pub struct Ledbat {
+    window: usize,
+    // The lowest delay seen in each of the last minutes, newest last
+    base_delays: VecDeque<(Instant, u32)>,
+    // Smoothed round trip time and its variance, in microseconds
+    rtt: Option<(f64, f64)>,
+    timeout: Duration,
+    // Timeouts in a row, each doubling the next
+    backoff: u32,
+}
+
+impl Ledbat {
+    pub fn new() -> Self {
+        Self {
+            window: 2 * MIN_WINDOW,
+            base_delays: VecDeque::new(),
+            rtt: None,
+            timeout: INITIAL_TIMEOUT,
+            backoff: 0,
+        }
+    }
+
+    /// Bytes that may be in flight
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// How long an unacknowledged packet waits before it is sent again
+    pub fn timeout(&self) -> Duration {
+        (self.timeout * 2u32.pow(self.backoff)).min(MAX_TIMEOUT)
+    }
+
+    /// Account for `acked` bytes the peer has received, `delay` being what
+    /// the peer measured for the packet that told us
+    pub fn on_ack(&mut self, acked: usize, delay: u32, now: Instant) {
+        self.backoff = 0;
+
+        let base = self.update_base_delay(delay, now);
+        let queuing = delay.wrapping_sub(base) as f64;
+
+        let off_target = (TARGET_DELAY as f64 - queuing) / TARGET_DELAY as f64;
+        let change = GAIN * off_target * acked as f64 / self.window as f64;
+
+        self.window =
+            (self.window as f64 + change).clamp(MIN_WINDOW as f64, MAX_WINDOW as f64) as usize;
+    }
+
+    /// Account for a round trip measured on a packet sent only once
+    pub fn on_rtt(&mut self, sample: Duration) {
+        let sample = sample.as_micros() as f64;
+
+        let (rtt, variance) = match self.rtt {
+            None => (sample, sample / 2.0),
+            Some((rtt, variance)) => (
+                rtt + (sample - rtt) / 8.0,
+                variance + ((rtt - sample).abs() - variance) / 4.0,
+            ),
+        };
+
+        self.rtt = Some((rtt, variance));
+
+        let timeout = Duration::from_micros((rtt + 4.0 * variance) as u64);
+        self.timeout = timeout.clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    /// A packet went missing while others got through
+    pub fn on_loss(&mut self) {
+        self.window = (self.window / 2).max(MIN_WINDOW);
+    }
+
+    /// Nothing got through for a whole timeout
+    pub fn on_timeout(&mut self) {
+        self.window = MIN_WINDOW;
+
+        if self.timeout() < MAX_TIMEOUT {
+            self.backoff += 1;
+        }
+    }
+
+    /// The lowest delay of the last minutes, counting `delay`. Clocks of the
+    /// two ends are unrelated, so delays are only compared to each other.
+    fn update_base_delay(&mut self, delay: u32, now: Instant) -> u32 {
+        let minute = Duration::from_secs(60);
+
+        match self.base_delays.back_mut() {
+            Some((started, lowest)) if now.duration_since(*started) < minute => {
+                if (delay.wrapping_sub(*lowest) as i32) < 0 {
+                    *lowest = delay;
+                }
+            }
+
+            _ => {
+                self.base_delays.push_back((now, delay));
+
+                if self.base_delays.len() > BASE_DELAY_MINUTES {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+
+        self.base_delays
+            .iter()
+            .map(|&(_, lowest)| lowest)
+            .reduce(|a, b| if (b.wrapping_sub(a) as i32) < 0 { b } else { a })
+            .unwrap_or(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_follows_queuing_delay() {
+        let mut ledbat = Ledbat::new();
+        let now = Instant::now();
+        let start = ledbat.window();
+
+        // Delays at the base mean nothing is queued, whatever the clock offset
+        for _ in 0..100 {
+            ledbat.on_ack(MAX_PAYLOAD, u32::MAX - 10, now);
+        }
+
+        let grown = ledbat.window();
+        assert!(grown > start);
+
+        // Delays well above the target shrink it again
+        for _ in 0..100 {
+            ledbat.on_ack(MAX_PAYLOAD, (u32::MAX - 10).wrapping_add(300_000), now);
+        }
+
+        assert!(ledbat.window() < grown);
+
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window(), MIN_WINDOW);
+        assert_eq!(ledbat.timeout(), 2 * INITIAL_TIMEOUT);
+
+        // Backing off ends with the next acknowledgement
+        ledbat.on_rtt(Duration::from_millis(10));
+        ledbat.on_ack(MAX_PAYLOAD, u32::MAX - 10, now);
+        assert_eq!(ledbat.timeout(), MIN_TIMEOUT);
+    }
+}
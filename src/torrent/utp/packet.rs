@@ -0,0 +1,152 @@
+// Size of the fixed header every packet starts with
+pub const HEADER_SIZE: usize = 20;
+
+const VERSION: u8 = 1;
+
+// Extension carrying the selective ACK bitmask
+const SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+/// A uTP packet (BEP 29)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub kind: Kind,
+    pub connection_id: u16,
+    // Microseconds, by the sender's clock
+    pub timestamp: u32,
+    // The sender's clock minus the timestamp of the last packet it received
+    pub timestamp_difference: u32,
+    // Bytes the sender can take in
+    pub window: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    // Bit i is set if packet ack_nr + 2 + i has arrived
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(kind: Kind, connection_id: u16) -> Self {
+        Self {
+            kind,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window: 0,
+            seq_nr: 0,
+            ack_nr: 0,
+            selective_ack: None,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE || bytes[0] & 0x0f != VERSION {
+            return None;
+        }
+
+        let kind = match bytes[0] >> 4 {
+            0 => Kind::Data,
+            1 => Kind::Fin,
+            2 => Kind::State,
+            3 => Kind::Reset,
+            4 => Kind::Syn,
+            _ => return None,
+        };
+
+        let u16_at = |at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+
+        let mut selective_ack = None;
+        let mut extension = bytes[1];
+        let mut at = HEADER_SIZE;
+
+        // Each extension names the type of the one after it
+        while extension != 0 {
+            let length = *bytes.get(at + 1)? as usize;
+            let data = bytes.get(at + 2..at + 2 + length)?;
+
+            if extension == SELECTIVE_ACK {
+                selective_ack = Some(data.to_vec());
+            }
+
+            extension = bytes[at];
+            at += 2 + length;
+        }
+
+        Some(Self {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack,
+            payload: bytes[at..].to_vec(),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+
+        bytes.push((self.kind as u8) << 4 | VERSION);
+        bytes.push(match self.selective_ack {
+            Some(_) => SELECTIVE_ACK,
+            None => 0,
+        });
+
+        bytes.extend_from_slice(&self.connection_id.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        bytes.extend_from_slice(&self.window.to_be_bytes());
+        bytes.extend_from_slice(&self.seq_nr.to_be_bytes());
+        bytes.extend_from_slice(&self.ack_nr.to_be_bytes());
+
+        if let Some(mask) = &self.selective_ack {
+            bytes.push(0);
+            bytes.push(mask.len() as u8);
+            bytes.extend_from_slice(mask);
+        }
+
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_survive_encoding() {
+        let mut packet = Packet::new(Kind::Data, 0x1234);
+        packet.timestamp = 1_000_000;
+        packet.timestamp_difference = 2_000;
+        packet.window = 1 << 20;
+        packet.seq_nr = 65535;
+        packet.ack_nr = 7;
+        packet.selective_ack = Some(vec![0b101, 0, 0, 0x80]);
+        packet.payload = b"payload".to_vec();
+
+        let bytes = packet.encode();
+
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(bytes[1], SELECTIVE_ACK);
+        assert_eq!(Packet::parse(&bytes), Some(packet));
+
+        // Bencoded DHT messages share the socket, and must not pass for uTP
+        assert_eq!(
+            Packet::parse(b"d1:ad2:id20:aaaaaaaaaaaaaaaaaaaae1:q4:ping1:t2:aa1:y1:qe"),
+            None
+        );
+    }
+}
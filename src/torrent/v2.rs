@@ -0,0 +1,428 @@
+use super::pipeline::BLOCK_SIZE;
+use super::sha::Sha256;
+use super::{get_bstr, get_int, Attributes, Bencoded, File, Payload, Priority, Sha1};
+
+const BLOCK: usize = BLOCK_SIZE as usize;
+
+/// What a piece is checked against once it has been downloaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceHash {
+    /// The SHA-1 of the whole piece (v1)
+    Sha1(Sha1),
+    /// The root of the merkle tree over the 16 KiB blocks of the piece
+    /// (v2), `leaves` wide. Only the first `length` bytes belong to the
+    /// file; the rest is padding up to the next file.
+    Merkle {
+        root: Sha256,
+        leaves: usize,
+        length: usize,
+    },
+}
+
+impl PieceHash {
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match self {
+            Self::Sha1(sha) => Sha1::digest(data) == *sha,
+
+            Self::Merkle {
+                root,
+                leaves,
+                length,
+            } => {
+                data.len() >= *length
+                    && data[*length..].iter().all(|&byte| byte == 0)
+                    && merkle_root(block_hashes(&data[..*length]), *leaves, Sha256::default())
+                        == *root
+            }
+        }
+    }
+}
+
+/// A file as the `file tree` of a v2 torrent describes it
+#[derive(Debug)]
+pub struct TreeFile {
+    path: Vec<String>,
+    length: usize,
+    // Left out for empty files
+    pieces_root: Option<Sha256>,
+}
+
+/// The files of the `file tree` of a v2 info dictionary, in order
+pub fn tree_files(info: &Bencoded) -> Vec<TreeFile> {
+    let tree = super::get_dict(info, "file tree").expect("No torrent `file tree` entry");
+
+    let mut files = Vec::new();
+    walk(&tree, &mut Vec::new(), &mut files);
+
+    files
+}
+
+fn walk(node: &Bencoded, prefix: &mut Vec<String>, files: &mut Vec<TreeFile>) {
+    let entries = match node {
+        Bencoded::Dict(entries) => entries,
+        _ => panic!("Torrent `file tree` entry is not a dictionary"),
+    };
+
+    for (name, child) in entries {
+        let name = match name {
+            Bencoded::Bstr(name) => name,
+            _ => panic!("Torrent `file tree` key is not a byte string"),
+        };
+
+        // An empty name marks the node as a file, described by its value
+        if name.is_empty() {
+            let length = get_int(child, "length")
+                .map(|length| length as usize)
+                .expect("Torrent `file tree` file has no `length` entry");
+
+            let pieces_root = get_bstr(child, "pieces root")
+                .filter(|root| root.len() == 32)
+                .map(|root| Sha256::new_raw(&root));
+
+            assert!(
+                length == 0 || pieces_root.is_some(),
+                "Torrent `file tree` file has no `pieces root` entry"
+            );
+
+            files.push(TreeFile {
+                path: prefix.clone(),
+                length,
+                pieces_root,
+            });
+
+            continue;
+        }
+
+        let name = String::from_utf8(name.clone()).expect("Torrent `file tree` path not UTF-8");
+
+        prefix.push(name);
+        walk(child, prefix, files);
+        prefix.pop();
+    }
+}
+
+/// The payload of a v2-only torrent. Every file starts on a piece boundary,
+/// which is the same as a v1 payload with padding files in between.
+pub fn payload(name: String, files: &[TreeFile], piece_length: usize) -> Payload {
+    if let [file] = files {
+        if file.path == [name.as_str()] {
+            return Payload::Single {
+                name,
+                length: file.length,
+            };
+        }
+    }
+
+    let mut padded = Vec::new();
+
+    for (index, file) in files.iter().enumerate() {
+        padded.push(File {
+            path: file.path.clone(),
+            length: file.length,
+            attributes: Attributes::default(),
+            priority: Priority::default(),
+        });
+
+        let padding = (piece_length - file.length % piece_length) % piece_length;
+
+        if padding > 0 && index + 1 < files.len() {
+            padded.push(File {
+                path: vec![String::from(".pad"), padding.to_string()],
+                length: padding,
+                attributes: Attributes {
+                    padding: true,
+                    ..Attributes::default()
+                },
+                priority: Priority::default(),
+            });
+        }
+    }
+
+    Payload::Multi {
+        name,
+        files: padded,
+    }
+}
+
+/// The hash of every piece, taken from the `piece layers` of files longer
+/// than a piece, and checked against the roots of their trees. Without
+/// `piece_layers`, only torrents with no file longer than a piece have
+/// their hashes.
+pub fn piece_hashes(
+    files: &[TreeFile],
+    piece_length: usize,
+    piece_layers: Option<&Bencoded>,
+) -> Option<Vec<PieceHash>> {
+    assert!(
+        piece_length.is_power_of_two() && piece_length >= BLOCK,
+        "Torrent `piece length` is not a power of two of 16 KiB or more"
+    );
+
+    let leaves = piece_length / BLOCK;
+    let mut hashes = Vec::new();
+
+    for file in files.iter().filter(|file| file.length > 0) {
+        let root = file.pieces_root.expect("Checked when parsed");
+
+        if file.length <= piece_length {
+            hashes.push(PieceHash::Merkle {
+                root,
+                leaves: file.length.div_ceil(BLOCK).next_power_of_two(),
+                length: file.length,
+            });
+
+            continue;
+        }
+
+        let layer = layer(piece_layers?, &root).expect("No `piece layers` entry for a file");
+        let piece_count = file.length.div_ceil(piece_length);
+
+        assert_eq!(
+            layer.len(),
+            32 * piece_count,
+            "Torrent `piece layers` entry has the wrong length"
+        );
+
+        let layer: Vec<Sha256> = layer.chunks_exact(32).map(Sha256::new_raw).collect();
+
+        // Pieces past the end of the file are all zero leaves
+        let padding = merkle_root(Vec::new(), leaves, Sha256::default());
+
+        assert_eq!(
+            merkle_root(layer.clone(), piece_count.next_power_of_two(), padding),
+            root,
+            "Torrent `piece layers` do not match their `pieces root`"
+        );
+
+        for (piece, hash) in layer.into_iter().enumerate() {
+            hashes.push(PieceHash::Merkle {
+                root: hash,
+                leaves,
+                length: piece_length.min(file.length - piece * piece_length),
+            });
+        }
+    }
+
+    Some(hashes)
+}
+
+/// Make sure the v1 part of a hybrid torrent describes the same files as
+/// its v2 part, leaving out the padding files only v1 needs
+pub fn check_hybrid(payload: &Payload, files: &[TreeFile]) {
+    let v1_files: Vec<(Vec<String>, usize)> = match payload {
+        Payload::Single { name, length } => vec![(vec![name.clone()], *length)],
+
+        Payload::Multi { name: _, files } => files
+            .iter()
+            .filter(|file| !file.attributes.padding)
+            .map(|file| (file.path.clone(), file.length))
+            .collect(),
+    };
+
+    let v2_files: Vec<(Vec<String>, usize)> = files
+        .iter()
+        .map(|file| (file.path.clone(), file.length))
+        .collect();
+
+    assert_eq!(
+        v1_files, v2_files,
+        "Hybrid torrent describes different files for v1 and v2"
+    );
+}
+
+/// The `piece layers` entry for the file with `root`, whose keys are raw
+/// hashes rather than text
+fn layer(piece_layers: &Bencoded, root: &Sha256) -> Option<Vec<u8>> {
+    let key = Bencoded::Bstr(root.as_ref().to_vec());
+
+    match piece_layers {
+        Bencoded::Dict(entries) => {
+            entries
+                .iter()
+                .find(|(k, _)| *k == key)
+                .and_then(|(_, v)| match v {
+                    Bencoded::Bstr(layer) => Some(layer.clone()),
+                    _ => None,
+                })
+        }
+        _ => None,
+    }
+}
+
+fn block_hashes(data: &[u8]) -> Vec<Sha256> {
+    data.chunks(BLOCK).map(Sha256::digest).collect()
+}
+
+/// The root of a tree `width` leaves wide, a power of two, with `layer` at
+/// the bottom and `padding` filling the rest of it
+fn merkle_root(mut layer: Vec<Sha256>, width: usize, padding: Sha256) -> Sha256 {
+    layer.resize(width.max(1), padding);
+
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| Sha256::digest(&[pair[0].as_ref(), pair[1].as_ref()].concat()))
+            .collect();
+    }
+
+    layer[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Torrent;
+    use super::*;
+
+    fn hash_pair(a: Sha256, b: Sha256) -> Sha256 {
+        Sha256::digest(&[a.as_ref(), b.as_ref()].concat())
+    }
+
+    fn file_node(length: usize, root: Sha256) -> Bencoded {
+        Bencoded::dict(vec![(
+            "",
+            Bencoded::dict(vec![
+                ("length", Bencoded::Int(length as i64)),
+                ("pieces root", Bencoded::Bstr(root.as_ref().to_vec())),
+            ]),
+        )])
+    }
+
+    /// A file of three blocks in pieces of two, and another of one block
+    fn payload() -> (Vec<u8>, Vec<u8>) {
+        let big: Vec<u8> = (0..3 * BLOCK).map(|i| (i % 253) as u8).collect();
+        let small = vec![9u8; 1000];
+
+        (big, small)
+    }
+
+    /// The info dictionary and piece layers of a v2 torrent for `payload`,
+    /// with its trees worked out by hand
+    fn v2_torrent(v1: Option<Vec<Bencoded>>) -> (Bencoded, Bencoded) {
+        let (big, small) = payload();
+
+        let blocks: Vec<Sha256> = big.chunks(BLOCK).map(Sha256::digest).collect();
+        let first = hash_pair(blocks[0], blocks[1]);
+        let second = hash_pair(blocks[2], Sha256::default());
+        let big_root = hash_pair(first, second);
+
+        let small_root = Sha256::digest(&small);
+
+        let mut info = vec![
+            ("meta version", Bencoded::Int(2)),
+            ("name", Bencoded::from("payload")),
+            ("piece length", Bencoded::Int(2 * BLOCK as i64)),
+            (
+                "file tree",
+                Bencoded::dict(vec![
+                    ("big", file_node(big.len(), big_root)),
+                    (
+                        "dir",
+                        Bencoded::dict(vec![("small", file_node(small.len(), small_root))]),
+                    ),
+                ]),
+            ),
+        ];
+
+        if let Some(files) = v1 {
+            let mut padded = big.clone();
+            padded.resize(4 * BLOCK, 0);
+            padded.extend_from_slice(&small);
+
+            let pieces: Vec<u8> = padded
+                .chunks(2 * BLOCK)
+                .flat_map(|piece| Sha1::digest(piece).as_ref().to_vec())
+                .collect();
+
+            info.push(("files", Bencoded::List(files)));
+            info.push(("pieces", Bencoded::Bstr(pieces)));
+        }
+
+        let layers = Bencoded::Dict(vec![(
+            Bencoded::Bstr(big_root.as_ref().to_vec()),
+            Bencoded::Bstr([first.as_ref(), second.as_ref()].concat()),
+        )]);
+
+        (Bencoded::dict(info), layers)
+    }
+
+    fn v1_file(path: &[&str], length: usize, attr: Option<&str>) -> Bencoded {
+        let mut file = vec![
+            ("length", Bencoded::Int(length as i64)),
+            (
+                "path",
+                Bencoded::List(path.iter().map(|&part| Bencoded::from(part)).collect()),
+            ),
+        ];
+
+        if let Some(attr) = attr {
+            file.push(("attr", Bencoded::from(attr)));
+        }
+
+        Bencoded::dict(file)
+    }
+
+    #[test]
+    fn v2_torrents_are_verified_by_merkle_trees() {
+        let (info, layers) = v2_torrent(None);
+        let torrent = Torrent::from_info(String::new(), &info, Some(&layers));
+        let (big, small) = payload();
+
+        assert_eq!(
+            torrent.info_hash,
+            Sha256::digest(&Vec::from(&info)).truncated()
+        );
+
+        // The small file starts a piece of its own
+        assert_eq!(torrent.piece_count(), 3);
+        assert_eq!(torrent.payload.length(), 4 * BLOCK + small.len());
+
+        let mut second = big[2 * BLOCK..].to_vec();
+        second.resize(2 * BLOCK, 0);
+
+        assert!(torrent.pieces[0].matches(&big[..2 * BLOCK]));
+        assert!(torrent.pieces[1].matches(&second));
+        assert!(torrent.pieces[2].matches(&small));
+
+        // Neither a flipped bit nor anything in the padding gets through
+        let mut corrupt = big[..2 * BLOCK].to_vec();
+        corrupt[5] ^= 1;
+        assert!(!torrent.pieces[0].matches(&corrupt));
+
+        second[2 * BLOCK - 1] = 1;
+        assert!(!torrent.pieces[1].matches(&second));
+    }
+
+    #[test]
+    fn hybrid_torrents_describe_the_same_files() {
+        let files = vec![
+            v1_file(&["big"], 3 * BLOCK, None),
+            v1_file(&[".pad", "16384"], BLOCK, Some("p")),
+            v1_file(&["dir", "small"], 1000, None),
+        ];
+
+        let (info, layers) = v2_torrent(Some(files));
+        let torrent = Torrent::from_info(String::new(), &info, Some(&layers));
+
+        // Known to v1 peers by the SHA-1 info hash, and checked by the trees
+        assert_eq!(torrent.info_hash, Sha1::digest(&Vec::from(&info)));
+        assert_eq!(torrent.piece_count(), 3);
+        assert!(matches!(torrent.pieces[0], PieceHash::Merkle { .. }));
+
+        // Without piece layers, as from a magnet link, the v1 hashes do
+        let torrent = Torrent::from_info(String::new(), &info, None);
+        assert!(matches!(torrent.pieces[0], PieceHash::Sha1(_)));
+    }
+
+    #[test]
+    #[should_panic(expected = "different files")]
+    fn hybrid_torrents_must_agree() {
+        let files = vec![
+            v1_file(&["big"], 3 * BLOCK, None),
+            v1_file(&[".pad", "16384"], BLOCK, Some("p")),
+            v1_file(&["dir", "other"], 1000, None),
+        ];
+
+        let (info, layers) = v2_torrent(Some(files));
+        Torrent::from_info(String::new(), &info, Some(&layers));
+    }
+}
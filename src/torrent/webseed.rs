@@ -0,0 +1,274 @@
+use std::time::Duration;
+
+use reqwest::header::RANGE;
+use reqwest::{Client, StatusCode};
+
+use super::storage::Layout;
+use super::url::escape_segment;
+use super::{Payload, Torrent};
+
+// How long a single range request may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An HTTP server holding the payload (BEP 19), from which whole pieces are
+/// fetched with range requests
+#[derive(Debug, Clone)]
+pub struct WebSeed {
+    // Where each file of the payload is found, in payload order
+    file_urls: Vec<String>,
+    layout: Layout,
+    client: Client,
+}
+
+impl WebSeed {
+    pub fn new(url: &str, torrent: &Torrent) -> Self {
+        // The name is only appended to a URL that names a directory, while
+        // the files of a multi file torrent are always below it
+        let file_urls = match &torrent.payload {
+            Payload::Single { name, length: _ } if url.ends_with('/') => {
+                vec![format!("{}{}", url, escape_segment(name))]
+            }
+
+            Payload::Single { .. } => vec![String::from(url)],
+
+            Payload::Multi { name, files } => {
+                let base = format!("{}/{}", url.trim_end_matches('/'), escape_segment(name));
+
+                files
+                    .iter()
+                    .map(|file| {
+                        file.path.iter().fold(base.clone(), |url, segment| {
+                            format!("{}/{}", url, escape_segment(segment))
+                        })
+                    })
+                    .collect()
+            }
+        };
+
+        Self {
+            file_urls,
+            layout: Layout::new(torrent),
+            client: Client::new(),
+        }
+    }
+
+    /// Download `piece`, from as many files as it spans, and check it
+    /// against its hash
+    pub async fn fetch(&self, piece: usize) -> Option<Vec<u8>> {
+        let size = self.layout.piece_size(piece);
+        let start = self.layout.locate(piece, 0, size).ok()?;
+
+        let mut data = Vec::with_capacity(size);
+
+        for segment in self.layout.segments(start, size) {
+            let url = &self.file_urls[segment.file];
+            data.extend(
+                self.fetch_range(url, segment.offset, segment.length)
+                    .await?,
+            );
+        }
+
+        self.layout.is_valid(piece, &data).then_some(data)
+    }
+
+    async fn fetch_range(&self, url: &str, offset: usize, length: usize) -> Option<Vec<u8>> {
+        let response = self
+            .client
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", offset, offset + length - 1))
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await
+            .ok()?;
+
+        let status = response.status();
+        let body = response.bytes().await.ok()?;
+
+        match status {
+            StatusCode::PARTIAL_CONTENT if body.len() == length => Some(body.to_vec()),
+
+            // Servers that know nothing of ranges send the whole file
+            StatusCode::OK if body.len() >= offset + length => {
+                Some(body[offset..offset + length].to_vec())
+            }
+
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::super::storage::MemoryStorage;
+    use super::super::{
+        Attributes, Downloader, File, PeerId, PeerList, PieceHash, Priority, RawInfo, Sha1,
+    };
+    use super::*;
+
+    /// Serve `files` by path over HTTP, honouring single range requests
+    async fn serve(files: HashMap<String, Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let files = Arc::new(files);
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let files = Arc::clone(&files);
+
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buffer = [0u8; 1024];
+
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(size) => request.extend_from_slice(&buffer[..size]),
+                        }
+                    }
+
+                    let request = String::from_utf8(request).unwrap();
+                    let path = request.split(' ').nth(1).unwrap();
+
+                    let range = request
+                        .lines()
+                        .find_map(|line| {
+                            line.to_lowercase()
+                                .strip_prefix("range: bytes=")
+                                .map(String::from)
+                        })
+                        .map(|range| {
+                            let (from, to) = range.split_once('-').unwrap();
+                            (from.parse::<usize>().unwrap(), to.parse::<usize>().unwrap())
+                        });
+
+                    let response = match (files.get(path), range) {
+                        (Some(file), Some((from, to))) => {
+                            response("206 Partial Content", &file[from..=to])
+                        }
+                        (Some(file), None) => response("200 OK", file),
+                        (None, _) => response("404 Not Found", &[]),
+                    };
+
+                    stream.write_all(&response).await.ok();
+                });
+            }
+        });
+
+        format!("http://{}", address)
+    }
+
+    fn response(status: &str, body: &[u8]) -> Vec<u8> {
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        );
+
+        [head.as_bytes(), body].concat()
+    }
+
+    /// A two file torrent, with pieces that straddle the files
+    fn two_files(payload: &[u8], web_seeds: Vec<String>) -> Torrent {
+        Torrent {
+            announce: String::new(),
+            info_hash: Sha1::digest(b"info"),
+            piece_length: 4096,
+            pieces: payload
+                .chunks(4096)
+                .map(|piece| PieceHash::Sha1(Sha1::digest(piece)))
+                .collect(),
+            private: false,
+            payload: Payload::Multi {
+                name: String::from("some payload"),
+                files: vec![
+                    File {
+                        path: vec![String::from("a")],
+                        length: 5000,
+                        attributes: Attributes::default(),
+                        priority: Priority::default(),
+                    },
+                    File {
+                        path: vec![String::from("nested"), String::from("b")],
+                        length: payload.len() - 5000,
+                        attributes: Attributes::default(),
+                        priority: Priority::default(),
+                    },
+                ],
+            },
+            info: RawInfo(Arc::new(Vec::new())),
+            web_seeds,
+        }
+    }
+
+    #[tokio::test]
+    async fn pieces_are_fetched_across_files() {
+        let payload: Vec<u8> = (0..12_000).map(|i| (i % 251) as u8).collect();
+
+        let url = serve(HashMap::from([
+            (String::from("/some%20payload/a"), payload[..5000].to_vec()),
+            (
+                String::from("/some%20payload/nested/b"),
+                payload[5000..].to_vec(),
+            ),
+        ]))
+        .await;
+
+        let torrent = two_files(&payload, vec![url.clone()]);
+        let seed = WebSeed::new(&url, &torrent);
+
+        for piece in 0..torrent.piece_count() {
+            let start = piece * 4096;
+            let end = (start + 4096).min(payload.len());
+
+            assert_eq!(seed.fetch(piece).await.unwrap(), &payload[start..end]);
+        }
+
+        // Nothing comes of a seed without the payload
+        let missing = WebSeed::new(&format!("{}/elsewhere/", url), &torrent);
+        assert_eq!(missing.fetch(0).await, None);
+
+        // Or of one with different data
+        let mut corrupt = payload.clone();
+        corrupt[100] ^= 1;
+        let seed = WebSeed::new(&url, &two_files(&corrupt, Vec::new()));
+        assert_eq!(seed.fetch(0).await, None);
+        assert!(seed.fetch(1).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn downloads_complete_from_web_seeds_alone() {
+        let payload: Vec<u8> = (0..30_000).map(|i| (i % 241) as u8).collect();
+
+        let url = serve(HashMap::from([
+            (String::from("/some%20payload/a"), payload[..5000].to_vec()),
+            (
+                String::from("/some%20payload/nested/b"),
+                payload[5000..].to_vec(),
+            ),
+        ]))
+        .await;
+
+        let torrent = two_files(&payload, vec![format!("{}/", url)]);
+
+        let peer_list = PeerList {
+            our_id: PeerId::new(),
+            expected_info_hash: torrent.info_hash,
+            interval: 0,
+            peers: Vec::new(),
+        };
+
+        let storage = MemoryStorage::new(Layout::new(&torrent));
+
+        Downloader::new(&torrent, peer_list, 8)
+            .run(storage)
+            .await
+            .unwrap();
+    }
+}